@@ -0,0 +1,38 @@
+# Writes "Hello\n", then exits with the sum of the numbers (42).
+# The executable has a code segment at 0x10000 (entry point at 0x10074, right after the headers)
+# and a data segment at 0x11000 whose memory size includes a zero-filled .bss.
+    .text
+    .globl _start
+_start:
+    lui a1, 0x11            # Address of msg
+    li a0, 1                # stdout
+    li a2, 6
+    li a7, 64               # write
+    ecall
+
+    lui t0, 0x11
+    addi t0, t0, 8          # Address of numbers
+    li t1, 0
+    li t2, 4
+loop:
+    lw t3, 0(t0)
+    add t1, t1, t3
+    addi t0, t0, 4
+    addi t2, t2, -1
+    bnez t2, loop
+
+    # Goes through the stack
+    addi sp, sp, -16
+    sw t1, 12(sp)
+    lw a0, 12(sp)
+    addi sp, sp, 16
+
+    li a7, 93               # exit
+    ecall
+
+    .data
+msg:
+    .ascii "Hello\n"
+    .align 2
+numbers:
+    .word 1, 2, 3, 36
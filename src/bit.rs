@@ -36,7 +36,7 @@ pub fn bytes_from_low_bits(n: i32) -> (i8, i8, i8, i8) {
         index += 1;
     }
 
-    return (i1, i2, i3, i4);
+    (i1, i2, i3, i4)
 }
 
 pub fn i32_from_bytes(i1: i8, i2: i8, i3: i8, i4: i8) -> i32 {
@@ -71,7 +71,7 @@ pub fn i32_from_bytes(i1: i8, i2: i8, i3: i8, i4: i8) -> i32 {
         index += 1;
     }
 
-    return result;
+    result
 }
 
 fn get_i8_bit(input: i8, n: u8) -> bool {
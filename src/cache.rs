@@ -0,0 +1,377 @@
+use serde::Deserialize;
+use std::fmt;
+
+// Behaviour of the stores
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WritePolicy {
+    // Stores only update the line, written to the main memory once evicted. A missed store fetches
+    // the line first.
+    WriteBack,
+    // Stores are written to the main memory, a missed store doesn't fetch the line
+    WriteThrough,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    // Valid lines replaced by another one
+    pub evictions: u64,
+    // Dirty lines written to the main memory when evicted
+    pub write_backs: u64,
+}
+
+impl Stats {
+    pub fn hit_rate(&self) -> f64 {
+        let accesses = self.hits + self.misses;
+        if accesses == 0 {
+            return 0.;
+        }
+        self.hits as f64 / accesses as f64
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} hits, {} misses ({:.2}% hit rate), {} evictions, {} write-backs",
+            self.hits,
+            self.misses,
+            self.hit_rate() * 100.,
+            self.evictions,
+            self.write_backs
+        )
+    }
+}
+
+// Line chosen for eviction once every line of the set is valid
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Replacement {
+    // Least recently used
+    Lru,
+    // First in, first out
+    Fifo,
+    // Pseudo-random, always the same sequence so that the runs are reproducible
+    Random,
+    // Tree pseudo-LRU, the associativity has to be a power of 2
+    Plru,
+}
+
+#[derive(Clone, Default)]
+struct Line {
+    valid: bool,
+    // Address divided by the line size, used as tag
+    block: u32,
+    dirty: bool,
+    // Last use for LRU, allocation for FIFO
+    stamp: u64,
+}
+
+// Line replaced by another one
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Eviction {
+    // Address of the first byte of the line
+    pub address: i32,
+    // The line has to be written to the next level
+    pub dirty: bool,
+}
+
+// Outcome of a lookup
+pub struct Lookup {
+    pub hit: bool,
+    pub evicted: Option<Eviction>,
+    // Index of the line holding the address, unless missed without allocation
+    pub slot: Option<usize>,
+}
+
+// Set-associative cache keeping only the tags, the data stays in the main memory
+pub struct Cache {
+    line: u32,
+    associativity: usize,
+    replacement: Replacement,
+    // Lines of every set, one set after the other
+    lines: Vec<Line>,
+    // Incremented on every access to order the lines
+    clock: u64,
+    // Nodes of the PLRU tree of every set, set if the pseudo-LRU line is on the right
+    trees: Vec<bool>,
+    // State of the xorshift generator used by the random replacement
+    seed: u64,
+    pub stats: Stats,
+}
+
+impl Cache {
+    pub fn new(size: u32, line: u32, associativity: u32, replacement: Replacement) -> Self {
+        let lines = (size / line) as usize;
+        let associativity = associativity as usize;
+        Cache {
+            line,
+            associativity,
+            replacement,
+            lines: vec![Line::default(); lines],
+            clock: 0,
+            trees: vec![false; lines / associativity * (associativity - 1)],
+            seed: 0x2545_f491_4f6c_dd1d,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn line_size(&self) -> usize {
+        self.line as usize
+    }
+
+    // Address of the first byte of the line containing address
+    pub fn line_address(&self, address: i32) -> i32 {
+        address & !(self.line as i32 - 1)
+    }
+
+    // Looks up the line containing address, allocated if missing and allocate is set. A store
+    // marks the line dirty.
+    pub fn access(&mut self, address: i32, allocate: bool, dirty: bool) -> Lookup {
+        let block = address as u32 / self.line;
+        if let Some(slot) = self.find(block) {
+            self.stats.hits += 1;
+            self.lines[slot].dirty |= dirty;
+            self.used(slot);
+            return Lookup {
+                hit: true,
+                evicted: None,
+                slot: Some(slot),
+            };
+        }
+
+        self.stats.misses += 1;
+        if !allocate {
+            return Lookup {
+                hit: false,
+                evicted: None,
+                slot: None,
+            };
+        }
+        let (slot, evicted) = self.allocate(block, dirty);
+        Lookup {
+            hit: false,
+            evicted,
+            slot: Some(slot),
+        }
+    }
+
+    // Places the line containing address without counting an access, e.g. a line evicted from the
+    // level above
+    pub fn insert(&mut self, address: i32, dirty: bool) -> Option<Eviction> {
+        let block = address as u32 / self.line;
+        if let Some(slot) = self.find(block) {
+            self.lines[slot].dirty |= dirty;
+            self.used(slot);
+            return None;
+        }
+        self.allocate(block, dirty).1
+    }
+
+    // Removes the line containing address, returns whether it was dirty if present
+    pub fn remove(&mut self, address: i32) -> Option<bool> {
+        let slot = self.find(address as u32 / self.line)?;
+        self.lines[slot].valid = false;
+        Some(self.lines[slot].dirty)
+    }
+
+    pub fn mark_dirty(&mut self, slot: usize) {
+        self.lines[slot].dirty = true;
+    }
+
+    // Clears the valid bit of every line
+    pub fn invalidate(&mut self) {
+        for line in self.lines.iter_mut() {
+            line.valid = false;
+        }
+    }
+
+    fn find(&self, block: u32) -> Option<usize> {
+        let first = self.set(block) * self.associativity;
+        (first..first + self.associativity)
+            .find(|&slot| self.lines[slot].valid && self.lines[slot].block == block)
+    }
+
+    fn set(&self, block: u32) -> usize {
+        block as usize % (self.lines.len() / self.associativity)
+    }
+
+    // Places a missing block in an invalid line of its set if any, in the victim otherwise
+    fn allocate(&mut self, block: u32, dirty: bool) -> (usize, Option<Eviction>) {
+        let set = self.set(block);
+        let first = set * self.associativity;
+        let slot = match (first..first + self.associativity).find(|&slot| !self.lines[slot].valid) {
+            Some(slot) => slot,
+            None => first + self.victim(set),
+        };
+        let mut evicted = None;
+        if self.lines[slot].valid {
+            self.stats.evictions += 1;
+            if self.lines[slot].dirty {
+                self.stats.write_backs += 1;
+            }
+            evicted = Some(Eviction {
+                address: (self.lines[slot].block * self.line) as i32,
+                dirty: self.lines[slot].dirty,
+            });
+        }
+        self.clock += 1;
+        self.lines[slot] = Line {
+            valid: true,
+            block,
+            dirty,
+            stamp: self.clock,
+        };
+        self.touch(set, slot - first);
+        (slot, evicted)
+    }
+
+    fn used(&mut self, slot: usize) {
+        self.clock += 1;
+        if self.replacement == Replacement::Lru {
+            self.lines[slot].stamp = self.clock;
+        }
+        self.touch(slot / self.associativity, slot % self.associativity);
+    }
+
+    // Way to evict from a full set
+    fn victim(&mut self, set: usize) -> usize {
+        let first = set * self.associativity;
+        match self.replacement {
+            Replacement::Lru | Replacement::Fifo => (0..self.associativity)
+                .min_by_key(|&way| self.lines[first + way].stamp)
+                .unwrap(),
+            Replacement::Random => {
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 7;
+                self.seed ^= self.seed << 17;
+                (self.seed % self.associativity as u64) as usize
+            }
+            Replacement::Plru => {
+                // Follows the nodes down to the pseudo-LRU line
+                let tree = set * (self.associativity - 1);
+                let mut node = 0;
+                let mut way = 0;
+                let mut size = self.associativity;
+                while size > 1 {
+                    size /= 2;
+                    if self.trees[tree + node] {
+                        way += size;
+                        node = 2 * node + 2;
+                    } else {
+                        node = 2 * node + 1;
+                    }
+                }
+                way
+            }
+        }
+    }
+
+    // Points the nodes of the PLRU tree on the path of an accessed way away from it
+    fn touch(&mut self, set: usize, way: usize) {
+        if self.replacement != Replacement::Plru {
+            return;
+        }
+        let tree = set * (self.associativity - 1);
+        let mut node = 0;
+        let mut first = 0;
+        let mut size = self.associativity;
+        while size > 1 {
+            size /= 2;
+            let right = way >= first + size;
+            self.trees[tree + node] = !right;
+            if right {
+                first += size;
+                node = 2 * node + 2;
+            } else {
+                node = 2 * node + 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru() {
+        // 2 sets of 2 lines of 16 bytes
+        let mut cache = Cache::new(64, 16, 2, Replacement::Lru);
+        assert!(!cache.access(0, true, false).hit);
+        assert!(cache.access(12, true, false).hit);
+        assert!(!cache.access(32, true, false).hit);
+        // Another set
+        assert!(!cache.access(16, true, false).hit);
+        // 0 is more recently used than 32, which is evicted
+        assert!(cache.access(0, true, false).hit);
+        assert!(!cache.access(64, true, false).hit);
+        assert!(cache.access(0, true, false).hit);
+        assert!(!cache.access(32, true, false).hit);
+        assert_eq!(
+            Stats {
+                hits: 3,
+                misses: 5,
+                evictions: 2,
+                write_backs: 0,
+            },
+            cache.stats
+        );
+    }
+
+    #[test]
+    fn test_fifo() {
+        // A single set of 2 lines
+        let mut cache = Cache::new(32, 16, 2, Replacement::Fifo);
+        assert!(!cache.access(0, true, false).hit);
+        assert!(!cache.access(16, true, false).hit);
+        assert!(cache.access(0, true, false).hit);
+        // 0 is the oldest line, even if used more recently than 16
+        assert!(!cache.access(32, true, false).hit);
+        assert!(cache.access(16, true, false).hit);
+        assert!(!cache.access(0, true, false).hit);
+    }
+
+    #[test]
+    fn test_plru() {
+        // A single set of 4 lines
+        let mut cache = Cache::new(64, 16, 4, Replacement::Plru);
+        for address in [0, 16, 32, 48] {
+            assert!(!cache.access(address, true, false).hit);
+        }
+        assert!(cache.access(0, true, false).hit);
+        // The tree points to the half of 32 and 48, then to 32, whereas 16 is the least recently
+        // used line
+        assert!(!cache.access(64, true, false).hit);
+        assert!(cache.access(16, true, false).hit);
+        assert!(!cache.access(32, true, false).hit);
+    }
+
+    #[test]
+    fn test_random() {
+        let run = || {
+            let mut cache = Cache::new(64, 16, 4, Replacement::Random);
+            let hits: Vec<bool> = (0..64)
+                .map(|i| cache.access(i * 48 % 160, true, false).hit)
+                .collect();
+            (hits, cache.stats)
+        };
+        let (hits, stats) = run();
+        // Reproducible
+        assert_eq!((hits, stats), run());
+        assert_eq!(64, stats.hits + stats.misses);
+        assert_eq!(stats.misses - 4, stats.evictions);
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let mut cache = Cache::new(64, 16, 2, Replacement::Lru);
+        cache.access(0, true, false);
+        cache.invalidate();
+        assert!(!cache.access(0, true, false).hit);
+        assert_eq!(0, cache.stats.evictions);
+    }
+}
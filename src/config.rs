@@ -0,0 +1,702 @@
+use crate::cache::{Replacement, WritePolicy};
+use crate::hierarchy::Inclusion;
+use crate::opcodes::{functional_unit, FunctionalUnit, InstructionType};
+use crate::predictor::PredictorKind;
+use crate::I5_7360U;
+use enum_map::{Enum, EnumMap};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs;
+
+// Description of the simulated machine, read from a TOML file (see res/config/default.toml).
+// Every parameter defaults to its original value, so a file only has to set the ones it changes.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Clock frequency in Hz, used to convert the cycles into a duration
+    pub frequency: i64,
+    pub latency: Latency,
+    // Latency and issue interval of each class of execution units, by lowercase name in the file
+    #[serde(deserialize_with = "units")]
+    pub units: EnumMap<FunctionalUnit, Unit>,
+    pub l1i: L1iConfig,
+    pub l1d: L1dConfig,
+    // Caches below L1I and L1D, from L2 down to the main memory: [[levels]] in the file
+    pub levels: Vec<LevelConfig>,
+    // Timing of the main memory behind the caches, latency.memory_access if missing
+    pub dram: Option<DramConfig>,
+    pub mvm3: Mvm3Config,
+    pub predictor: PredictorConfig,
+    // Execution cycles of each instruction type: the latency of its unit, unless overridden by
+    // lowercase name in the file (e.g. lw = 50)
+    #[serde(skip)]
+    pub cycles: EnumMap<InstructionType, f32>,
+    #[serde(rename = "cycles", deserialize_with = "instruction_cycles")]
+    overrides: EnumMap<InstructionType, Option<f32>>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Unit {
+    // Cycles until the result is available
+    pub latency: f32,
+    // Cycles until the unit accepts another instruction: 1 if pipelined, the latency if iterative
+    pub interval: f32,
+}
+
+// Unit parameters set in a file, the missing ones keep their default
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UnitOverride {
+    latency: Option<f32>,
+    interval: Option<f32>,
+}
+
+// Cycles spent by the stages outside of the execution
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Latency {
+    // Fetching from the main memory, on top of the L1 access for the VMs with a L1I
+    pub memory_access: f32,
+    pub l1_access: f32,
+    // Write-back of MVM-1 and MVM-2
+    pub register_access: f32,
+    // Decode of MVM-1 and MVM-2
+    pub decode: f32,
+}
+
+// L1 instruction cache of MVM-2 and MVM-3
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct L1iConfig {
+    // Bytes
+    pub size: u32,
+    pub line: u32,
+    // Lines per set
+    pub associativity: u32,
+    pub replacement: Replacement,
+}
+
+// L1 data cache of MVM-2 and MVM-3
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct L1dConfig {
+    // Bytes
+    pub size: u32,
+    pub line: u32,
+    // Lines per set
+    pub associativity: u32,
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+}
+
+// Cache shared by instructions and data
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LevelConfig {
+    // Bytes
+    pub size: u32,
+    pub line: u32,
+    // Lines per set
+    pub associativity: u32,
+    pub replacement: Replacement,
+    // Cycles of an access
+    pub latency: f32,
+    pub inclusion: Inclusion,
+}
+
+// Cycles are CPU cycles
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DramConfig {
+    pub channels: u32,
+    // Banks per channel
+    pub banks: u32,
+    // Bytes of a row
+    pub row_size: u32,
+    // Column access, once the row is open
+    pub t_cas: f32,
+    // Row activation
+    pub t_rcd: f32,
+    // Precharge closing the open row
+    pub t_rp: f32,
+    // Transfer of a line on the data bus of the channel
+    pub t_burst: f32,
+    // Interval between two refreshes, 0 to disable them
+    pub t_refi: f32,
+    // Duration of a refresh
+    pub t_rfc: f32,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mvm3Config {
+    // Number of elements each bus can hold
+    pub decode_bus: usize,
+    pub execute_bus: usize,
+    pub write_bus: usize,
+}
+
+// Branch predictor of MVM-3 and MVM-4
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PredictorConfig {
+    pub kind: PredictorKind,
+    // Counters of the bimodal and gshare tables, and of the chooser of the tournament predictor
+    pub entries: u32,
+    // Bits of global history of gshare
+    pub history: u32,
+    // Entries of the branch target buffer of the jumps, 0 to disable it
+    pub btb_entries: u32,
+    // Depth of the return address stack, 0 to disable it
+    pub ras_depth: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut config = Config {
+            frequency: I5_7360U,
+            latency: Latency::default(),
+            units: EnumMap::from(default_unit),
+            l1i: L1iConfig::default(),
+            l1d: L1dConfig::default(),
+            levels: vec![],
+            dram: None,
+            mvm3: Mvm3Config::default(),
+            predictor: PredictorConfig::default(),
+            cycles: EnumMap::new(),
+            overrides: EnumMap::new(),
+        };
+        config.resolve_cycles();
+        config
+    }
+}
+
+fn default_unit(unit: FunctionalUnit) -> Unit {
+    match unit {
+        FunctionalUnit::Alu => Unit {
+            latency: 1.,
+            interval: 1.,
+        },
+        // Pipelined
+        FunctionalUnit::Multiplier => Unit {
+            latency: 3.,
+            interval: 1.,
+        },
+        // Iterative, blocked until the result is available
+        FunctionalUnit::Divider => Unit {
+            latency: 20.,
+            interval: 20.,
+        },
+        // A single access at a time
+        FunctionalUnit::Memory => Unit {
+            latency: 50.,
+            interval: 50.,
+        },
+    }
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Latency {
+            memory_access: 50.,
+            l1_access: 1.,
+            register_access: 1.,
+            decode: 1.,
+        }
+    }
+}
+
+impl Default for L1iConfig {
+    fn default() -> Self {
+        L1iConfig {
+            size: 32 * 1024,
+            line: 64,
+            associativity: 8,
+            replacement: Replacement::Lru,
+        }
+    }
+}
+
+impl Default for L1dConfig {
+    fn default() -> Self {
+        L1dConfig {
+            size: 32 * 1024,
+            line: 64,
+            associativity: 8,
+            replacement: Replacement::Lru,
+            write_policy: WritePolicy::WriteBack,
+        }
+    }
+}
+
+impl Default for LevelConfig {
+    fn default() -> Self {
+        LevelConfig {
+            size: 256 * 1024,
+            line: 64,
+            associativity: 8,
+            replacement: Replacement::Lru,
+            latency: 12.,
+            inclusion: Inclusion::NonInclusive,
+        }
+    }
+}
+
+// DDR4-2400 at the default frequency
+impl Default for DramConfig {
+    fn default() -> Self {
+        DramConfig {
+            channels: 2,
+            banks: 16,
+            row_size: 8 * 1024,
+            t_cas: 32.,
+            t_rcd: 32.,
+            t_rp: 32.,
+            t_burst: 8.,
+            t_refi: 17940.,
+            t_rfc: 805.,
+        }
+    }
+}
+
+impl Default for Mvm3Config {
+    fn default() -> Self {
+        Mvm3Config {
+            decode_bus: 1,
+            execute_bus: 1,
+            write_bus: 1,
+        }
+    }
+}
+
+impl Default for PredictorConfig {
+    fn default() -> Self {
+        PredictorConfig {
+            kind: PredictorKind::NotTaken,
+            entries: 4096,
+            history: 12,
+            btb_entries: 0,
+            ras_depth: 0,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, String> {
+        let s = fs::read_to_string(path)
+            .map_err(|e| format_args!("unable to read {}: {}", path, e).to_string())?;
+        Config::parse(&s).map_err(|e| format_args!("{}: {}", path, e).to_string())
+    }
+
+    pub fn parse(s: &str) -> Result<Config, String> {
+        let mut config: Config = toml::from_str(s).map_err(|e| e.to_string())?;
+        config.resolve_cycles();
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn resolve_cycles(&mut self) {
+        let units = &self.units;
+        let overrides = &self.overrides;
+        self.cycles = EnumMap::from(|instruction_type: InstructionType| {
+            let latency = units[functional_unit(&instruction_type)].latency;
+            overrides[instruction_type].unwrap_or(latency)
+        });
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.frequency <= 0 {
+            return Err(format_args!("invalid frequency: {}", self.frequency).to_string());
+        }
+        // The MVM-3 units count down the cycles of a step until 0
+        let mut latencies = vec![
+            (
+                "latency.memory_access".to_string(),
+                self.latency.memory_access,
+            ),
+            ("latency.l1_access".to_string(), self.latency.l1_access),
+        ];
+        for (unit, parameters) in self.units.iter() {
+            latencies.push((
+                format_args!("units.{}.latency", name(&unit)).to_string(),
+                parameters.latency,
+            ));
+            latencies.push((
+                format_args!("units.{}.interval", name(&unit)).to_string(),
+                parameters.interval,
+            ));
+        }
+        for (i, level) in self.levels.iter().enumerate() {
+            latencies.push((
+                format_args!("levels[{}].latency", i).to_string(),
+                level.latency,
+            ));
+        }
+        if let Some(dram) = &self.dram {
+            for (name, cycles) in [
+                ("dram.t_cas", dram.t_cas),
+                ("dram.t_rcd", dram.t_rcd),
+                ("dram.t_rp", dram.t_rp),
+                ("dram.t_burst", dram.t_burst),
+            ] {
+                latencies.push((name.to_string(), cycles));
+            }
+        }
+        for (instruction_type, cycles) in self.cycles.iter() {
+            latencies.push((
+                format_args!("cycles.{}", name(&instruction_type)).to_string(),
+                *cycles,
+            ));
+        }
+        for (name, cycles) in latencies {
+            if cycles < 1. || cycles.fract() != 0. {
+                return Err(format_args!(
+                    "{} has to be a whole number of cycles greater than 0: {}",
+                    name, cycles
+                )
+                .to_string());
+            }
+        }
+        for (name, cycles) in [
+            ("latency.register_access", self.latency.register_access),
+            ("latency.decode", self.latency.decode),
+        ] {
+            if cycles < 0. {
+                return Err(format_args!("{} can't be negative: {}", name, cycles).to_string());
+            }
+        }
+        validate_cache(
+            "l1i",
+            self.l1i.size,
+            self.l1i.line,
+            self.l1i.associativity,
+            self.l1i.replacement,
+        )?;
+        validate_cache(
+            "l1d",
+            self.l1d.size,
+            self.l1d.line,
+            self.l1d.associativity,
+            self.l1d.replacement,
+        )?;
+        for (i, level) in self.levels.iter().enumerate() {
+            let name = format_args!("levels[{}]", i).to_string();
+            validate_cache(
+                &name,
+                level.size,
+                level.line,
+                level.associativity,
+                level.replacement,
+            )?;
+            // A line moves between the levels as a whole
+            if level.line != self.l1i.line || level.line != self.l1d.line {
+                return Err(format_args!(
+                    "{}.line has to be the one of l1i and l1d: {}",
+                    name, level.line
+                )
+                .to_string());
+            }
+        }
+        if let Some(dram) = &self.dram {
+            self.validate_dram(dram)?;
+        }
+        if !self.predictor.entries.is_power_of_two() {
+            return Err(format_args!(
+                "predictor.entries has to be a power of 2: {}",
+                self.predictor.entries
+            )
+            .to_string());
+        }
+        if self.predictor.history == 0 || self.predictor.history > 32 {
+            return Err(format_args!(
+                "predictor.history has to be between 1 and 32 bits: {}",
+                self.predictor.history
+            )
+            .to_string());
+        }
+        if self.predictor.btb_entries != 0 && !self.predictor.btb_entries.is_power_of_two() {
+            return Err(format_args!(
+                "predictor.btb_entries has to be a power of 2: {}",
+                self.predictor.btb_entries
+            )
+            .to_string());
+        }
+        for (name, size) in [
+            ("mvm3.decode_bus", self.mvm3.decode_bus),
+            ("mvm3.execute_bus", self.mvm3.execute_bus),
+            ("mvm3.write_bus", self.mvm3.write_bus),
+        ] {
+            if size == 0 {
+                return Err(format_args!("{} can't be empty", name).to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_dram(&self, dram: &DramConfig) -> Result<(), String> {
+        for (name, count) in [("dram.channels", dram.channels), ("dram.banks", dram.banks)] {
+            if count == 0 {
+                return Err(format_args!("{} can't be 0", name).to_string());
+            }
+        }
+        // A line never spans two rows
+        let line = self.l1i.line.max(self.l1d.line);
+        if !dram.row_size.is_power_of_two() || dram.row_size < line {
+            return Err(format_args!(
+                "dram.row_size has to be a power of 2 greater than the line size: {}",
+                dram.row_size
+            )
+            .to_string());
+        }
+        for (name, cycles) in [("dram.t_refi", dram.t_refi), ("dram.t_rfc", dram.t_rfc)] {
+            if cycles < 0. || cycles.fract() != 0. {
+                return Err(format_args!(
+                    "{} has to be a whole number of cycles: {}",
+                    name, cycles
+                )
+                .to_string());
+            }
+        }
+        if dram.t_refi != 0. && dram.t_rfc >= dram.t_refi {
+            return Err(format_args!(
+                "dram.t_rfc has to be lower than dram.t_refi: {}",
+                dram.t_rfc
+            )
+            .to_string());
+        }
+        Ok(())
+    }
+}
+
+fn validate_cache(
+    name: &str,
+    size: u32,
+    line: u32,
+    associativity: u32,
+    replacement: Replacement,
+) -> Result<(), String> {
+    // The accesses are aligned on at most 4 bytes, so they never cross a line
+    if line < 4 || !line.is_power_of_two() {
+        return Err(format_args!(
+            "{}.line has to be a power of 2 greater than 4: {}",
+            name, line
+        )
+        .to_string());
+    }
+    if associativity == 0 || (replacement == Replacement::Plru && !associativity.is_power_of_two())
+    {
+        return Err(format_args!(
+            "{}.associativity has to be a power of 2 with plru, greater than 0 otherwise: {}",
+            name, associativity
+        )
+        .to_string());
+    }
+    if size == 0 || !size.is_multiple_of(line * associativity) {
+        return Err(format_args!(
+            "{}.size has to be a multiple of line * associativity: {}",
+            name, size
+        )
+        .to_string());
+    }
+    Ok(())
+}
+
+// Instruction types and units are named after their variant in lowercase, e.g. fencei
+fn name<T: Debug>(variant: &T) -> String {
+    format!("{:?}", variant).to_lowercase()
+}
+
+fn variant<T: Enum<()> + Debug>(key: &str) -> Option<T> {
+    (0..T::POSSIBLE_VALUES)
+        .map(T::from_usize)
+        .find(|variant| name(variant) == key)
+}
+
+fn units<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<EnumMap<FunctionalUnit, Unit>, D::Error> {
+    let mut units = EnumMap::from(default_unit);
+    for (key, value) in HashMap::<String, UnitOverride>::deserialize(deserializer)? {
+        let unit: FunctionalUnit =
+            variant(&key).ok_or_else(|| D::Error::custom(format_args!("unknown unit: {}", key)))?;
+        units[unit].latency = value.latency.unwrap_or(units[unit].latency);
+        units[unit].interval = value.interval.unwrap_or(units[unit].interval);
+    }
+    Ok(units)
+}
+
+fn instruction_cycles<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<EnumMap<InstructionType, Option<f32>>, D::Error> {
+    let mut overrides = EnumMap::new();
+    for (key, value) in HashMap::<String, f32>::deserialize(deserializer)? {
+        let instruction_type: InstructionType = variant(&key)
+            .ok_or_else(|| D::Error::custom(format_args!("unknown instruction type: {}", key)))?;
+        overrides[instruction_type] = Some(value);
+    }
+    Ok(overrides)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default() {
+        assert_eq!(
+            Config::default(),
+            Config::load("res/config/default.toml").unwrap()
+        );
+        assert_eq!(Config::default(), Config::parse("").unwrap());
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            "frequency = 1000000000
+            [latency]
+            memory_access = 100
+            [mvm3]
+            execute_bus = 2
+            [l1i]
+            replacement = \"plru\"
+            [l1d]
+            write_policy = \"write-through\"
+            [units.divider]
+            interval = 1
+            [cycles]
+            divu = 10
+            fencei = 3",
+        )
+        .unwrap();
+        assert_eq!(1_000_000_000, config.frequency);
+        assert_eq!(100., config.latency.memory_access);
+        assert_eq!(1., config.latency.l1_access);
+        assert_eq!(2, config.mvm3.execute_bus);
+        assert_eq!(WritePolicy::WriteThrough, config.l1d.write_policy);
+        assert_eq!(64, config.l1d.line);
+        assert_eq!(Replacement::Plru, config.l1i.replacement);
+        assert_eq!(Replacement::Lru, config.l1d.replacement);
+        assert_eq!(1, config.mvm3.write_bus);
+        assert_eq!(20., config.units[FunctionalUnit::Divider].latency);
+        assert_eq!(1., config.units[FunctionalUnit::Divider].interval);
+        assert_eq!(20., config.cycles[InstructionType::DIV]);
+        assert_eq!(10., config.cycles[InstructionType::DIVU]);
+        assert_eq!(3., config.cycles[InstructionType::FENCEI]);
+        assert_eq!(3., config.cycles[InstructionType::MUL]);
+        assert_eq!(50., config.cycles[InstructionType::LW]);
+    }
+
+    #[test]
+    fn test_levels() {
+        let config = Config::parse(
+            "[[levels]]
+            [[levels]]
+            size = 4194304
+            associativity = 16
+            latency = 42
+            inclusion = \"inclusive\"",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                LevelConfig::default(),
+                LevelConfig {
+                    size: 4 * 1024 * 1024,
+                    associativity: 16,
+                    latency: 42.,
+                    inclusion: Inclusion::Inclusive,
+                    ..LevelConfig::default()
+                }
+            ],
+            config.levels
+        );
+        assert!(Config::parse("[[levels]]\nlatency = 0").is_err());
+        assert!(Config::parse("[[levels]]\nline = 128").is_err());
+        assert!(Config::parse("[[levels]]\nsize = 1000").is_err());
+        assert!(Config::parse("[[levels]]\ninclusion = \"mostly\"").is_err());
+    }
+
+    #[test]
+    fn test_dram() {
+        assert_eq!(None, Config::default().dram);
+        let config = Config::parse(
+            "[dram]
+            banks = 8
+            t_refi = 0",
+        )
+        .unwrap();
+        assert_eq!(
+            Some(DramConfig {
+                banks: 8,
+                t_refi: 0.,
+                ..DramConfig::default()
+            }),
+            config.dram
+        );
+        assert!(Config::parse("[dram]\nchannels = 0").is_err());
+        assert!(Config::parse("[dram]\nrow_size = 32").is_err());
+        assert!(Config::parse("[dram]\nrow_size = 3000").is_err());
+        assert!(Config::parse("[dram]\nt_cas = 0").is_err());
+        assert!(Config::parse("[dram]\nt_rfc = 20000").is_err());
+        assert!(Config::parse("[dram]\nt_refi = -1").is_err());
+        assert!(Config::parse("[dram]\ntrcd = 1").is_err());
+    }
+
+    #[test]
+    fn test_predictor() {
+        let config = Config::parse(
+            "[predictor]
+            kind = \"tournament\"
+            entries = 1024
+            btb_entries = 512
+            ras_depth = 16",
+        )
+        .unwrap();
+        assert_eq!(
+            PredictorConfig {
+                kind: PredictorKind::Tournament,
+                entries: 1024,
+                history: 12,
+                btb_entries: 512,
+                ras_depth: 16,
+            },
+            config.predictor
+        );
+        assert!(Config::parse("[predictor]\nkind = \"perceptron\"").is_err());
+        assert!(Config::parse("[predictor]\nentries = 1000").is_err());
+        assert!(Config::parse("[predictor]\nentries = 0").is_err());
+        assert!(Config::parse("[predictor]\nhistory = 0").is_err());
+        assert!(Config::parse("[predictor]\nhistory = 33").is_err());
+        assert!(Config::parse("[predictor]\nbtb_entries = 100").is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Config::parse("frequency = 0").is_err());
+        assert!(Config::parse("clock = 1").is_err());
+        assert!(Config::parse("[l1i]\nways = 1").is_err());
+        assert!(Config::parse("[cycles]\nfoo = 1").is_err());
+        assert!(Config::parse("[cycles]\nadd = 0").is_err());
+        assert!(Config::parse("[cycles]\nadd = 1.5").is_err());
+        assert!(Config::parse("[units.fpu]\nlatency = 1").is_err());
+        assert!(Config::parse("[units.divider]\nlatency = 0").is_err());
+        assert!(Config::parse("[units.divider]\nissue = 1").is_err());
+        assert!(Config::parse("[latency]\ndecode = -1").is_err());
+        assert!(Config::parse("[mvm3]\nwrite_bus = 0").is_err());
+        assert!(Config::parse("[l1d]\nline = 2").is_err());
+        assert!(Config::parse("[l1d]\nline = 48").is_err());
+        assert!(Config::parse("[l1d]\nassociativity = 0").is_err());
+        assert!(Config::parse("[l1d]\nsize = 1000").is_err());
+        assert!(Config::parse("[l1d]\nwrite_policy = \"write-around\"").is_err());
+        assert!(Config::parse("[l1i]\nreplacement = \"mru\"").is_err());
+        assert!(Config::parse("[l1i]\nassociativity = 3\nsize = 768").is_ok());
+        assert!(
+            Config::parse("[l1i]\nassociativity = 3\nsize = 768\nreplacement = \"plru\"").is_err()
+        );
+        assert!(Config::load("res/config/missing.toml").is_err());
+    }
+}
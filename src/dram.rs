@@ -0,0 +1,260 @@
+use crate::config::DramConfig;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    // The row was open
+    pub row_hits: u64,
+    // The bank had no open row
+    pub row_misses: u64,
+    // Another row had to be closed first
+    pub row_conflicts: u64,
+    pub refreshes: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} row hits, {} row misses, {} row conflicts, {} refreshes",
+            self.row_hits, self.row_misses, self.row_conflicts, self.refreshes
+        )
+    }
+}
+
+#[derive(Clone, Default)]
+struct Bank {
+    open_row: Option<u32>,
+    // Cycle from which the bank accepts another command
+    ready: f32,
+}
+
+struct Channel {
+    banks: Vec<Bank>,
+    // Cycle from which the data bus is free
+    bus_ready: f32,
+}
+
+struct Request {
+    address: i32,
+    // Cycle of the request
+    arrival: f32,
+}
+
+// Main memory split into channels of banks, each bank keeping its last row open. Consecutive lines
+// are spread over the channels then the banks, and a row holds row_size bytes of each bank.
+// All the timings are in CPU cycles.
+pub struct Dram {
+    channels: Vec<Channel>,
+    row_size: u32,
+    t_cas: f32,
+    t_rcd: f32,
+    t_rp: f32,
+    t_burst: f32,
+    t_refi: f32,
+    t_rfc: f32,
+    // Number of refresh intervals elapsed
+    refreshed: u64,
+    queue: Vec<Request>,
+    pub stats: Stats,
+}
+
+impl Dram {
+    pub fn new(config: &DramConfig) -> Self {
+        Dram {
+            channels: (0..config.channels)
+                .map(|_| Channel {
+                    banks: vec![Bank::default(); config.banks as usize],
+                    bus_ready: 0.,
+                })
+                .collect(),
+            row_size: config.row_size,
+            t_cas: config.t_cas,
+            t_rcd: config.t_rcd,
+            t_rp: config.t_rp,
+            t_burst: config.t_burst,
+            t_refi: config.t_refi,
+            t_rfc: config.t_rfc,
+            refreshed: 0,
+            queue: vec![],
+            stats: Stats::default(),
+        }
+    }
+
+    // Queues a read or a write of the line at address
+    pub fn request(&mut self, address: i32, now: f32) {
+        self.queue.push(Request {
+            address,
+            arrival: now,
+        });
+    }
+
+    // Serves the queued requests, returns the cycles from now until the last one completes
+    pub fn schedule(&mut self, now: f32) -> f32 {
+        let mut end = now;
+        while !self.queue.is_empty() {
+            // FR-FCFS: the oldest request to an open row first, the oldest request otherwise
+            let idx = (0..self.queue.len())
+                .find(|&idx| self.row_hit(self.queue[idx].address))
+                .unwrap_or(0);
+            let request = self.queue.remove(idx);
+            end = end.max(self.serve(&request));
+        }
+        end - now
+    }
+
+    fn row_hit(&self, address: i32) -> bool {
+        let (channel, bank, row) = self.map(address);
+        self.channels[channel].banks[bank].open_row == Some(row)
+    }
+
+    // Channel, bank and row of an address
+    fn map(&self, address: i32) -> (usize, usize, u32) {
+        let chunk = address as u32 / self.row_size;
+        let channels = self.channels.len() as u32;
+        let banks = self.channels[0].banks.len() as u32;
+        let channel = chunk % channels;
+        let bank = chunk / channels % banks;
+        let row = chunk / channels / banks;
+        (channel as usize, bank as usize, row)
+    }
+
+    // Returns the cycle at which the data of the request is transferred
+    fn serve(&mut self, request: &Request) -> f32 {
+        let (channel, bank, row) = self.map(request.address);
+        let start = request
+            .arrival
+            .max(self.channels[channel].banks[bank].ready);
+        let start = self.refresh(start);
+
+        let state = &mut self.channels[channel].banks[bank];
+        let mut latency = self.t_cas;
+        match state.open_row {
+            Some(open_row) if open_row == row => self.stats.row_hits += 1,
+            None => {
+                self.stats.row_misses += 1;
+                latency += self.t_rcd;
+            }
+            Some(_) => {
+                self.stats.row_conflicts += 1;
+                latency += self.t_rp + self.t_rcd;
+            }
+        }
+        state.open_row = Some(row);
+
+        // The banks of a channel share its data bus
+        let transfer = (start + latency).max(self.channels[channel].bus_ready);
+        let end = transfer + self.t_burst;
+        self.channels[channel].bus_ready = end;
+        self.channels[channel].banks[bank].ready = end;
+        end
+    }
+
+    // Every t_refi cycles, the rows are closed and the banks are refreshed during t_rfc cycles.
+    // Returns the cycle from which a command issued at start can proceed.
+    fn refresh(&mut self, start: f32) -> f32 {
+        if self.t_refi == 0. {
+            return start;
+        }
+        let period = (start / self.t_refi).floor() as u64;
+        if period > self.refreshed {
+            self.stats.refreshes += period - self.refreshed;
+            self.refreshed = period;
+            for channel in self.channels.iter_mut() {
+                for bank in channel.banks.iter_mut() {
+                    bank.open_row = None;
+                }
+            }
+        }
+        let refresh_end = period as f32 * self.t_refi + self.t_rfc;
+        if period > 0 && start < refresh_end {
+            return refresh_end;
+        }
+        start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dram(refresh: bool) -> Dram {
+        let mut config = DramConfig {
+            channels: 2,
+            banks: 2,
+            row_size: 1024,
+            t_cas: 10.,
+            t_rcd: 20.,
+            t_rp: 30.,
+            t_burst: 4.,
+            t_refi: 0.,
+            t_rfc: 0.,
+        };
+        if refresh {
+            config.t_refi = 1000.;
+            config.t_rfc = 100.;
+        }
+        Dram::new(&config)
+    }
+
+    fn access(dram: &mut Dram, address: i32, now: f32) -> f32 {
+        dram.request(address, now);
+        dram.schedule(now)
+    }
+
+    #[test]
+    fn test_rows() {
+        let mut dram = dram(false);
+        // Channel 0, bank 0, row 0
+        assert_eq!(34., access(&mut dram, 0, 0.));
+        assert_eq!(14., access(&mut dram, 64, 100.));
+        // Channel 0, bank 0, row 1
+        assert_eq!(64., access(&mut dram, 4096, 200.));
+        // Channel 1, bank 0, row 0
+        assert_eq!(34., access(&mut dram, 1024, 300.));
+        // Channel 0, bank 1, row 0
+        assert_eq!(34., access(&mut dram, 2048, 400.));
+        assert_eq!(
+            Stats {
+                row_hits: 1,
+                row_misses: 3,
+                row_conflicts: 1,
+                refreshes: 0,
+            },
+            dram.stats
+        );
+    }
+
+    #[test]
+    fn test_busy() {
+        let mut dram = dram(false);
+        assert_eq!(34., access(&mut dram, 0, 0.));
+        // The bank is busy until 34
+        assert_eq!(14. + 24., access(&mut dram, 64, 10.));
+        // Another bank, ready at 40, but the bus of the channel is busy until 48
+        assert_eq!(48. + 4. - 10., access(&mut dram, 2048, 10.));
+    }
+
+    #[test]
+    fn test_fr_fcfs() {
+        let mut dram = dram(false);
+        access(&mut dram, 0, 0.);
+        // The row hit is served before the older row conflict, which completes last
+        dram.request(4096, 100.);
+        dram.request(64, 100.);
+        assert_eq!(14. + 64., dram.schedule(100.));
+        assert_eq!(1, dram.stats.row_hits);
+        assert_eq!(1, dram.stats.row_conflicts);
+    }
+
+    #[test]
+    fn test_refresh() {
+        let mut dram = dram(true);
+        assert_eq!(34., access(&mut dram, 0, 0.));
+        assert_eq!(14., access(&mut dram, 64, 500.));
+        // Waits for the end of the refresh, which closed the row
+        assert_eq!(50. + 34., access(&mut dram, 64, 1050.));
+        assert_eq!(34., access(&mut dram, 0, 3500.));
+        assert_eq!(3, dram.stats.refreshes);
+    }
+}
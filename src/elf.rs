@@ -0,0 +1,204 @@
+use crate::encoding::decode_or_illegal;
+use crate::error::ElfError;
+use crate::opcodes::*;
+
+pub const MAGIC: &[u8] = b"\x7fELF";
+
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 0xf3;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+
+const EHDR_SIZE: usize = 52;
+const PHDR_SIZE: usize = 32;
+
+struct ProgramHeader {
+    kind: u32,
+    offset: usize,
+    address: usize,
+    file_size: usize,
+    memory_size: usize,
+    flags: u32,
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Loads a statically linked RV32 executable. Each PT_LOAD segment becomes a segment copied into
+// memory (zero-filled up to its memory size) and the executable segments are decoded into
+// instructions. Words that don't decode are kept as illegal instructions, as an executable
+// segment may also contain read-only data.
+pub fn load(bytes: &[u8]) -> Result<Application, ElfError> {
+    if bytes.len() < EHDR_SIZE || !is_elf(bytes) {
+        return Err(ElfError::BadMagic);
+    }
+    if bytes[4] != ELFCLASS32 || bytes[5] != ELFDATA2LSB {
+        return Err(ElfError::WrongClass);
+    }
+    if u16_at(bytes, 16) != ET_EXEC {
+        return Err(ElfError::NotExecutable);
+    }
+    let machine = u16_at(bytes, 18);
+    if machine != EM_RISCV {
+        return Err(ElfError::WrongMachine(machine));
+    }
+
+    let entry = u32_at(bytes, 24) as usize;
+    let program_headers = program_headers(bytes)?;
+
+    let mut segments = vec![];
+    let mut text: Option<(usize, usize)> = None;
+    for header in program_headers.iter().filter(|h| h.kind == PT_LOAD) {
+        if header.file_size > header.memory_size || header.offset + header.file_size > bytes.len() {
+            return Err(ElfError::InvalidSegment(header.address));
+        }
+        let mut segment: Vec<i8> = bytes[header.offset..header.offset + header.file_size]
+            .iter()
+            .map(|b| *b as i8)
+            .collect();
+        // The remaining bytes (e.g. .bss) are zero-initialized
+        segment.resize(header.memory_size, 0);
+        segments.push(Segment {
+            address: header.address,
+            bytes: segment,
+        });
+
+        if header.flags & PF_X != 0 {
+            let end = header.address + header.file_size;
+            text = Some(match text {
+                Some((start, previous_end)) => (start.min(header.address), previous_end.max(end)),
+                None => (header.address, end),
+            });
+        }
+    }
+
+    let (text_start, text_end) = match text {
+        Some(v) => v,
+        None => return Err(ElfError::NoExecutableSegment),
+    };
+    if entry < text_start || entry >= text_end {
+        return Err(ElfError::EntryOutOfText(entry));
+    }
+    if text_start % 4 != 0 {
+        return Err(ElfError::MisalignedText(text_start));
+    }
+
+    let mut instructions: Vec<Box<dyn InstructionRunner>> = vec![];
+    let mut address = text_start;
+    while address + 4 <= text_end {
+        instructions.push(decode_or_illegal(word_at(&segments, address)));
+        address += 4;
+    }
+
+    Ok(Application {
+        instructions,
+        segments,
+        text_address: Some(text_start as i32),
+        entry: (entry - text_start) as i32,
+    })
+}
+
+fn program_headers(bytes: &[u8]) -> Result<Vec<ProgramHeader>, ElfError> {
+    let offset = u32_at(bytes, 28) as usize;
+    let entry_size = u16_at(bytes, 42) as usize;
+    let count = u16_at(bytes, 44) as usize;
+    if entry_size < PHDR_SIZE || offset + entry_size * count > bytes.len() {
+        return Err(ElfError::InvalidProgramHeaders);
+    }
+
+    let mut headers = vec![];
+    for i in 0..count {
+        let start = offset + i * entry_size;
+        headers.push(ProgramHeader {
+            kind: u32_at(bytes, start),
+            offset: u32_at(bytes, start + 4) as usize,
+            address: u32_at(bytes, start + 8) as usize,
+            file_size: u32_at(bytes, start + 16) as usize,
+            memory_size: u32_at(bytes, start + 20) as usize,
+            flags: u32_at(bytes, start + 24),
+        });
+    }
+    Ok(headers)
+}
+
+// Reads a word from the loaded segments, the bytes outside of any segment are zeros
+fn word_at(segments: &Vec<Segment>, address: usize) -> u32 {
+    let mut word = 0;
+    for i in 0..4 {
+        let byte_address = address + i;
+        for segment in segments {
+            if byte_address >= segment.address
+                && byte_address < segment.address + segment.bytes.len()
+            {
+                word |= (segment.bytes[byte_address - segment.address] as u8 as u32) << (8 * i);
+            }
+        }
+    }
+    word
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn hello() -> Vec<u8> {
+        fs::read("res/elf/hello").unwrap()
+    }
+
+    #[test]
+    fn test_load() {
+        let application = load(&hello()).unwrap();
+        assert_eq!(0x74, application.entry);
+        assert_eq!(Some(0x10000), application.text_address);
+        assert_eq!(2, application.segments.len());
+        assert_eq!(0x11000, application.segments[1].address);
+        // .bss is zero-filled
+        assert_eq!(64, application.segments[1].bytes.len());
+        assert_eq!(
+            "Illegal { word: 1179403647 }",
+            format!("{:?}", application.instructions[0])
+        );
+        assert_eq!(
+            "Lui { rd: A1, imm: 17 }",
+            format!("{:?}", application.instructions[0x74 / 4])
+        );
+    }
+
+    #[test]
+    fn test_load_errors() {
+        assert_eq!(ElfError::BadMagic, load(b"#!/bin/sh").err().unwrap());
+
+        let mut bytes = hello();
+        // ELF64
+        bytes[4] = 2;
+        assert_eq!(ElfError::WrongClass, load(&bytes).err().unwrap());
+
+        let mut bytes = hello();
+        // x86
+        bytes[18] = 3;
+        assert_eq!(ElfError::WrongMachine(3), load(&bytes).err().unwrap());
+
+        let bytes = hello();
+        assert_eq!(
+            ElfError::InvalidProgramHeaders,
+            load(&bytes[..100]).err().unwrap()
+        );
+    }
+}
@@ -0,0 +1,705 @@
+use crate::error::EncodeError;
+use crate::opcodes::*;
+use enum_map::Enum;
+
+// Major opcodes (bits 0-6) of the RV32IM base formats
+pub const OPCODE_LOAD: u32 = 0b0000011;
+pub const OPCODE_MISC_MEM: u32 = 0b0001111;
+pub const OPCODE_OP_IMM: u32 = 0b0010011;
+pub const OPCODE_AUIPC: u32 = 0b0010111;
+pub const OPCODE_STORE: u32 = 0b0100011;
+pub const OPCODE_OP: u32 = 0b0110011;
+pub const OPCODE_LUI: u32 = 0b0110111;
+pub const OPCODE_BRANCH: u32 = 0b1100011;
+pub const OPCODE_JALR: u32 = 0b1100111;
+pub const OPCODE_JAL: u32 = 0b1101111;
+pub const OPCODE_SYSTEM: u32 = 0b1110011;
+
+pub const FUNCT7_BASE: u32 = 0b0000000;
+pub const FUNCT7_ALT: u32 = 0b0100000;
+pub const FUNCT7_MULDIV: u32 = 0b0000001;
+
+pub const NOP: u32 = 0x00000013;
+pub const ECALL: u32 = 0x00000073;
+pub const EBREAK: u32 = 0x00100073;
+// fence iorw, iorw
+pub const FENCE: u32 = 0x0ff0000f;
+pub const FENCE_I: u32 = 0x0000100f;
+
+pub fn r_type(
+    opcode: u32,
+    funct3: u32,
+    funct7: u32,
+    rd: RegisterType,
+    rs1: RegisterType,
+    rs2: RegisterType,
+) -> u32 {
+    funct7 << 25
+        | register(rs2) << 20
+        | register(rs1) << 15
+        | funct3 << 12
+        | register(rd) << 7
+        | opcode
+}
+
+pub fn i_type(
+    opcode: u32,
+    funct3: u32,
+    rd: RegisterType,
+    rs1: RegisterType,
+    imm: i32,
+) -> Result<u32, EncodeError> {
+    check_range(imm, 12)?;
+    Ok(
+        (imm as u32 & 0xfff) << 20
+            | register(rs1) << 15
+            | funct3 << 12
+            | register(rd) << 7
+            | opcode,
+    )
+}
+
+// Shifts by an immediate are I-type instructions where the upper 7 bits hold a funct7
+pub fn shift_type(
+    funct3: u32,
+    funct7: u32,
+    rd: RegisterType,
+    rs1: RegisterType,
+    shamt: i32,
+) -> Result<u32, EncodeError> {
+    if !(0..32).contains(&shamt) {
+        return Err(EncodeError::InvalidShiftAmount(shamt));
+    }
+    Ok(r_type(OPCODE_OP_IMM, funct3, funct7, rd, rs1, RegisterType::ZERO) | (shamt as u32) << 20)
+}
+
+pub fn s_type(
+    funct3: u32,
+    rs1: RegisterType,
+    rs2: RegisterType,
+    imm: i32,
+) -> Result<u32, EncodeError> {
+    check_range(imm, 12)?;
+    let imm = imm as u32;
+    Ok((imm >> 5 & 0x7f) << 25
+        | register(rs2) << 20
+        | register(rs1) << 15
+        | funct3 << 12
+        | (imm & 0x1f) << 7
+        | OPCODE_STORE)
+}
+
+pub fn b_type(
+    funct3: u32,
+    rs1: RegisterType,
+    rs2: RegisterType,
+    offset: i32,
+) -> Result<u32, EncodeError> {
+    check_range(offset, 13)?;
+    check_alignment(offset)?;
+    let imm = offset as u32;
+    Ok((imm >> 12 & 0x1) << 31
+        | (imm >> 5 & 0x3f) << 25
+        | register(rs2) << 20
+        | register(rs1) << 15
+        | funct3 << 12
+        | (imm >> 1 & 0xf) << 8
+        | (imm >> 11 & 0x1) << 7
+        | OPCODE_BRANCH)
+}
+
+// The immediate is the value of the upper 20 bits, as used by lui and auipc
+pub fn u_type(opcode: u32, rd: RegisterType, imm: i32) -> Result<u32, EncodeError> {
+    if !(-(1 << 19)..(1 << 20)).contains(&imm) {
+        return Err(EncodeError::ImmediateOutOfRange { imm, bits: 20 });
+    }
+    Ok((imm as u32) << 12 | register(rd) << 7 | opcode)
+}
+
+pub fn j_type(rd: RegisterType, offset: i32) -> Result<u32, EncodeError> {
+    check_range(offset, 21)?;
+    check_alignment(offset)?;
+    let imm = offset as u32;
+    Ok((imm >> 20 & 0x1) << 31
+        | (imm >> 1 & 0x3ff) << 21
+        | (imm >> 11 & 0x1) << 20
+        | (imm >> 12 & 0xff) << 12
+        | register(rd) << 7
+        | OPCODE_JAL)
+}
+
+// Operands of an encoded instruction. imm is the sign-extended immediate of its format, the shift
+// amount of the shifts and the upper 20 bits of lui and auipc, 0 without immediate.
+pub struct Fields {
+    pub instruction_type: InstructionType,
+    pub rd: RegisterType,
+    pub rs1: RegisterType,
+    pub rs2: RegisterType,
+    pub imm: i32,
+}
+
+// Decodes the type and the operands of a 32-bit word, never ILLEGAL. It's the single decoder shared
+// by decode and fast::decode.
+pub fn decode_fields(word: u32) -> Result<Fields, Exception> {
+    let opcode = word & 0x7f;
+    let funct3 = word >> 12 & 0x7;
+    let funct7 = word >> 25;
+    let illegal = Exception::IllegalInstruction(word);
+
+    let (instruction_type, imm) = match opcode {
+        OPCODE_OP => {
+            let instruction_type = match (funct7, funct3) {
+                (FUNCT7_BASE, 0x0) => InstructionType::ADD,
+                (FUNCT7_ALT, 0x0) => InstructionType::SUB,
+                (FUNCT7_BASE, 0x1) => InstructionType::SLL,
+                (FUNCT7_BASE, 0x2) => InstructionType::SLT,
+                (FUNCT7_BASE, 0x3) => InstructionType::SLTU,
+                (FUNCT7_BASE, 0x4) => InstructionType::XOR,
+                (FUNCT7_BASE, 0x5) => InstructionType::SRL,
+                (FUNCT7_ALT, 0x5) => InstructionType::SRA,
+                (FUNCT7_BASE, 0x6) => InstructionType::OR,
+                (FUNCT7_BASE, 0x7) => InstructionType::AND,
+                (FUNCT7_MULDIV, 0x0) => InstructionType::MUL,
+                (FUNCT7_MULDIV, 0x1) => InstructionType::MULH,
+                (FUNCT7_MULDIV, 0x2) => InstructionType::MULHSU,
+                (FUNCT7_MULDIV, 0x3) => InstructionType::MULHU,
+                (FUNCT7_MULDIV, 0x4) => InstructionType::DIV,
+                (FUNCT7_MULDIV, 0x5) => InstructionType::DIVU,
+                (FUNCT7_MULDIV, 0x6) => InstructionType::REM,
+                (FUNCT7_MULDIV, 0x7) => InstructionType::REMU,
+                _ => return Err(illegal),
+            };
+            (instruction_type, 0)
+        }
+        OPCODE_OP_IMM => {
+            let imm = i_immediate(word);
+            let shamt = (word >> 20 & 0x1f) as i32;
+            match (funct3, funct7) {
+                _ if word == NOP => (InstructionType::NOP, 0),
+                (0x0, _) => (InstructionType::ADDI, imm),
+                (0x2, _) => (InstructionType::SLTI, imm),
+                (0x3, _) => (InstructionType::SLTIU, imm),
+                (0x4, _) => (InstructionType::XORI, imm),
+                (0x6, _) => (InstructionType::ORI, imm),
+                (0x7, _) => (InstructionType::ANDI, imm),
+                (0x1, FUNCT7_BASE) => (InstructionType::SLLI, shamt),
+                (0x5, FUNCT7_BASE) => (InstructionType::SRLI, shamt),
+                (0x5, FUNCT7_ALT) => (InstructionType::SRAI, shamt),
+                _ => return Err(illegal),
+            }
+        }
+        OPCODE_LOAD => {
+            let instruction_type = match funct3 {
+                0x0 => InstructionType::LB,
+                0x1 => InstructionType::LH,
+                0x2 => InstructionType::LW,
+                0x4 => InstructionType::LBU,
+                0x5 => InstructionType::LHU,
+                _ => return Err(illegal),
+            };
+            (instruction_type, i_immediate(word))
+        }
+        OPCODE_STORE => {
+            let instruction_type = match funct3 {
+                0x0 => InstructionType::SB,
+                0x1 => InstructionType::SH,
+                0x2 => InstructionType::SW,
+                _ => return Err(illegal),
+            };
+            (instruction_type, s_immediate(word))
+        }
+        OPCODE_BRANCH => {
+            let instruction_type = match funct3 {
+                0x0 => InstructionType::BEQ,
+                0x1 => InstructionType::BNE,
+                0x4 => InstructionType::BLT,
+                0x5 => InstructionType::BGE,
+                0x6 => InstructionType::BLTU,
+                0x7 => InstructionType::BGEU,
+                _ => return Err(illegal),
+            };
+            (instruction_type, b_immediate(word))
+        }
+        OPCODE_JAL => (InstructionType::JAL, j_immediate(word)),
+        OPCODE_JALR if funct3 == 0 => (InstructionType::JALR, i_immediate(word)),
+        OPCODE_LUI => (InstructionType::LUI, word as i32 >> 12),
+        OPCODE_AUIPC => (InstructionType::AUIPC, word as i32 >> 12),
+        // The predecessor and successor sets are ignored, see Fence
+        OPCODE_MISC_MEM if funct3 == 0 => (InstructionType::FENCE, 0),
+        OPCODE_MISC_MEM if funct3 == 1 => (InstructionType::FENCEI, 0),
+        OPCODE_SYSTEM if word == ECALL => (InstructionType::ECALL, 0),
+        OPCODE_SYSTEM if word == EBREAK => (InstructionType::EBREAK, 0),
+        _ => return Err(illegal),
+    };
+    Ok(Fields {
+        instruction_type,
+        rd: register_type(word >> 7),
+        rs1: register_type(word >> 15),
+        rs2: register_type(word >> 20),
+        imm,
+    })
+}
+
+// Decodes a 32-bit word into the matching instruction. Branch and jump offsets are relative to
+// the pc of the instruction.
+pub fn decode(word: u32) -> Result<Box<dyn InstructionRunner>, Exception> {
+    let Fields {
+        instruction_type,
+        rd,
+        rs1,
+        rs2,
+        imm,
+    } = decode_fields(word)?;
+    let rs = rs1;
+    let offset = imm;
+
+    let instruction: Box<dyn InstructionRunner> = match instruction_type {
+        InstructionType::ADD => Box::new(Add { rd, rs1, rs2 }),
+        InstructionType::SUB => Box::new(Sub { rd, rs1, rs2 }),
+        InstructionType::SLL => Box::new(Sll { rd, rs1, rs2 }),
+        InstructionType::SLT => Box::new(Slt { rd, rs1, rs2 }),
+        InstructionType::SLTU => Box::new(Sltu { rd, rs1, rs2 }),
+        InstructionType::XOR => Box::new(Xor { rd, rs1, rs2 }),
+        InstructionType::SRL => Box::new(Srl { rd, rs1, rs2 }),
+        InstructionType::SRA => Box::new(Sra { rd, rs1, rs2 }),
+        InstructionType::OR => Box::new(Or { rd, rs1, rs2 }),
+        InstructionType::AND => Box::new(And { rd, rs1, rs2 }),
+        InstructionType::MUL => Box::new(Mul { rd, rs1, rs2 }),
+        InstructionType::MULH => Box::new(Mulh { rd, rs1, rs2 }),
+        InstructionType::MULHSU => Box::new(Mulhsu { rd, rs1, rs2 }),
+        InstructionType::MULHU => Box::new(Mulhu { rd, rs1, rs2 }),
+        InstructionType::DIV => Box::new(Div { rd, rs1, rs2 }),
+        InstructionType::DIVU => Box::new(Divu { rd, rs1, rs2 }),
+        InstructionType::REM => Box::new(Rem { rd, rs1, rs2 }),
+        InstructionType::REMU => Box::new(Remu { rd, rs1, rs2 }),
+        InstructionType::NOP => Box::new(Nop {}),
+        InstructionType::ADDI => Box::new(Addi { imm, rd, rs }),
+        InstructionType::SLTI => Box::new(Slti { rd, rs, imm }),
+        InstructionType::SLTIU => Box::new(Sltiu { rd, rs, imm }),
+        InstructionType::XORI => Box::new(Xori { imm, rd, rs }),
+        InstructionType::ORI => Box::new(Ori { imm, rd, rs }),
+        InstructionType::ANDI => Box::new(Andi { imm, rd, rs }),
+        InstructionType::SLLI => Box::new(Slli { rd, rs, imm }),
+        InstructionType::SRLI => Box::new(Srli { rd, rs, imm }),
+        InstructionType::SRAI => Box::new(Srai { rd, rs, imm }),
+        InstructionType::LB => Box::new(Lb { rd, offset, rs1 }),
+        InstructionType::LH => Box::new(Lh { rd, offset, rs1 }),
+        InstructionType::LW => Box::new(Lw { rd, offset, rs1 }),
+        InstructionType::LBU => Box::new(Lbu { rd, offset, rs1 }),
+        InstructionType::LHU => Box::new(Lhu { rd, offset, rs1 }),
+        InstructionType::SB => Box::new(Sb { rs2, offset, rs1 }),
+        InstructionType::SH => Box::new(Sh { rs2, offset, rs1 }),
+        InstructionType::SW => Box::new(Sw { rs2, offset, rs1 }),
+        InstructionType::BEQ => Box::new(Beq { rs1, rs2, offset }),
+        InstructionType::BNE => Box::new(Bne { rs1, rs2, offset }),
+        InstructionType::BLT => Box::new(Blt { rs1, rs2, offset }),
+        InstructionType::BGE => Box::new(Bge { rs1, rs2, offset }),
+        InstructionType::BLTU => Box::new(Bltu { rs1, rs2, offset }),
+        InstructionType::BGEU => Box::new(Bgeu { rs1, rs2, offset }),
+        InstructionType::JAL => Box::new(Jal { offset, rd }),
+        InstructionType::JALR => Box::new(Jalr { rd, rs, imm }),
+        InstructionType::LUI => Box::new(Lui { rd, imm }),
+        InstructionType::AUIPC => Box::new(Auipc { rd, imm }),
+        InstructionType::FENCE => Box::new(Fence {}),
+        InstructionType::FENCEI => Box::new(FenceI {}),
+        InstructionType::ECALL => Box::new(Ecall {}),
+        InstructionType::EBREAK => Box::new(Ebreak {}),
+        InstructionType::ILLEGAL => return Err(Exception::IllegalInstruction(word)),
+    };
+    Ok(instruction)
+}
+
+// Decodes a fetched word, a word that doesn't decode traps once executed
+pub fn decode_or_illegal(word: u32) -> Box<dyn InstructionRunner> {
+    match decode(word) {
+        Ok(instruction) => instruction,
+        Err(_) => Box::new(Illegal { word }),
+    }
+}
+
+// Sign-extended immediates of the instruction formats
+pub fn i_immediate(word: u32) -> i32 {
+    word as i32 >> 20
+}
+
+pub fn s_immediate(word: u32) -> i32 {
+    ((word & 0xfe000000) as i32 >> 20) | (word >> 7 & 0x1f) as i32
+}
+
+pub fn b_immediate(word: u32) -> i32 {
+    ((word & 0x80000000) as i32 >> 19)
+        | ((word & 0x80) << 4) as i32
+        | (word >> 20 & 0x7e0) as i32
+        | (word >> 7 & 0x1e) as i32
+}
+
+pub fn j_immediate(word: u32) -> i32 {
+    ((word & 0x80000000) as i32 >> 11)
+        | (word & 0xff000) as i32
+        | (word >> 9 & 0x800) as i32
+        | (word >> 20 & 0x7fe) as i32
+}
+
+fn register(register: RegisterType) -> u32 {
+    Enum::<()>::to_usize(register) as u32
+}
+
+fn register_type(bits: u32) -> RegisterType {
+    <RegisterType as Enum<()>>::from_usize((bits & 0x1f) as usize)
+}
+
+fn check_range(imm: i32, bits: u32) -> Result<(), EncodeError> {
+    let limit = 1 << (bits - 1);
+    if imm < -limit || imm >= limit {
+        return Err(EncodeError::ImmediateOutOfRange { imm, bits });
+    }
+    Ok(())
+}
+
+fn check_alignment(offset: i32) -> Result<(), EncodeError> {
+    if offset % 2 != 0 {
+        return Err(EncodeError::MisalignedOffset(offset));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(instruction: Box<dyn InstructionRunner>) {
+        let word = instruction.encode().unwrap();
+        let decoded = decode(word).unwrap();
+        assert_eq!(format!("{:?}", instruction), format!("{:?}", decoded));
+        assert_eq!(word, decoded.encode().unwrap());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        use RegisterType::*;
+        let instructions: Vec<Box<dyn InstructionRunner>> = vec![
+            Box::new(Add {
+                rd: T0,
+                rs1: A1,
+                rs2: T6,
+            }),
+            Box::new(Addi {
+                imm: -2048,
+                rd: SP,
+                rs: SP,
+            }),
+            Box::new(And {
+                rd: S11,
+                rs1: A0,
+                rs2: RA,
+            }),
+            Box::new(Andi {
+                imm: 2047,
+                rd: A5,
+                rs: T2,
+            }),
+            Box::new(Auipc { rd: T1, imm: -1 }),
+            Box::new(Beq {
+                rs1: A0,
+                rs2: ZERO,
+                offset: -4096,
+            }),
+            Box::new(Bge {
+                rs1: T0,
+                rs2: T1,
+                offset: 4094,
+            }),
+            Box::new(Bgeu {
+                rs1: S1,
+                rs2: S2,
+                offset: -2,
+            }),
+            Box::new(Blt {
+                rs1: A6,
+                rs2: A7,
+                offset: 2048,
+            }),
+            Box::new(Bltu {
+                rs1: T3,
+                rs2: T4,
+                offset: -2050,
+            }),
+            Box::new(Bne {
+                rs1: GP,
+                rs2: TP,
+                offset: 12,
+            }),
+            Box::new(Div {
+                rd: A0,
+                rs1: A1,
+                rs2: A2,
+            }),
+            Box::new(Divu {
+                rd: A3,
+                rs1: A4,
+                rs2: A5,
+            }),
+            Box::new(Ecall {}),
+            Box::new(Ebreak {}),
+            Box::new(Fence {}),
+            Box::new(FenceI {}),
+            Box::new(Jal {
+                offset: -1048576,
+                rd: RA,
+            }),
+            Box::new(Jal {
+                offset: 1048574,
+                rd: ZERO,
+            }),
+            Box::new(Jalr {
+                rd: RA,
+                rs: T0,
+                imm: -12,
+            }),
+            Box::new(Lui {
+                rd: A0,
+                imm: 524287,
+            }),
+            Box::new(Lui {
+                rd: A0,
+                imm: -524288,
+            }),
+            Box::new(Lb {
+                rd: T0,
+                offset: -1,
+                rs1: SP,
+            }),
+            Box::new(Lbu {
+                rd: T1,
+                offset: 1,
+                rs1: SP,
+            }),
+            Box::new(Lh {
+                rd: T2,
+                offset: -2048,
+                rs1: S0,
+            }),
+            Box::new(Lhu {
+                rd: S1,
+                offset: 2047,
+                rs1: S0,
+            }),
+            Box::new(Lw {
+                rd: A0,
+                offset: 100,
+                rs1: A1,
+            }),
+            Box::new(Nop {}),
+            Box::new(Mul {
+                rd: T5,
+                rs1: T4,
+                rs2: T3,
+            }),
+            Box::new(Mulh {
+                rd: S2,
+                rs1: S3,
+                rs2: S4,
+            }),
+            Box::new(Mulhsu {
+                rd: S5,
+                rs1: S6,
+                rs2: S7,
+            }),
+            Box::new(Mulhu {
+                rd: S8,
+                rs1: S9,
+                rs2: S10,
+            }),
+            Box::new(Or {
+                rd: A0,
+                rs1: A0,
+                rs2: A0,
+            }),
+            Box::new(Ori {
+                imm: -1,
+                rd: T0,
+                rs: T0,
+            }),
+            Box::new(Rem {
+                rd: A1,
+                rs1: A2,
+                rs2: A3,
+            }),
+            Box::new(Remu {
+                rd: A4,
+                rs1: A5,
+                rs2: A6,
+            }),
+            Box::new(Sb {
+                rs2: T0,
+                offset: -2048,
+                rs1: SP,
+            }),
+            Box::new(Sh {
+                rs2: T1,
+                offset: 2047,
+                rs1: SP,
+            }),
+            Box::new(Sll {
+                rd: T0,
+                rs1: T1,
+                rs2: T2,
+            }),
+            Box::new(Slli {
+                rd: T0,
+                rs: T1,
+                imm: 31,
+            }),
+            Box::new(Slt {
+                rd: A0,
+                rs1: A1,
+                rs2: A2,
+            }),
+            Box::new(Sltu {
+                rd: A0,
+                rs1: ZERO,
+                rs2: A2,
+            }),
+            Box::new(Slti {
+                rd: A0,
+                rs: A1,
+                imm: -100,
+            }),
+            Box::new(Sltiu {
+                rd: A0,
+                rs: A1,
+                imm: 1,
+            }),
+            Box::new(Sra {
+                rd: T0,
+                rs1: T1,
+                rs2: T2,
+            }),
+            Box::new(Srai {
+                rd: T0,
+                rs: T1,
+                imm: 1,
+            }),
+            Box::new(Srl {
+                rd: T0,
+                rs1: T1,
+                rs2: T2,
+            }),
+            Box::new(Srli {
+                rd: T0,
+                rs: T1,
+                imm: 0,
+            }),
+            Box::new(Sub {
+                rd: T0,
+                rs1: T1,
+                rs2: T2,
+            }),
+            Box::new(Sw {
+                rs2: RA,
+                offset: 12,
+                rs1: SP,
+            }),
+            Box::new(Xor {
+                rd: T0,
+                rs1: T1,
+                rs2: T2,
+            }),
+            Box::new(Xori {
+                imm: -1,
+                rd: T0,
+                rs: T1,
+            }),
+        ];
+        for instruction in instructions {
+            round_trip(instruction);
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        // Expected values produced by the GNU assembler
+        let add = Add {
+            rd: RegisterType::A0,
+            rs1: RegisterType::A1,
+            rs2: RegisterType::A2,
+        };
+        assert_eq!(0x00c58533, add.encode().unwrap());
+        let addi = Addi {
+            imm: -16,
+            rd: RegisterType::SP,
+            rs: RegisterType::SP,
+        };
+        assert_eq!(0xff010113, addi.encode().unwrap());
+        let sw = Sw {
+            rs2: RegisterType::RA,
+            offset: 12,
+            rs1: RegisterType::SP,
+        };
+        assert_eq!(0x00112623, sw.encode().unwrap());
+        let lw = Lw {
+            rd: RegisterType::A5,
+            offset: -20,
+            rs1: RegisterType::S0,
+        };
+        assert_eq!(0xfec42783, lw.encode().unwrap());
+        let jalr = Jalr {
+            rd: RegisterType::ZERO,
+            rs: RegisterType::RA,
+            imm: 0,
+        };
+        assert_eq!(0x00008067, jalr.encode().unwrap());
+        let mul = Mul {
+            rd: RegisterType::A0,
+            rs1: RegisterType::A0,
+            rs2: RegisterType::A1,
+        };
+        assert_eq!(0x02b50533, mul.encode().unwrap());
+        let lui = Lui {
+            rd: RegisterType::A5,
+            imm: 0x12345,
+        };
+        assert_eq!(0x123457b7, lui.encode().unwrap());
+        let srai = Srai {
+            rd: RegisterType::A0,
+            rs: RegisterType::A0,
+            imm: 3,
+        };
+        assert_eq!(0x40355513, srai.encode().unwrap());
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        let addi = Addi {
+            imm: 2048,
+            rd: RegisterType::A0,
+            rs: RegisterType::A0,
+        };
+        assert!(addi.encode().is_err());
+        let slli = Slli {
+            rd: RegisterType::A0,
+            rs: RegisterType::A0,
+            imm: 32,
+        };
+        assert!(slli.encode().is_err());
+        let beq = Beq {
+            rs1: RegisterType::A0,
+            rs2: RegisterType::A0,
+            offset: 3,
+        };
+        assert!(beq.encode().is_err());
+        let lui = Lui {
+            rd: RegisterType::A0,
+            imm: 1 << 20,
+        };
+        assert!(lui.encode().is_err());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(decode(0).is_err());
+        assert!(decode(0xffffffff).is_err());
+        // sub with an invalid funct7
+        assert!(decode(0x60c58533).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use crate::opcodes::Trap;
+use std::fmt;
+
+// Why an instruction can't be encoded into machine code
+#[derive(Clone, PartialEq, Debug)]
+pub enum EncodeError {
+    ImmediateOutOfRange { imm: i32, bits: u32 },
+    InvalidShiftAmount(i32),
+    MisalignedOffset(i32),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::ImmediateOutOfRange { imm, bits } => {
+                write!(f, "immediate {} doesn't fit in {} bits", imm, bits)
+            }
+            EncodeError::InvalidShiftAmount(shamt) => write!(f, "invalid shift amount: {}", shamt),
+            EncodeError::MisalignedOffset(offset) => {
+                write!(f, "offset {} is not a multiple of 2", offset)
+            }
+        }
+    }
+}
+
+// Why an ELF file can't be loaded
+#[derive(Clone, PartialEq, Debug)]
+pub enum ElfError {
+    BadMagic,
+    // Only little-endian ELF32 files are supported
+    WrongClass,
+    NotExecutable,
+    WrongMachine(u16),
+    InvalidProgramHeaders,
+    // The segment at this address is larger than its memory size or than the file
+    InvalidSegment(usize),
+    NoExecutableSegment,
+    EntryOutOfText(usize),
+    MisalignedText(usize),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::BadMagic => write!(f, "not an ELF file"),
+            ElfError::WrongClass => write!(f, "only little-endian ELF32 files are supported"),
+            ElfError::NotExecutable => write!(f, "not an executable"),
+            ElfError::WrongMachine(machine) => {
+                write!(f, "not a RISC-V executable (machine {:#x})", machine)
+            }
+            ElfError::InvalidProgramHeaders => write!(f, "invalid program header table"),
+            ElfError::InvalidSegment(address) => write!(f, "invalid segment at {:#x}", address),
+            ElfError::NoExecutableSegment => write!(f, "no executable segment"),
+            ElfError::EntryOutOfText(entry) => {
+                write!(f, "entry point {:#x} out of the executable segments", entry)
+            }
+            ElfError::MisalignedText(address) => {
+                write!(f, "misaligned executable segment at {:#x}", address)
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ParseErrorKind {
+    UnknownRegister(String),
+    UndefinedLabel(String),
+    UnknownInstruction(String),
+    UnknownDirective(String),
+    // Number of arguments out of [min, max]
+    ArgumentCount { min: usize, max: usize, got: usize },
+    InvalidImmediate(String),
+    ImmediateOutOfRange(String),
+    InvalidOperand(String),
+    InvalidString(String),
+    InvalidAlignment(i32),
+    InvalidSize(i32),
+    InstructionInDataSection,
+    Encoding(EncodeError),
+}
+
+impl ParseErrorKind {
+    // Source text the error is about, if any
+    pub fn token(&self) -> Option<&str> {
+        match self {
+            ParseErrorKind::UnknownRegister(s)
+            | ParseErrorKind::UndefinedLabel(s)
+            | ParseErrorKind::InvalidImmediate(s)
+            | ParseErrorKind::ImmediateOutOfRange(s)
+            | ParseErrorKind::InvalidOperand(s)
+            | ParseErrorKind::InvalidString(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnknownRegister(s) => write!(f, "unknown register: {}", s),
+            ParseErrorKind::UndefinedLabel(s) => write!(f, "label {} does not exist", s),
+            ParseErrorKind::UnknownInstruction(s) => write!(f, "invalid instruction type: {}", s),
+            ParseErrorKind::UnknownDirective(s) => write!(f, "invalid directive: {}", s),
+            ParseErrorKind::ArgumentCount { min, max, got } if min == max => {
+                write!(f, "expected {} arguments, got {}", min, got)
+            }
+            ParseErrorKind::ArgumentCount { min, max, got } if *max == usize::MAX => {
+                write!(f, "expected at least {} arguments, got {}", min, got)
+            }
+            ParseErrorKind::ArgumentCount { min, max, got } => write!(
+                f,
+                "expected between {} and {} arguments, got {}",
+                min, max, got
+            ),
+            ParseErrorKind::InvalidImmediate(s) => write!(f, "invalid immediate: {}", s),
+            ParseErrorKind::ImmediateOutOfRange(s) => write!(f, "immediate out of range: {}", s),
+            ParseErrorKind::InvalidOperand(s) => write!(f, "invalid operand: {}", s),
+            ParseErrorKind::InvalidString(s) => write!(f, "invalid string: {}", s),
+            ParseErrorKind::InvalidAlignment(n) => write!(f, "invalid alignment: {}", n),
+            ParseErrorKind::InvalidSize(n) => write!(f, "invalid size: {}", n),
+            ParseErrorKind::InstructionInDataSection => {
+                write!(f, "instruction in the data section")
+            }
+            ParseErrorKind::Encoding(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// Error in an assembly source, line and column start at 1
+#[derive(Clone, PartialEq, Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.kind
+        )
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Cause {
+    // Exception raised by the guest program
+    Trap(Trap),
+    // The program doesn't fit in memory
+    SegmentOutOfMemory {
+        address: usize,
+        end: usize,
+        memory: usize,
+    },
+    Encoding(EncodeError),
+}
+
+impl From<Trap> for Cause {
+    fn from(trap: Trap) -> Self {
+        Cause::Trap(trap)
+    }
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cause::Trap(trap) => write!(f, "{}", trap),
+            Cause::SegmentOutOfMemory {
+                address,
+                end,
+                memory,
+            } => write!(
+                f,
+                "segment [{}, {}) doesn't fit in {} bytes of memory",
+                address, end, memory
+            ),
+            Cause::Encoding(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// Error while running a program
+#[derive(PartialEq, Debug)]
+pub struct RuntimeError {
+    pub pc: i32,
+    // Machine code of the instruction at pc, None if the error isn't raised by an instruction
+    // (e.g. while loading the program)
+    pub instruction: Option<u32>,
+    pub cause: Cause,
+}
+
+// Trap raised before an instruction is decoded, e.g. while fetching it
+impl From<Trap> for RuntimeError {
+    fn from(trap: Trap) -> Self {
+        RuntimeError {
+            pc: trap.pc,
+            instruction: None,
+            cause: Cause::Trap(trap),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.instruction {
+            Some(word) => write!(
+                f,
+                "pc {} (instruction {:#010x}): {}",
+                self.pc, word, self.cause
+            ),
+            None => write!(f, "{}", self.cause),
+        }
+    }
+}
@@ -0,0 +1,672 @@
+use crate::encoding::*;
+use crate::error::{Cause, RuntimeError};
+use crate::opcodes::*;
+use crate::syscall;
+use crate::{Report, VirtualMachine};
+use enum_map::Enum;
+
+// Functional model without any timing: the instructions are decoded once into a flat enum and
+// executed on a plain register file, without virtual calls nor allocations. It's meant to run long
+// programs at host speed, or to fast-forward them before switching to a MVM. The reported cycles
+// are the number of instructions executed.
+pub struct Fast {
+    ctx: Context,
+}
+
+impl VirtualMachine<'_> for Fast {
+    fn resume(&mut self) -> Result<Report, RuntimeError> {
+        let start = self.ctx.instructions;
+        fast_forward(&mut self.ctx, u64::MAX)?;
+        Ok(Report {
+            cycles: (self.ctx.instructions - start) as f32,
+            exit_code: self.ctx.exit_code,
+            caches: vec![],
+            dram: None,
+            branches: None,
+        })
+    }
+
+    fn context(&mut self) -> &mut Context {
+        &mut self.ctx
+    }
+}
+
+impl Fast {
+    pub fn new(memory_bytes: usize) -> Self {
+        Fast {
+            ctx: Context::new(memory_bytes),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Alu {
+    Add,
+    Sub,
+    Sll,
+    Slt,
+    Sltu,
+    Xor,
+    Srl,
+    Sra,
+    Or,
+    And,
+    Mul,
+    Mulh,
+    Mulhsu,
+    Mulhu,
+    Div,
+    Divu,
+    Rem,
+    Remu,
+}
+
+impl Alu {
+    fn apply(self, a: i32, b: i32) -> i32 {
+        match self {
+            Alu::Add => a.wrapping_add(b),
+            Alu::Sub => a.wrapping_sub(b),
+            Alu::Sll => a.wrapping_shl(b as u32),
+            Alu::Slt => (a < b) as i32,
+            Alu::Sltu => ((a as u32) < (b as u32)) as i32,
+            Alu::Xor => a ^ b,
+            Alu::Srl => (a as u32).wrapping_shr(b as u32) as i32,
+            Alu::Sra => a.wrapping_shr(b as u32),
+            Alu::Or => a | b,
+            Alu::And => a & b,
+            Alu::Mul => a.wrapping_mul(b),
+            Alu::Mulh => ((a as i64 * b as i64) >> 32) as i32,
+            Alu::Mulhsu => ((a as i64 * b as u32 as i64) >> 32) as i32,
+            Alu::Mulhu => ((a as u32 as u64 * b as u32 as u64) >> 32) as i32,
+            // Same results as Div, Divu, Rem and Remu on division by zero and overflow
+            Alu::Div if b == 0 => -1,
+            Alu::Div => a.wrapping_div(b),
+            Alu::Divu => (a as u32).checked_div(b as u32).unwrap_or(u32::MAX) as i32,
+            Alu::Rem if b == 0 => a,
+            Alu::Rem => a.wrapping_rem(b),
+            Alu::Remu => (a as u32).checked_rem(b as u32).unwrap_or(a as u32) as i32,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Condition {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Ltu,
+    Geu,
+}
+
+impl Condition {
+    fn holds(self, a: i32, b: i32) -> bool {
+        match self {
+            Condition::Eq => a == b,
+            Condition::Ne => a != b,
+            Condition::Lt => a < b,
+            Condition::Ge => a >= b,
+            Condition::Ltu => (a as u32) < (b as u32),
+            Condition::Geu => a as u32 >= b as u32,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Width {
+    Byte,
+    Half,
+    Word,
+    ByteUnsigned,
+    HalfUnsigned,
+}
+
+// Registers are indexes in the register file (x0-x31)
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Instruction {
+    Op {
+        alu: Alu,
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    // Shifts keep their shift amount in imm
+    OpImm {
+        alu: Alu,
+        rd: u8,
+        rs1: u8,
+        imm: i32,
+    },
+    Load {
+        width: Width,
+        rd: u8,
+        rs1: u8,
+        offset: i32,
+    },
+    // size in bytes
+    Store {
+        size: u8,
+        rs1: u8,
+        rs2: u8,
+        offset: i32,
+    },
+    Branch {
+        condition: Condition,
+        rs1: u8,
+        rs2: u8,
+        offset: i32,
+    },
+    Jal {
+        rd: u8,
+        offset: i32,
+    },
+    Jalr {
+        rd: u8,
+        rs1: u8,
+        imm: i32,
+    },
+    // imm is already shifted into the upper 20 bits
+    Lui {
+        rd: u8,
+        imm: i32,
+    },
+    Auipc {
+        rd: u8,
+        imm: i32,
+    },
+    Fence,
+    FenceI,
+    Ecall,
+    Ebreak,
+    Illegal(u32),
+}
+
+// Decodes a 32-bit word from the fields decoded by encoding::decode_fields, so that both
+// interpreters share the same encodings.
+pub fn decode(word: u32) -> Instruction {
+    let fields = match decode_fields(word) {
+        Ok(fields) => fields,
+        Err(_) => return Instruction::Illegal(word),
+    };
+    let rd = Enum::<()>::to_usize(fields.rd) as u8;
+    let rs1 = Enum::<()>::to_usize(fields.rs1) as u8;
+    let rs2 = Enum::<()>::to_usize(fields.rs2) as u8;
+    let imm = fields.imm;
+    let op = |alu| Instruction::Op { alu, rd, rs1, rs2 };
+    let op_imm = |alu| Instruction::OpImm { alu, rd, rs1, imm };
+    let load = |width| Instruction::Load {
+        width,
+        rd,
+        rs1,
+        offset: imm,
+    };
+    let store = |size| Instruction::Store {
+        size,
+        rs1,
+        rs2,
+        offset: imm,
+    };
+    let branch = |condition| Instruction::Branch {
+        condition,
+        rs1,
+        rs2,
+        offset: imm,
+    };
+
+    match fields.instruction_type {
+        InstructionType::ADD => op(Alu::Add),
+        InstructionType::SUB => op(Alu::Sub),
+        InstructionType::SLL => op(Alu::Sll),
+        InstructionType::SLT => op(Alu::Slt),
+        InstructionType::SLTU => op(Alu::Sltu),
+        InstructionType::XOR => op(Alu::Xor),
+        InstructionType::SRL => op(Alu::Srl),
+        InstructionType::SRA => op(Alu::Sra),
+        InstructionType::OR => op(Alu::Or),
+        InstructionType::AND => op(Alu::And),
+        InstructionType::MUL => op(Alu::Mul),
+        InstructionType::MULH => op(Alu::Mulh),
+        InstructionType::MULHSU => op(Alu::Mulhsu),
+        InstructionType::MULHU => op(Alu::Mulhu),
+        InstructionType::DIV => op(Alu::Div),
+        InstructionType::DIVU => op(Alu::Divu),
+        InstructionType::REM => op(Alu::Rem),
+        InstructionType::REMU => op(Alu::Remu),
+        // addi zero, zero, 0
+        InstructionType::NOP | InstructionType::ADDI => op_imm(Alu::Add),
+        InstructionType::SLTI => op_imm(Alu::Slt),
+        InstructionType::SLTIU => op_imm(Alu::Sltu),
+        InstructionType::XORI => op_imm(Alu::Xor),
+        InstructionType::ORI => op_imm(Alu::Or),
+        InstructionType::ANDI => op_imm(Alu::And),
+        InstructionType::SLLI => op_imm(Alu::Sll),
+        InstructionType::SRLI => op_imm(Alu::Srl),
+        InstructionType::SRAI => op_imm(Alu::Sra),
+        InstructionType::LB => load(Width::Byte),
+        InstructionType::LH => load(Width::Half),
+        InstructionType::LW => load(Width::Word),
+        InstructionType::LBU => load(Width::ByteUnsigned),
+        InstructionType::LHU => load(Width::HalfUnsigned),
+        InstructionType::SB => store(1),
+        InstructionType::SH => store(2),
+        InstructionType::SW => store(4),
+        InstructionType::BEQ => branch(Condition::Eq),
+        InstructionType::BNE => branch(Condition::Ne),
+        InstructionType::BLT => branch(Condition::Lt),
+        InstructionType::BGE => branch(Condition::Ge),
+        InstructionType::BLTU => branch(Condition::Ltu),
+        InstructionType::BGEU => branch(Condition::Geu),
+        InstructionType::JAL => Instruction::Jal { rd, offset: imm },
+        InstructionType::JALR => Instruction::Jalr { rd, rs1, imm },
+        InstructionType::LUI => Instruction::Lui { rd, imm: imm << 12 },
+        InstructionType::AUIPC => Instruction::Auipc { rd, imm: imm << 12 },
+        InstructionType::FENCE => Instruction::Fence,
+        InstructionType::FENCEI => Instruction::FenceI,
+        InstructionType::ECALL => Instruction::Ecall,
+        InstructionType::EBREAK => Instruction::Ebreak,
+        InstructionType::ILLEGAL => Instruction::Illegal(word),
+    }
+}
+
+// Decoded copy of the text, kept up to date when a store overwrites an instruction
+struct Code {
+    start: i32,
+    instructions: Vec<Instruction>,
+}
+
+impl Code {
+    fn new(ctx: &Context) -> Self {
+        let (start, end) = ctx.text;
+        let mut instructions = vec![];
+        let mut pc = start;
+        // The text is aligned on 4 bytes and loaded in memory
+        while pc + 4 <= end {
+            instructions.push(decode(ctx.fetch(pc).unwrap()));
+            pc += 4;
+        }
+        Code {
+            start,
+            instructions,
+        }
+    }
+
+    // pc has to be in the text
+    fn get(&self, ctx: &Context, pc: i32) -> Result<Instruction, Trap> {
+        ctx.check_fetch(pc)?;
+        Ok(self.instructions[((pc - self.start) / 4) as usize])
+    }
+
+    // Decodes again the instructions overlapping [address, address + size)
+    fn invalidate(&mut self, ctx: &Context, address: i32, size: i32) {
+        let first = (address - self.start).max(0) / 4;
+        let last = (address + size - 1 - self.start) / 4;
+        for idx in first..=last {
+            if let Some(instruction) = self.instructions.get_mut(idx as usize) {
+                *instruction = decode(ctx.fetch(self.start + idx * 4).unwrap());
+            }
+        }
+    }
+}
+
+// Runs at most limit instructions of a loaded program, starting at ctx.pc and stopping earlier
+// once the program completes. The registers and the memory are left in ctx, so that another VM
+// can resume the execution.
+pub fn fast_forward(ctx: &mut Context, limit: u64) -> Result<(), RuntimeError> {
+    let mut registers = [0; 32];
+    for (register, value) in ctx.registers.iter() {
+        registers[Enum::<()>::to_usize(register)] = *value;
+    }
+    let mut code = Code::new(ctx);
+
+    let mut result = Ok(());
+    let mut count = 0;
+    while count < limit && ctx.exit_code.is_none() && ctx.in_text(ctx.pc) {
+        let instruction = match code.get(ctx, ctx.pc) {
+            Ok(instruction) => instruction,
+            Err(trap) => {
+                result = Err(trap.into());
+                break;
+            }
+        };
+        match step(ctx, &mut registers, &mut code, instruction) {
+            Ok(pc) => ctx.pc = pc,
+            Err(cause) => {
+                result = Err(RuntimeError {
+                    pc: ctx.pc,
+                    instruction: ctx.fetch(ctx.pc).ok(),
+                    cause,
+                });
+                break;
+            }
+        }
+        count += 1;
+    }
+
+    ctx.instructions += count;
+    copy_registers(&registers, ctx);
+    result
+}
+
+// Executes an instruction and returns the next pc
+fn step(
+    ctx: &mut Context,
+    registers: &mut [i32; 32],
+    code: &mut Code,
+    instruction: Instruction,
+) -> Result<i32, Cause> {
+    let pc = ctx.pc;
+    let (rd, value) = match instruction {
+        Instruction::Op { alu, rd, rs1, rs2 } => (
+            rd,
+            alu.apply(registers[rs1 as usize], registers[rs2 as usize]),
+        ),
+        Instruction::OpImm { alu, rd, rs1, imm } => (rd, alu.apply(registers[rs1 as usize], imm)),
+        Instruction::Load {
+            width,
+            rd,
+            rs1,
+            offset,
+        } => {
+            let address = registers[rs1 as usize].wrapping_add(offset);
+            let value = match width {
+                Width::Byte => ctx.read_memory(address, 1)?[0] as i32,
+                Width::ByteUnsigned => ctx.read_memory(address, 1)?[0] as u8 as i32,
+                Width::Half => {
+                    let bytes = ctx.read_memory(address, 2)?;
+                    i16::from_le_bytes([bytes[0] as u8, bytes[1] as u8]) as i32
+                }
+                Width::HalfUnsigned => {
+                    let bytes = ctx.read_memory(address, 2)?;
+                    u16::from_le_bytes([bytes[0] as u8, bytes[1] as u8]) as i32
+                }
+                Width::Word => {
+                    let bytes = ctx.read_memory(address, 4)?;
+                    i32::from_le_bytes([
+                        bytes[0] as u8,
+                        bytes[1] as u8,
+                        bytes[2] as u8,
+                        bytes[3] as u8,
+                    ])
+                }
+            };
+            (rd, value)
+        }
+        Instruction::Store {
+            size,
+            rs1,
+            rs2,
+            offset,
+        } => {
+            let address = registers[rs1 as usize].wrapping_add(offset);
+            let bytes = registers[rs2 as usize].to_le_bytes().map(|b| b as i8);
+            ctx.write_memory(address, &bytes[..size as usize])?;
+            if address < ctx.text.1 && address + size as i32 > ctx.text.0 {
+                code.invalidate(ctx, address, size as i32);
+            }
+            return Ok(pc + 4);
+        }
+        Instruction::Branch {
+            condition,
+            rs1,
+            rs2,
+            offset,
+        } => {
+            if condition.holds(registers[rs1 as usize], registers[rs2 as usize]) {
+                return Ok(pc.wrapping_add(offset));
+            }
+            return Ok(pc + 4);
+        }
+        Instruction::Jal { rd, offset } => {
+            write_register(registers, rd, pc + 4);
+            return Ok(pc.wrapping_add(offset));
+        }
+        Instruction::Jalr { rd, rs1, imm } => {
+            // rs1 is read before rd is written, as both can be the same register
+            let target = registers[rs1 as usize].wrapping_add(imm) & !1;
+            write_register(registers, rd, pc + 4);
+            return Ok(target);
+        }
+        Instruction::Lui { rd, imm } => (rd, imm),
+        Instruction::Auipc { rd, imm } => (rd, pc.wrapping_add(imm)),
+        // The stores into the text already decode again the words they overwrite, so there is
+        // nothing left to invalidate
+        Instruction::Fence | Instruction::FenceI => return Ok(pc + 4),
+        Instruction::Ecall => {
+            // The system calls work on the context registers
+            copy_registers(registers, ctx);
+            let execution = syscall::handle(ctx);
+            ctx.write(&execution);
+            registers[Enum::<()>::to_usize(execution.register)] = ctx.registers[execution.register];
+            return Ok(execution.pc);
+        }
+        Instruction::Ebreak => {
+            return Err(Cause::Trap(Trap {
+                exception: Exception::Breakpoint,
+                pc,
+                address: pc,
+            }))
+        }
+        Instruction::Illegal(word) => {
+            return Err(Cause::Trap(Trap {
+                exception: Exception::IllegalInstruction(word),
+                pc,
+                address: pc,
+            }))
+        }
+    };
+    write_register(registers, rd, value);
+    Ok(pc + 4)
+}
+
+// Writes to x0 are discarded
+fn write_register(registers: &mut [i32; 32], rd: u8, value: i32) {
+    if rd != 0 {
+        registers[rd as usize] = value;
+    }
+}
+
+fn copy_registers(registers: &[i32; 32], ctx: &mut Context) {
+    for (idx, value) in registers.iter().enumerate() {
+        ctx.registers[<RegisterType as Enum<()>>::from_usize(idx)] = *value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::elf;
+    use crate::mvm1::Mvm1;
+    use crate::mvm3::Mvm3;
+    use crate::parser::parse;
+    use std::fs;
+
+    // Runs the application on MVM-1 and on the fast interpreter, both have to end in the same state
+    fn assert_same(application: &Application, memory_bytes: usize, init: fn(&mut Context)) {
+        let mut reference = Mvm1::new(memory_bytes, &Config::default());
+        let mut fast = Fast::new(memory_bytes);
+        for vm in [
+            &mut reference as &mut dyn VirtualMachine,
+            &mut fast as &mut dyn VirtualMachine,
+        ] {
+            vm.context().io.stdout = Box::new(std::io::sink());
+            init(vm.context());
+        }
+        let expected = reference.run(application).unwrap();
+        let report = fast.run(application).unwrap();
+
+        assert_eq!(expected.exit_code, report.exit_code);
+        assert_eq!(reference.context().instructions as f32, report.cycles);
+        assert_eq!(reference.context().instructions, fast.ctx.instructions);
+        assert_eq!(reference.context().pc, fast.ctx.pc);
+        assert_eq!(reference.context().registers, fast.ctx.registers);
+        assert!(reference.context().memory == fast.ctx.memory);
+    }
+
+    #[test]
+    fn test_decode() {
+        let application = parse(
+            "add t0, t1, t2
+            srai a0, a1, 3
+            back:
+            lhu s1, -2(sp)
+            sb a0, 5(t0)
+            bgeu t0, t1, back
+            jal ra, end
+            lui a0, 0x12345
+            end:
+            ebreak"
+                .to_string(),
+        )
+        .unwrap();
+        let words: Vec<u32> = application
+            .instructions
+            .iter()
+            .map(|i| i.encode().unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                Instruction::Op {
+                    alu: Alu::Add,
+                    rd: 5,
+                    rs1: 6,
+                    rs2: 7
+                },
+                Instruction::OpImm {
+                    alu: Alu::Sra,
+                    rd: 10,
+                    rs1: 11,
+                    imm: 3
+                },
+                Instruction::Load {
+                    width: Width::HalfUnsigned,
+                    rd: 9,
+                    rs1: 2,
+                    offset: -2
+                },
+                Instruction::Store {
+                    size: 1,
+                    rs1: 5,
+                    rs2: 10,
+                    offset: 5
+                },
+                Instruction::Branch {
+                    condition: Condition::Geu,
+                    rs1: 5,
+                    rs2: 6,
+                    offset: -8
+                },
+                Instruction::Jal { rd: 1, offset: 8 },
+                Instruction::Lui {
+                    rd: 10,
+                    imm: 0x12345000
+                },
+                Instruction::Ebreak,
+            ],
+            words.iter().map(|w| decode(*w)).collect::<Vec<_>>()
+        );
+        assert_eq!(Instruction::Illegal(0), decode(0));
+    }
+
+    #[test]
+    fn test_programs() {
+        let source = fs::read_to_string("res/risc/prime-number-1109.asm").unwrap();
+        assert_same(&parse(source).unwrap(), 5, |_| {});
+
+        let source = fs::read_to_string("res/risc/prime-number.asm").unwrap();
+        assert_same(&parse(source).unwrap(), 5, |ctx| ctx.memory[0] = 9);
+
+        let application = elf::load(&fs::read("res/elf/hello").unwrap()).unwrap();
+        assert_same(&application, 0x12000, |_| {});
+    }
+
+    #[test]
+    fn test_instructions() {
+        let application = parse(
+            "li t0, -7
+            li t1, 3
+            mul a0, t0, t1
+            mulh a1, t0, t1
+            mulhu a2, t0, t1
+            div a3, t0, t1
+            remu a4, t0, t1
+            div a5, t0, zero
+            sltu a6, t0, t1
+            srl a7, t0, t1
+            sra s2, t0, t1
+            sll s3, t1, t0
+            sw t0, 4(zero)
+            lb s4, 4(zero)
+            lbu s5, 4(zero)
+            lhu s6, 4(zero)
+            bltu t1, t0, skip
+            li s7, 1
+            skip:
+            auipc s8, 1
+            call f
+            j end
+            f:
+            addi s9, zero, 42
+            ret
+            end:
+            nop"
+            .to_string(),
+        )
+        .unwrap();
+        assert_same(&application, 8, |_| {});
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        // Replaces addi a0, zero, 1 by addi a0, zero, 2 before running it
+        let application = parse(
+            "lw t0, 20(zero)
+            addi t1, zero, 1
+            slli t1, t1, 20
+            add t0, t0, t1
+            sw t0, 20(zero)
+            addi a0, zero, 1"
+                .to_string(),
+        )
+        .unwrap();
+        // Without any main memory, the text starts at 0
+        let mut vm = Fast::new(0);
+        vm.run(&application).unwrap();
+        assert_eq!(2, vm.ctx.registers[RegisterType::A0]);
+    }
+
+    #[test]
+    fn test_fast_forward() {
+        let source = fs::read_to_string("res/risc/prime-number-1109.asm").unwrap();
+        let application = parse(source).unwrap();
+        let mut reference = Mvm1::new(5, &Config::default());
+        reference.run(&application).unwrap();
+
+        let mut vm = Mvm3::new(5, &Config::default());
+        vm.context().load(&application).unwrap();
+        fast_forward(vm.context(), 100).unwrap();
+        assert_eq!(100, vm.context().instructions);
+        vm.resume().unwrap();
+        assert_eq!(reference.context().registers, vm.context().registers);
+        assert_eq!(reference.context().instructions, vm.context().instructions);
+    }
+
+    #[test]
+    fn test_trap() {
+        let application = parse("addi t0, zero, 1\nlw t1, 0(t0)".to_string()).unwrap();
+        let mut vm = Fast::new(8);
+        let error = vm.run(&application).err().unwrap();
+        assert_eq!(12, error.pc);
+        assert_eq!(
+            Cause::Trap(Trap {
+                exception: Exception::LoadAddressMisaligned,
+                pc: 12,
+                address: 1,
+            }),
+            error.cause
+        );
+        // Registers written before the trap are kept
+        assert_eq!(1, vm.ctx.registers[RegisterType::T0]);
+        assert_eq!(1, vm.ctx.instructions);
+    }
+}
@@ -0,0 +1,553 @@
+use crate::bit::i32_from_bytes;
+use crate::cache::{Cache, Eviction, Stats, WritePolicy};
+use crate::config::Config;
+use crate::dram::{self, Dram};
+use crate::opcodes::{Context, MemoryAccess, Trap};
+use serde::Deserialize;
+
+// Lines kept by a level compared to the levels above it
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Inclusion {
+    // Every line of the levels above is in the level as well: a line evicted from the level is
+    // invalidated above
+    Inclusive,
+    // A line is either in the level or above: a hit moves the line up, and the level is filled with
+    // the lines evicted from above only (victim cache)
+    Exclusive,
+    // The missing lines are allocated on the way up, without invalidating the levels above
+    NonInclusive,
+}
+
+struct Level {
+    cache: Cache,
+    latency: f32,
+    inclusion: Inclusion,
+}
+
+// Caches of MVM-2 and MVM-3: the L1 instruction and data caches, then the levels shared by both
+// (L2, L3, etc.) down to the main memory. A miss goes down until a level holds the line.
+pub struct Hierarchy {
+    l1i: Cache,
+    // Bytes of each line of L1I, copies of the main memory which aren't updated by the stores until
+    // fence.i invalidates the cache
+    l1i_data: Vec<Vec<i8>>,
+    l1d: Cache,
+    write_policy: WritePolicy,
+    levels: Vec<Level>,
+    cycles_l1_access: f32,
+    cycles_memory_access: f32,
+    dram: Option<Dram>,
+    // Current cycle of the VM
+    now: f32,
+}
+
+impl Hierarchy {
+    pub fn new(config: &Config) -> Self {
+        Hierarchy {
+            l1i: Cache::new(
+                config.l1i.size,
+                config.l1i.line,
+                config.l1i.associativity,
+                config.l1i.replacement,
+            ),
+            l1i_data: vec![vec![]; (config.l1i.size / config.l1i.line) as usize],
+            l1d: Cache::new(
+                config.l1d.size,
+                config.l1d.line,
+                config.l1d.associativity,
+                config.l1d.replacement,
+            ),
+            write_policy: config.l1d.write_policy,
+            levels: config
+                .levels
+                .iter()
+                .map(|level| Level {
+                    cache: Cache::new(
+                        level.size,
+                        level.line,
+                        level.associativity,
+                        level.replacement,
+                    ),
+                    latency: level.latency,
+                    inclusion: level.inclusion,
+                })
+                .collect(),
+            cycles_l1_access: config.latency.l1_access,
+            cycles_memory_access: config.latency.memory_access,
+            dram: config.dram.as_ref().map(Dram::new),
+            now: 0.,
+        }
+    }
+
+    // Sets the cycle of the next accesses, so that the DRAM knows the busy banks and the refreshes
+    pub fn set_cycle(&mut self, cycle: f32) {
+        self.now = cycle;
+    }
+
+    // Returns the instruction at pc with the cycles spent
+    pub fn fetch(&mut self, ctx: &Context, pc: i32) -> Result<(u32, f32), Trap> {
+        ctx.check_fetch(pc)?;
+        let lookup = self.l1i.access(pc, true, false);
+        let slot = lookup.slot.unwrap();
+        let address = self.l1i.line_address(pc);
+        let mut cycles = self.cycles_l1_access;
+        if !lookup.hit {
+            let (read_cycles, dirty) = self.read(0, address);
+            if dirty {
+                self.l1i.mark_dirty(slot);
+            }
+            cycles += read_cycles + self.evict(0, lookup.evicted) + self.schedule();
+            // The end of the memory isn't necessarily aligned on a line
+            let start = address as usize;
+            let end = (start + self.l1i.line_size()).min(ctx.memory.len());
+            self.l1i_data[slot] = ctx.memory[start..end].to_vec();
+        }
+
+        let bytes = &self.l1i_data[slot];
+        let idx = (pc - address) as usize;
+        let word = i32_from_bytes(bytes[idx], bytes[idx + 1], bytes[idx + 2], bytes[idx + 3]);
+        Ok((word as u32, cycles))
+    }
+
+    // Cycles spent by a load or a store
+    pub fn access(&mut self, access: &MemoryAccess) -> f32 {
+        let address = self.l1d.line_address(access.address);
+        if access.store && self.write_policy == WritePolicy::WriteThrough {
+            // The line is updated if present, while the store goes to the next level anyway
+            self.l1d.access(access.address, false, false);
+            return self.write(0, address) + self.schedule();
+        }
+
+        let lookup = self.l1d.access(access.address, true, access.store);
+        let mut cycles = self.cycles_l1_access;
+        if !lookup.hit {
+            let (read_cycles, dirty) = self.read(0, address);
+            if dirty {
+                self.l1d.mark_dirty(lookup.slot.unwrap());
+            }
+            // The victim is handled once the line is read, so that it can't replace it
+            cycles += read_cycles + self.evict(0, lookup.evicted) + self.schedule();
+        }
+        cycles
+    }
+
+    pub fn invalidate_l1i(&mut self) {
+        self.l1i.invalidate();
+    }
+
+    // Statistics of every level by name
+    pub fn stats(&self) -> Vec<(String, Stats)> {
+        let mut stats = vec![
+            ("l1i".to_string(), self.l1i.stats),
+            ("l1d".to_string(), self.l1d.stats),
+        ];
+        for (i, level) in self.levels.iter().enumerate() {
+            stats.push((format_args!("l{}", i + 2).to_string(), level.cache.stats));
+        }
+        stats
+    }
+
+    pub fn dram_stats(&self) -> Option<dram::Stats> {
+        self.dram.as_ref().map(|dram| dram.stats)
+    }
+
+    // Cycles of a read or a write of the line at address by the main memory. With a DRAM, the
+    // request is queued and its cycles are counted once every request of the access is known.
+    fn memory(&mut self, address: i32) -> f32 {
+        match &mut self.dram {
+            Some(dram) => {
+                dram.request(address, self.now);
+                0.
+            }
+            None => self.cycles_memory_access,
+        }
+    }
+
+    // Cycles until the DRAM has served the requests of an access
+    fn schedule(&mut self) -> f32 {
+        match &mut self.dram {
+            Some(dram) => dram.schedule(self.now),
+            None => 0.,
+        }
+    }
+
+    // Cycles to bring the line at address from the level i (or the main memory past the last
+    // level), and whether the line is dirty, when moved up from an exclusive level
+    fn read(&mut self, i: usize, address: i32) -> (f32, bool) {
+        if i == self.levels.len() {
+            return (self.memory(address), false);
+        }
+        let latency = self.levels[i].latency;
+        let level = &mut self.levels[i];
+
+        if level.inclusion == Inclusion::Exclusive {
+            if level.cache.access(address, false, false).hit {
+                let dirty = level.cache.remove(address).unwrap();
+                return (latency, dirty);
+            }
+            let (cycles, dirty) = self.read(i + 1, address);
+            return (latency + cycles, dirty);
+        }
+
+        let lookup = level.cache.access(address, true, false);
+        if lookup.hit {
+            return (latency, false);
+        }
+        let (mut cycles, dirty) = self.read(i + 1, address);
+        if dirty {
+            self.levels[i].cache.mark_dirty(lookup.slot.unwrap());
+        }
+        cycles += self.replaced(i, lookup.evicted);
+        (latency + cycles, false)
+    }
+
+    // Cycles to write a dirty line to the level i or below
+    fn write(&mut self, i: usize, address: i32) -> f32 {
+        if i == self.levels.len() {
+            return self.memory(address);
+        }
+        let latency = self.levels[i].latency;
+        let level = &mut self.levels[i];
+
+        if level.inclusion == Inclusion::Exclusive {
+            let evicted = level.cache.insert(address, true);
+            return latency + self.evict(i + 1, evicted);
+        }
+        // The whole line is written, so a missing line doesn't have to be read first
+        let evicted = level.cache.access(address, true, true).evicted;
+        latency + self.replaced(i, evicted)
+    }
+
+    // Cycles to place a line evicted from the level above i
+    fn evict(&mut self, i: usize, evicted: Option<Eviction>) -> f32 {
+        let evicted = match evicted {
+            Some(evicted) => evicted,
+            None => return 0.,
+        };
+        if i < self.levels.len() && self.levels[i].inclusion == Inclusion::Exclusive {
+            // A clean line is placed off the critical path
+            let next = self.levels[i].cache.insert(evicted.address, evicted.dirty);
+            let mut cycles = self.evict(i + 1, next);
+            if evicted.dirty {
+                cycles += self.levels[i].latency;
+            }
+            return cycles;
+        }
+        if !evicted.dirty {
+            return 0.;
+        }
+        self.write(i, evicted.address)
+    }
+
+    // Cycles to handle a line replaced in the non-exclusive level i
+    fn replaced(&mut self, i: usize, evicted: Option<Eviction>) -> f32 {
+        let mut evicted = match evicted {
+            Some(evicted) => evicted,
+            None => return 0.,
+        };
+        if self.levels[i].inclusion == Inclusion::Inclusive {
+            // The copies above are invalidated, a dirty one is more recent
+            for level in self.levels[..i].iter_mut() {
+                evicted.dirty |= level.cache.remove(evicted.address).unwrap_or(false);
+            }
+            evicted.dirty |= self.l1d.remove(evicted.address).unwrap_or(false);
+            evicted.dirty |= self.l1i.remove(evicted.address).unwrap_or(false);
+        }
+        self.evict(i + 1, Some(evicted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcodes::Exception;
+
+    fn load(address: i32) -> MemoryAccess {
+        MemoryAccess {
+            address,
+            store: false,
+        }
+    }
+
+    fn store(address: i32) -> MemoryAccess {
+        MemoryAccess {
+            address,
+            store: true,
+        }
+    }
+
+    // Latencies of 1 cycle for L1 and 50 for the main memory
+    fn hierarchy(caches: &str) -> Hierarchy {
+        let config = Config::parse(&format!(
+            "[latency]
+            memory_access = 50
+            l1_access = 1
+            {}",
+            caches
+        ))
+        .unwrap();
+        Hierarchy::new(&config)
+    }
+
+    fn stats(hierarchy: &Hierarchy, name: &str) -> Stats {
+        hierarchy
+            .stats()
+            .into_iter()
+            .find(|stats| stats.0 == name)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn test_fetch() {
+        let mut hierarchy = hierarchy(
+            "[l1i]
+            size = 32
+            line = 16
+            associativity = 1",
+        );
+        let mut ctx = Context::new(40);
+        ctx.memory[20] = 1;
+        ctx.memory[36] = 2;
+        // The line is aligned, so that the instructions before pc are cached as well
+        assert_eq!(Ok((1, 51.)), hierarchy.fetch(&ctx, 20));
+        assert_eq!(Ok((0, 1.)), hierarchy.fetch(&ctx, 16));
+        // Stale until invalidated
+        ctx.memory[20] = 3;
+        assert_eq!(Ok((1, 1.)), hierarchy.fetch(&ctx, 20));
+        hierarchy.invalidate_l1i();
+        assert_eq!(Ok((3, 51.)), hierarchy.fetch(&ctx, 20));
+        // The last line is cut at the end of the memory
+        assert_eq!(Ok((2, 51.)), hierarchy.fetch(&ctx, 36));
+        // Misaligned, or past the end of the memory
+        assert_eq!(
+            Exception::InstructionAddressMisaligned,
+            hierarchy.fetch(&ctx, 18).unwrap_err().exception
+        );
+        assert_eq!(
+            Exception::InstructionAccessFault,
+            hierarchy.fetch(&ctx, 40).unwrap_err().exception
+        );
+        assert_eq!(
+            Stats {
+                hits: 2,
+                misses: 3,
+                evictions: 0,
+                write_backs: 0,
+            },
+            stats(&hierarchy, "l1i")
+        );
+    }
+
+    #[test]
+    fn test_write_back() {
+        let mut hierarchy = hierarchy(
+            "[l1d]
+            size = 32
+            line = 16
+            associativity = 1",
+        );
+        // Write-allocate
+        assert_eq!(51., hierarchy.access(&store(0)));
+        assert_eq!(1., hierarchy.access(&load(4)));
+        assert_eq!(1., hierarchy.access(&store(8)));
+        // Evicts the dirty line
+        assert_eq!(101., hierarchy.access(&load(32)));
+        // Evicts a clean line
+        assert_eq!(51., hierarchy.access(&load(0)));
+        assert_eq!(
+            Stats {
+                hits: 2,
+                misses: 3,
+                evictions: 2,
+                write_backs: 1,
+            },
+            stats(&hierarchy, "l1d")
+        );
+    }
+
+    #[test]
+    fn test_write_through() {
+        let mut hierarchy = hierarchy(
+            "[l1d]
+            size = 32
+            line = 16
+            associativity = 1
+            write_policy = \"write-through\"",
+        );
+        // No-allocate
+        assert_eq!(50., hierarchy.access(&store(0)));
+        assert_eq!(51., hierarchy.access(&load(0)));
+        assert_eq!(50., hierarchy.access(&store(4)));
+        // The line is never dirty
+        assert_eq!(51., hierarchy.access(&load(32)));
+        assert_eq!(
+            Stats {
+                hits: 1,
+                misses: 3,
+                evictions: 1,
+                write_backs: 0,
+            },
+            stats(&hierarchy, "l1d")
+        );
+    }
+
+    #[test]
+    fn test_levels() {
+        let mut hierarchy = hierarchy(
+            "[l1i]
+            line = 16
+            [l1d]
+            size = 32
+            line = 16
+            associativity = 1
+            [[levels]]
+            size = 128
+            line = 16
+            associativity = 1
+            latency = 10
+            [[levels]]
+            size = 256
+            line = 16
+            latency = 20",
+        );
+        assert_eq!(81., hierarchy.access(&store(0)));
+        // The dirty line of 0 is written to L2
+        assert_eq!(91., hierarchy.access(&load(32)));
+        // Missed by L1D only
+        assert_eq!(11., hierarchy.access(&load(0)));
+        // Replaces 0 in L2 as well, written to L3
+        assert_eq!(101., hierarchy.access(&load(128)));
+        // Missed by L1D and L2 only
+        assert_eq!(31., hierarchy.access(&load(0)));
+        assert_eq!(
+            vec!["l1i", "l1d", "l2", "l3"],
+            hierarchy
+                .stats()
+                .iter()
+                .map(|stats| stats.0.as_str())
+                .collect::<Vec<&str>>()
+        );
+        assert_eq!(
+            Stats {
+                hits: 2,
+                misses: 4,
+                evictions: 2,
+                write_backs: 1,
+            },
+            stats(&hierarchy, "l2")
+        );
+        assert_eq!(
+            Stats {
+                hits: 2,
+                misses: 3,
+                evictions: 0,
+                write_backs: 0,
+            },
+            stats(&hierarchy, "l3")
+        );
+    }
+
+    #[test]
+    fn test_inclusive() {
+        let l1d = "[l1i]
+            line = 16
+            [l1d]
+            size = 64
+            line = 16
+            associativity = 4";
+        let l2 = "[[levels]]
+            size = 32
+            line = 16
+            associativity = 1
+            latency = 10";
+
+        // Replacing 0 in L2 invalidates it in L1D, which has room for both lines
+        let mut inclusive = hierarchy(&format!("{}\n{}\ninclusion = \"inclusive\"", l1d, l2));
+        assert_eq!(61., inclusive.access(&load(0)));
+        assert_eq!(61., inclusive.access(&load(32)));
+        assert_eq!(61., inclusive.access(&load(0)));
+
+        let mut non_inclusive = hierarchy(&format!("{}\n{}", l1d, l2));
+        assert_eq!(61., non_inclusive.access(&load(0)));
+        assert_eq!(61., non_inclusive.access(&load(32)));
+        assert_eq!(1., non_inclusive.access(&load(0)));
+    }
+
+    #[test]
+    fn test_dram() {
+        assert_eq!(None, hierarchy("").dram_stats());
+        // A single bank of 256 byte rows, without refresh
+        let mut hierarchy = hierarchy(
+            "[l1d]
+            size = 32
+            line = 16
+            associativity = 1
+            [dram]
+            channels = 1
+            banks = 1
+            row_size = 256
+            t_cas = 10
+            t_rcd = 20
+            t_rp = 30
+            t_burst = 4
+            t_refi = 0",
+        );
+        assert_eq!(1. + 34., hierarchy.access(&store(0)));
+        // Row hit
+        hierarchy.set_cycle(100.);
+        assert_eq!(1. + 14., hierarchy.access(&load(16)));
+        // Row conflict for the read, then a row hit for the dirty line of 0, served first
+        hierarchy.set_cycle(200.);
+        assert_eq!(1. + 14. + 64., hierarchy.access(&load(256)));
+        // The bank is busy until 278
+        hierarchy.set_cycle(270.);
+        assert_eq!(1. + 8. + 14., hierarchy.access(&load(272)));
+        assert_eq!(
+            Some(dram::Stats {
+                row_hits: 3,
+                row_misses: 1,
+                row_conflicts: 1,
+                refreshes: 0,
+            }),
+            hierarchy.dram_stats()
+        );
+    }
+
+    #[test]
+    fn test_exclusive() {
+        let mut hierarchy = hierarchy(
+            "[l1i]
+            line = 16
+            [l1d]
+            size = 16
+            line = 16
+            associativity = 1
+            [[levels]]
+            size = 64
+            line = 16
+            associativity = 4
+            latency = 10
+            inclusion = \"exclusive\"",
+        );
+        // Not allocated in L2
+        assert_eq!(61., hierarchy.access(&load(0)));
+        // 0 moves to L2
+        assert_eq!(61., hierarchy.access(&load(16)));
+        // 0 moves back to L1D, and 16 to L2
+        assert_eq!(11., hierarchy.access(&store(0)));
+        assert_eq!(1., hierarchy.access(&load(0)));
+        // The dirty line of 0 costs the L2 access
+        assert_eq!(21., hierarchy.access(&load(16)));
+        assert_eq!(
+            Stats {
+                hits: 2,
+                misses: 2,
+                evictions: 0,
+                write_backs: 0,
+            },
+            stats(&hierarchy, "l2")
+        );
+    }
+}
@@ -1,86 +1,494 @@
 mod mvm3;
+mod mvm4;
+mod mvm5;
 
-use crate::opcodes::Application;
+use crate::bit::bytes_from_low_bits;
+use crate::cache::Stats;
+use crate::config::Config;
+use crate::error::RuntimeError;
+use crate::fast::Fast;
+use crate::mvm1::Mvm1;
+use crate::mvm2::Mvm2;
+use crate::mvm3::Mvm3;
+use crate::mvm4::Mvm4;
+use crate::mvm5::Mvm5;
+use crate::opcodes::{Application, Context};
+use crate::parser::{parse, parse_register};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::fs;
+use std::process;
+use std::time::Instant;
 
 mod bit;
+mod cache;
+mod config;
+mod dram;
+mod elf;
+mod encoding;
+mod error;
+mod fast;
+mod hierarchy;
 mod mvm1;
 mod mvm2;
 mod opcodes;
 mod parser;
+mod predictor;
+mod syscall;
 
 pub const I5_7360U: i64 = 2_300_000_000;
 pub const SECOND_TO_NANOSECOND: i64 = 1_000_000_000;
 
-trait VirtualMachine {
-    fn run(&mut self, application: &Application) -> Result<f32, String>;
+pub struct Report {
+    pub cycles: f32,
+    // Set if the program terminated by calling exit
+    pub exit_code: Option<i32>,
+    // Statistics of the simulated caches, by name
+    pub caches: Vec<(String, Stats)>,
+    // Statistics of the DRAM, if modelled
+    pub dram: Option<dram::Stats>,
+    // Statistics of the branch predictor, if any
+    pub branches: Option<predictor::Stats>,
+}
+
+trait VirtualMachine<'a> {
+    fn run(&mut self, application: &'a Application) -> Result<Report, RuntimeError> {
+        self.context().load(application)?;
+        self.resume()
+    }
+    // Runs a loaded program from the current state of the context
+    fn resume(&mut self) -> Result<Report, RuntimeError>;
+    fn context(&mut self) -> &mut Context;
 }
 
 fn main() {
     env_logger::init();
+
+    let matches = App::new("majorana")
+        .about("RISC-V virtual machine")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Runs a RISC-V assembly program")
+                .arg(
+                    Arg::with_name("vm")
+                        .long("vm")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&["mvm1", "mvm2", "mvm3", "mvm4", "mvm5", "fast"])
+                        .help("Virtual machine used to run the program"),
+                )
+                .arg(
+                    Arg::with_name("config")
+                        .long("config")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("TOML description of the machine (latencies, caches, buses, clock)"),
+                )
+                .arg(
+                    Arg::with_name("fast-forward")
+                        .long("fast-forward")
+                        .takes_value(true)
+                        .value_name("INSTRUCTIONS")
+                        .help("Number of instructions run by the fast interpreter before the VM"),
+                )
+                .arg(
+                    Arg::with_name("memory")
+                        .long("memory")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("BYTES")
+                        .help("Size of the main memory"),
+                )
+                .arg(
+                    Arg::with_name("register")
+                        .long("register")
+                        .short("r")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("REGISTER=VALUE")
+                        .help("Initial value of a register, e.g. t0=42"),
+                )
+                .arg(
+                    Arg::with_name("word")
+                        .long("word")
+                        .short("w")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("ADDRESS=VALUE")
+                        .help("Initial 32-bit word stored in memory, e.g. 0=1109"),
+                )
+                .arg(
+                    Arg::with_name("byte")
+                        .long("byte")
+                        .short("b")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("ADDRESS=VALUE")
+                        .help("Initial byte stored in memory, e.g. 4=1"),
+                )
+                .arg(
+                    Arg::with_name("program")
+                        .required(true)
+                        .value_name("PROGRAM")
+                        .help("RISC-V assembly file or RV32 ELF executable"),
+                ),
+        )
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        ("run", Some(sub_matches)) => run(sub_matches),
+        _ => Ok(0),
+    };
+    match result {
+        Ok(exit_code) => process::exit(exit_code),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<i32, String> {
+    let program = matches.value_of("program").unwrap();
+    let bytes = fs::read(program)
+        .map_err(|e| format_args!("unable to read {}: {}", program, e).to_string())?;
+    let application = if elf::is_elf(&bytes) {
+        elf::load(&bytes).map_err(|e| format_args!("{}: {}", program, e).to_string())?
+    } else {
+        let source = String::from_utf8(bytes)
+            .map_err(|e| format_args!("unable to read {}: {}", program, e).to_string())?;
+        parse(source).map_err(|e| format_args!("{}: {}", program, e).to_string())?
+    };
+
+    let memory = matches.value_of("memory").unwrap();
+    let memory_bytes = memory
+        .parse::<usize>()
+        .map_err(|e| format_args!("invalid memory size {}: {}", memory, e).to_string())?;
+
+    let config = match matches.value_of("config") {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    let mut vm: Box<dyn VirtualMachine> = match matches.value_of("vm").unwrap() {
+        "mvm1" => Box::new(Mvm1::new(memory_bytes, &config)),
+        "mvm2" => Box::new(Mvm2::new(memory_bytes, &config)),
+        "mvm3" => Box::new(Mvm3::new(memory_bytes, &config)),
+        "mvm4" => Box::new(Mvm4::new(memory_bytes, &config)),
+        "mvm5" => Box::new(Mvm5::new(memory_bytes, &config)),
+        _ => Box::new(Fast::new(memory_bytes)),
+    };
+    let fast_forward = match matches.value_of("fast-forward") {
+        Some(s) => s
+            .parse::<u64>()
+            .map_err(|e| format_args!("invalid number of instructions {}: {}", s, e).to_string())?,
+        None => 0,
+    };
+
+    let ctx = vm.context();
+    for assignment in matches.values_of("register").into_iter().flatten() {
+        let (register, value) = split_assignment(assignment)?;
+        let register = parse_register(register.to_string()).map_err(|e| e.to_string())?;
+        ctx.registers[register] = parse_value(value)?;
+    }
+    for assignment in matches.values_of("word").into_iter().flatten() {
+        let (address, value) = split_assignment(assignment)?;
+        let address = parse_address(address, 4, ctx)?;
+        let bytes = bytes_from_low_bits(parse_value(value)?);
+        ctx.memory[address] = bytes.0;
+        ctx.memory[address + 1] = bytes.1;
+        ctx.memory[address + 2] = bytes.2;
+        ctx.memory[address + 3] = bytes.3;
+    }
+    for assignment in matches.values_of("byte").into_iter().flatten() {
+        let (address, value) = split_assignment(assignment)?;
+        let address = parse_address(address, 1, ctx)?;
+        ctx.memory[address] = parse_value(value)? as i8;
+    }
+
+    let start = Instant::now();
+    let report = execute(vm.as_mut(), &application, fast_forward).map_err(|e| e.to_string())?;
+    let elapsed = start.elapsed();
+    let instructions = vm.context().instructions;
+    if fast_forward > 0 {
+        println!(
+            "{} instructions fast-forwarded",
+            instructions.min(fast_forward)
+        );
+    }
+    println!(
+        "{} cycles, {:.2} nanoseconds",
+        report.cycles,
+        nanoseconds(report.cycles, config.frequency)
+    );
+    println!(
+        "{} instructions, {:.2} MIPS on the host",
+        instructions,
+        mips(instructions, elapsed.as_secs_f64())
+    );
+    for (name, stats) in &report.caches {
+        println!("{}: {}", name, stats);
+    }
+    if let Some(stats) = report.dram {
+        println!("dram: {}", stats);
+    }
+    if let Some(stats) = report.branches {
+        println!("branches: {}", stats);
+    }
+    if let Some(exit_code) = report.exit_code {
+        println!("exit code {}", exit_code);
+    }
+    for (register, value) in vm.context().registers.iter() {
+        println!("{:<5}{}", format!("{:?}", register).to_lowercase(), value);
+    }
+    Ok(report.exit_code.unwrap_or(0))
+}
+
+// Runs the first instructions with the fast interpreter, then the rest with the VM
+fn execute<'a>(
+    vm: &mut dyn VirtualMachine<'a>,
+    application: &'a Application,
+    fast_forward: u64,
+) -> Result<Report, RuntimeError> {
+    if fast_forward == 0 {
+        return vm.run(application);
+    }
+    vm.context().load(application)?;
+    fast::fast_forward(vm.context(), fast_forward)?;
+    vm.resume()
+}
+
+// Millions of instructions per second of host time
+pub fn mips(instructions: u64, seconds: f64) -> f64 {
+    if seconds == 0. {
+        return 0.;
+    }
+    instructions as f64 / seconds / 1_000_000.
+}
+
+pub fn nanoseconds(cycles: f32, frequency: i64) -> f32 {
+    let s = cycles / frequency as f32;
+    s * SECOND_TO_NANOSECOND as f32
+}
+
+fn split_assignment(s: &str) -> Result<(&str, &str), String> {
+    match s.find('=') {
+        Some(i) => Ok((s[..i].trim(), s[i + 1..].trim())),
+        None => Err(format_args!("invalid assignment, expected KEY=VALUE: {}", s).to_string()),
+    }
+}
+
+fn parse_value(s: &str) -> Result<i32, String> {
+    s.parse::<i32>()
+        .map_err(|e| format_args!("invalid value {}: {}", s, e).to_string())
+}
+
+fn parse_address(s: &str, size: usize, ctx: &Context) -> Result<usize, String> {
+    let address = s
+        .parse::<usize>()
+        .map_err(|e| format_args!("invalid address {}: {}", s, e).to_string())?;
+    match address
+        .checked_add(size)
+        .filter(|end| *end <= ctx.memory.len())
+    {
+        Some(_) => Ok(address),
+        None => Err(format_args!(
+            "address {} is out of the {} bytes of memory",
+            address,
+            ctx.memory.len()
+        )
+        .to_string()),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::mvm1::Mvm1;
-    use crate::mvm2::Mvm2;
-    use crate::mvm3::Mvm3;
-    use crate::parser::parse;
-    use std::borrow::Borrow;
-    use std::fs;
 
-    fn execute(vm: &mut dyn VirtualMachine, instructions: &str) -> Result<f32, String> {
-        let application = parse(instructions.to_string()).unwrap();
-        return vm.run(&application);
+    fn execute<'a>(
+        vm: &mut dyn VirtualMachine<'a>,
+        application: &'a Application,
+    ) -> Result<Report, RuntimeError> {
+        vm.run(application)
     }
 
     fn stats(test: &str, cycles: f32) {
-        let s = cycles / I5_7360U as f32;
-        let ns = s * SECOND_TO_NANOSECOND as f32;
+        let ns = nanoseconds(cycles, I5_7360U);
         log::info!("{}: {} cycles, {:.2} nanoseconds", test, cycles, ns);
     }
 
+    fn prime_number() -> Application {
+        parse(fs::read_to_string("res/risc/prime-number-1109.asm").unwrap()).unwrap()
+    }
+
     #[test]
     fn test_mvm1_prime_number() {
-        let mut vm = Mvm1::new(5);
-        let cycles = execute(
-            &mut vm,
-            fs::read_to_string("res/risc/prime-number-1109.asm")
-                .unwrap()
-                .as_str()
-                .borrow(),
-        )
-        .unwrap();
+        let application = prime_number();
+        let mut vm = Mvm1::new(5, &Config::default());
+        let cycles = execute(&mut vm, &application).unwrap().cycles;
         stats("mvm1 - prime number", cycles);
     }
 
     #[test]
     fn test_mvm2_prime_number() {
-        let mut vm = Mvm2::new(5);
-        let cycles = execute(
-            &mut vm,
-            fs::read_to_string("res/risc/prime-number-1109.asm")
-                .unwrap()
-                .as_str()
-                .borrow(),
-        )
-        .unwrap();
+        let application = prime_number();
+        let mut vm = Mvm2::new(5, &Config::default());
+        let cycles = execute(&mut vm, &application).unwrap().cycles;
         stats("mvm2 - prime number", cycles);
     }
 
     #[test]
     fn test_mvm3_prime_number() {
-        let mut vm = Mvm3::new(5);
+        let application = prime_number();
+        let mut vm = Mvm3::new(5, &Config::default());
+        let cycles = execute(&mut vm, &application).unwrap().cycles;
+        stats("mvm3 - prime number", cycles);
+    }
+
+    #[test]
+    fn test_mvm4_prime_number() {
+        let application = prime_number();
+        let mut vm = Mvm4::new(5, &Config::default());
+        let cycles = execute(&mut vm, &application).unwrap().cycles;
+        stats("mvm4 - prime number", cycles);
+    }
+
+    #[test]
+    fn test_mvm5_prime_number() {
+        let application = prime_number();
+        let mut vm = Mvm5::new(5, &Config::default());
+        let cycles = execute(&mut vm, &application).unwrap().cycles;
+        stats("mvm5 - prime number", cycles);
+    }
+
+    #[test]
+    fn test_fast_prime_number() {
+        let application = prime_number();
+        let mut vm = Fast::new(5);
+        let start = Instant::now();
+        execute(&mut vm, &application).unwrap();
+        let instructions = vm.context().instructions;
+        log::info!(
+            "fast - prime number: {} instructions, {:.2} MIPS",
+            instructions,
+            mips(instructions, start.elapsed().as_secs_f64())
+        );
+    }
 
+    #[test]
+    fn test_fast_forward() {
+        let application = prime_number();
+        let mut reference = Mvm2::new(5, &Config::default());
+        let full = execute(&mut reference, &application).unwrap();
+
+        let mut vm = Mvm2::new(5, &Config::default());
+        let report = super::execute(&mut vm, &application, 1000).unwrap();
+        assert!(report.cycles < full.cycles);
+        assert_eq!(reference.context().registers, vm.context().registers);
+        assert_eq!(reference.context().instructions, vm.context().instructions);
+    }
+
+    #[test]
+    fn test_mips() {
+        assert_eq!(2., mips(1_000_000, 0.5));
+        assert_eq!(0., mips(1_000_000, 0.));
+    }
+
+    #[test]
+    fn test_elf() {
+        let application = elf::load(&fs::read("res/elf/hello").unwrap()).unwrap();
+        let vms: Vec<Box<dyn VirtualMachine>> = vec![
+            Box::new(Mvm1::new(0x12000, &Config::default())),
+            Box::new(Mvm2::new(0x12000, &Config::default())),
+            Box::new(Mvm3::new(0x12000, &Config::default())),
+            Box::new(Mvm4::new(0x12000, &Config::default())),
+            Box::new(Mvm5::new(0x12000, &Config::default())),
+            Box::new(Fast::new(0x12000)),
+        ];
+        for mut vm in vms {
+            vm.context().io.stdout = Box::new(std::io::sink());
+            let report = execute(vm.as_mut(), &application).unwrap();
+            assert_eq!(Some(42), report.exit_code);
+            assert_eq!(0x12000, vm.context().registers[opcodes::RegisterType::SP]);
+        }
+    }
+
+    #[test]
+    fn test_trap() {
+        let application = parse("addi t0, zero, -8\nsw t0, 0(t0)".to_string()).unwrap();
+        let vms: Vec<Box<dyn VirtualMachine>> = vec![
+            Box::new(Mvm1::new(16, &Config::default())),
+            Box::new(Mvm2::new(16, &Config::default())),
+            Box::new(Mvm3::new(16, &Config::default())),
+            Box::new(Mvm4::new(16, &Config::default())),
+            Box::new(Mvm5::new(16, &Config::default())),
+            Box::new(Fast::new(16)),
+        ];
+        for mut vm in vms {
+            let trap = opcodes::Trap {
+                exception: opcodes::Exception::StoreAccessFault,
+                pc: 20,
+                address: -8,
+            };
+            let error = execute(vm.as_mut(), &application).err().unwrap();
+            assert_eq!(20, error.pc);
+            // sw t0, 0(t0)
+            assert_eq!(Some(0x0052a023), error.instruction);
+            assert_eq!(error::Cause::Trap(trap), error.cause);
+        }
+    }
+
+    #[test]
+    fn test_misaligned_jump() {
+        // jalr only clears the lowest bit, so the target is in the text but not aligned
         let application = parse(
-            fs::read_to_string("res/risc/prime-number-1109.asm")
-                .unwrap()
-                .as_str()
-                .borrow()
+            "auipc t0, 0
+            jalr zero, 6(t0)
+            addi t1, zero, 1
+            addi t1, zero, 2"
                 .to_string(),
         )
         .unwrap();
-        let cycles = vm.run(&application).unwrap();
-        stats("mvm3 - prime number", cycles);
+        let vms: Vec<Box<dyn VirtualMachine>> = vec![
+            Box::new(Mvm1::new(0, &Config::default())),
+            Box::new(Mvm2::new(0, &Config::default())),
+            Box::new(Mvm3::new(0, &Config::default())),
+            Box::new(Mvm4::new(0, &Config::default())),
+            Box::new(Mvm5::new(0, &Config::default())),
+            Box::new(Fast::new(0)),
+        ];
+        for mut vm in vms {
+            let trap = opcodes::Trap {
+                exception: opcodes::Exception::InstructionAddressMisaligned,
+                pc: 6,
+                address: 6,
+            };
+            let error = execute(vm.as_mut(), &application).err().unwrap();
+            assert_eq!(6, error.pc);
+            assert_eq!(None, error.instruction);
+            assert_eq!(error::Cause::Trap(trap), error.cause);
+            assert_eq!(0, vm.context().registers[opcodes::RegisterType::T1]);
+        }
+    }
+
+    #[test]
+    fn test_initial_state() {
+        let application = parse("lw t1, 0, zero\nadd t0, t1, t2".to_string()).unwrap();
+        let mut vm = Mvm1::new(4, &Config::default());
+        vm.context().registers[parse_register("t2".to_string()).unwrap()] =
+            parse_value("2").unwrap();
+        let address = parse_address("0", 4, vm.context()).unwrap();
+        let bytes = bytes_from_low_bits(40);
+        vm.context().memory[address] = bytes.0;
+        assert!(parse_address("1", 4, vm.context()).is_err());
+        assert!(parse_address(&usize::MAX.to_string(), 1, vm.context()).is_err());
+        execute(&mut vm, &application).unwrap();
+        assert_eq!(42, vm.context().registers[opcodes::RegisterType::T0]);
+
+        assert_eq!(("t0", "42"), split_assignment("t0 = 42").unwrap());
+        assert!(split_assignment("t0").is_err());
     }
 }
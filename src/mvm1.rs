@@ -1,63 +1,66 @@
+use crate::config::Config;
+use crate::encoding::decode_or_illegal;
+use crate::error::RuntimeError;
 use crate::opcodes::*;
-use crate::VirtualMachine;
-use std::collections::HashMap;
-use std::fs;
-
-const CYCLES_MEMORY_ACCESS: f32 = 50.;
-const CYCLES_REGISTER_ACCESS: f32 = 1.;
-const CYCLES_DECODE: f32 = 1.;
+use crate::{Report, VirtualMachine};
 
 pub struct Mvm1 {
     ctx: Context,
+    config: Config,
     cycles: f32,
 }
 
-impl VirtualMachine for Mvm1 {
-    fn run(&mut self, application: &Application) -> Result<f32, String> {
-        while self.ctx.pc / 4 < application.instructions.len() as i32 {
-            let idx = self.fetch_instruction();
-            let runner = self.decode(application, idx);
-            let execution = self.execute(application, runner)?;
+impl VirtualMachine<'_> for Mvm1 {
+    fn resume(&mut self) -> Result<Report, RuntimeError> {
+        while self.ctx.exit_code.is_none() && self.ctx.in_text(self.ctx.pc) {
+            let word = self.fetch_instruction()?;
+            let runner = self.decode(word);
+            let execution = self.execute(runner.as_ref())?;
             self.ctx.pc = execution.0.pc;
             if write_back(&execution.1) {
                 self.ctx.write(&execution.0);
-                self.cycles += CYCLES_REGISTER_ACCESS;
+                self.cycles += self.config.latency.register_access;
             }
         }
-        return Ok(self.cycles);
+        Ok(Report {
+            cycles: self.cycles,
+            exit_code: self.ctx.exit_code,
+            caches: vec![],
+            dram: None,
+            branches: None,
+        })
+    }
+
+    fn context(&mut self) -> &mut Context {
+        &mut self.ctx
     }
 }
 
 impl Mvm1 {
-    pub fn new(memory_bytes: usize) -> Self {
+    pub fn new(memory_bytes: usize, config: &Config) -> Self {
         Mvm1 {
             ctx: Context::new(memory_bytes),
+            config: config.clone(),
             cycles: 0.,
         }
     }
 
-    fn fetch_instruction(&mut self) -> usize {
-        self.cycles += CYCLES_MEMORY_ACCESS;
-        (self.ctx.pc / 4) as usize
+    fn fetch_instruction(&mut self) -> Result<u32, Trap> {
+        self.cycles += self.config.latency.memory_access;
+        self.ctx.fetch(self.ctx.pc)
     }
 
-    fn decode<'a>(
-        &mut self,
-        application: &'a Application,
-        idx: usize,
-    ) -> &'a Box<dyn InstructionRunner> {
-        let runner = &application.instructions[idx];
-        self.cycles += CYCLES_DECODE;
-        runner
+    fn decode(&mut self, word: u32) -> Box<dyn InstructionRunner> {
+        self.cycles += self.config.latency.decode;
+        decode_or_illegal(word)
     }
 
     fn execute(
         &mut self,
-        application: &Application,
-        runner: &Box<dyn InstructionRunner>,
-    ) -> Result<(Execution, InstructionType), String> {
-        let execution = runner.run(&mut self.ctx, &application.labels)?;
-        self.cycles += cycles_per_instruction(runner.instruction_type());
+        runner: &dyn InstructionRunner,
+    ) -> Result<(Execution, InstructionType), RuntimeError> {
+        let execution = self.ctx.run(runner)?;
+        self.cycles += self.config.cycles[runner.instruction_type()];
         Ok((execution, runner.instruction_type()))
     }
 }
@@ -67,7 +70,8 @@ mod tests {
     use super::*;
     use crate::bit::bytes_from_low_bits;
     use crate::parser::parse;
-    use std::borrow::Borrow;
+    use std::collections::HashMap;
+    use std::fs;
 
     macro_rules! map (
     { $($key:expr => $value:expr),+ } => {
@@ -90,7 +94,7 @@ mod tests {
         assertions_memory: HashMap<usize, i8>,
     ) {
         let application = parse(instructions.to_string()).unwrap();
-        let mut runner = Mvm1::new(memory_bytes);
+        let mut runner = Mvm1::new(memory_bytes, &Config::default());
         for register in init_registers {
             runner.ctx.registers[register.0] = register.1;
         }
@@ -115,10 +119,42 @@ mod tests {
             map! {0 => bits.0,1 => bits.1,2 => bits.2,3 => bits.3},
             fs::read_to_string("res/risc/prime-number.asm")
                 .unwrap()
-                .as_str()
-                .borrow(),
+                .as_str(),
             map! {RegisterType::A0 => 4},
             map! {4=>1},
         );
     }
+
+    #[test]
+    fn test_exit() {
+        let application = parse(
+            "addi a0, zero, 2
+            addi a7, zero, 93
+            ecall
+            addi t0, zero, 1"
+                .to_string(),
+        )
+        .unwrap();
+        let mut runner = Mvm1::new(0, &Config::default());
+        let report = runner.run(&application).unwrap();
+        assert_eq!(Some(2), report.exit_code);
+        assert_eq!(0, runner.ctx.registers[RegisterType::T0]);
+    }
+
+    #[test]
+    fn test_config() {
+        let application = parse("addi t0, zero, 1\nlw t1, 0(zero)".to_string()).unwrap();
+        let config = Config::parse(
+            "[latency]
+            memory_access = 10
+            register_access = 2
+            decode = 0
+            [cycles]
+            lw = 5",
+        )
+        .unwrap();
+        let mut runner = Mvm1::new(4, &config);
+        // Per instruction: fetch 10, decode 0, write-back 2, then addi 1 and lw 5
+        assert_eq!(30., runner.run(&application).unwrap().cycles);
+    }
 }
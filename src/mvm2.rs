@@ -1,81 +1,82 @@
+use crate::config::Config;
+use crate::encoding::decode_or_illegal;
+use crate::error::RuntimeError;
+use crate::hierarchy::Hierarchy;
 use crate::opcodes::*;
-use crate::VirtualMachine;
-use std::collections::HashMap;
-use std::fs;
-
-const CYCLES_L1_ACCESS: f32 = 1.;
-const CYCLES_MEMORY_ACCESS: f32 = 50. + CYCLES_L1_ACCESS;
-const CYCLES_REGISTER_ACCESS: f32 = 1.;
-const CYCLES_DECODE: f32 = 1.;
-const L1I_SIZE: i32 = 64;
+use crate::{Report, VirtualMachine};
 
 pub struct Mvm2 {
     ctx: Context,
+    config: Config,
     cycles: f32,
-    l1i: (i32, i32),
+    caches: Hierarchy,
 }
 
-impl VirtualMachine for Mvm2 {
-    fn run(&mut self, application: &Application) -> Result<f32, String> {
-        while &self.ctx.pc / 4 < application.instructions.len() as i32 {
-            let idx = self.fetch_instruction();
-            let runner = self.decode(application, idx);
-            let execution = self.execute(application, runner)?;
+impl VirtualMachine<'_> for Mvm2 {
+    fn resume(&mut self) -> Result<Report, RuntimeError> {
+        while self.ctx.exit_code.is_none() && self.ctx.in_text(self.ctx.pc) {
+            let word = self.fetch_instruction()?;
+            let runner = self.decode(word);
+            let execution = self.execute(runner.as_ref())?;
             self.ctx.pc = execution.0.pc;
+            if let InstructionType::FENCEI = execution.1 {
+                // The next instructions have to be fetched again from the main memory
+                self.caches.invalidate_l1i();
+            }
             if write_back(&execution.1) {
                 self.ctx.write(&execution.0);
-                self.cycles += CYCLES_REGISTER_ACCESS;
+                self.cycles += self.config.latency.register_access;
             }
         }
-        return Ok(self.cycles);
+        Ok(Report {
+            cycles: self.cycles,
+            exit_code: self.ctx.exit_code,
+            caches: self.caches.stats(),
+            dram: self.caches.dram_stats(),
+            branches: None,
+        })
+    }
+
+    fn context(&mut self) -> &mut Context {
+        &mut self.ctx
     }
 }
 
 impl Mvm2 {
-    pub fn new(memory_bytes: usize) -> Self {
+    pub fn new(memory_bytes: usize, config: &Config) -> Self {
         Mvm2 {
             ctx: Context::new(memory_bytes),
+            config: config.clone(),
             cycles: 0.,
-            l1i: (-1, -1),
+            caches: Hierarchy::new(config),
         }
     }
 
-    fn fetch_instruction(&mut self) -> usize {
-        if self.present_in_l1i() {
-            self.cycles += CYCLES_L1_ACCESS;
-        } else {
-            self.fetch_l1i();
-        }
-
-        (self.ctx.pc / 4) as usize
-    }
-
-    fn present_in_l1i(&self) -> bool {
-        self.ctx.pc >= self.l1i.0 && self.ctx.pc <= self.l1i.1
+    fn fetch_instruction(&mut self) -> Result<u32, Trap> {
+        self.caches.set_cycle(self.cycles);
+        let (word, cycles) = self.caches.fetch(&self.ctx, self.ctx.pc)?;
+        self.cycles += cycles;
+        Ok(word)
     }
 
-    fn fetch_l1i(&mut self) {
-        self.cycles += CYCLES_MEMORY_ACCESS;
-        self.l1i = (self.ctx.pc, self.ctx.pc + L1I_SIZE);
-    }
-
-    fn decode<'a>(
-        &mut self,
-        application: &'a Application,
-        idx: usize,
-    ) -> &'a Box<dyn InstructionRunner> {
-        let runner = &application.instructions[idx];
-        self.cycles += CYCLES_DECODE;
-        runner
+    fn decode(&mut self, word: u32) -> Box<dyn InstructionRunner> {
+        self.cycles += self.config.latency.decode;
+        decode_or_illegal(word)
     }
 
     fn execute(
         &mut self,
-        application: &Application,
-        runner: &Box<dyn InstructionRunner>,
-    ) -> Result<(Execution, InstructionType), String> {
-        let execution = runner.run(&mut self.ctx, &application.labels)?;
-        self.cycles += cycles_per_instruction(runner.instruction_type());
+        runner: &dyn InstructionRunner,
+    ) -> Result<(Execution, InstructionType), RuntimeError> {
+        let cycles = match runner.memory_access(&self.ctx) {
+            Some(access) => {
+                self.caches.set_cycle(self.cycles);
+                self.caches.access(&access)
+            }
+            None => self.config.cycles[runner.instruction_type()],
+        };
+        let execution = self.ctx.run(runner)?;
+        self.cycles += cycles;
         Ok((execution, runner.instruction_type()))
     }
 }
@@ -84,8 +85,11 @@ impl Mvm2 {
 mod tests {
     use super::*;
     use crate::bit::bytes_from_low_bits;
+    use crate::cache::Stats;
+    use crate::dram;
     use crate::parser::parse;
-    use std::borrow::Borrow;
+    use std::collections::HashMap;
+    use std::fs;
 
     macro_rules! map (
     { $($key:expr => $value:expr),+ } => {
@@ -108,7 +112,7 @@ mod tests {
         assertions_memory: HashMap<usize, i8>,
     ) {
         let application = parse(instructions.to_string()).unwrap();
-        let mut runner = Mvm2::new(memory_bytes);
+        let mut runner = Mvm2::new(memory_bytes, &Config::default());
         for register in init_registers {
             runner.ctx.registers[register.0] = register.1;
         }
@@ -133,10 +137,135 @@ mod tests {
             map! {0 => bits.0,1 => bits.1,2 => bits.2,3 => bits.3},
             fs::read_to_string("res/risc/prime-number.asm")
                 .unwrap()
-                .as_str()
-                .borrow(),
+                .as_str(),
             map! {RegisterType::A0 => 4},
             map! {4=>1},
         );
     }
+
+    #[test]
+    fn test_exit() {
+        let application = parse(
+            "addi a0, zero, 2
+            addi a7, zero, 93
+            ecall
+            addi t0, zero, 1"
+                .to_string(),
+        )
+        .unwrap();
+        let mut runner = Mvm2::new(0, &Config::default());
+        let report = runner.run(&application).unwrap();
+        assert_eq!(Some(2), report.exit_code);
+        assert_eq!(0, runner.ctx.registers[RegisterType::T0]);
+    }
+
+    #[test]
+    fn test_l1d() {
+        // 2 lines of 16 bytes: the store to 32 and the load of 0 both evict a dirty line
+        let application = parse(
+            "sw t0, 0(zero)
+            lw t1, 4(zero)
+            sw t0, 32(zero)
+            lw t1, 0(zero)"
+                .to_string(),
+        )
+        .unwrap();
+        let config = Config::parse(
+            "[l1d]
+            size = 32
+            line = 16
+            associativity = 1",
+        )
+        .unwrap();
+        let mut runner = Mvm2::new(36, &config);
+        let report = runner.run(&application).unwrap();
+        assert_eq!(
+            vec![
+                (
+                    "l1i".to_string(),
+                    Stats {
+                        hits: 3,
+                        misses: 1,
+                        evictions: 0,
+                        write_backs: 0,
+                    }
+                ),
+                (
+                    "l1d".to_string(),
+                    Stats {
+                        hits: 1,
+                        misses: 3,
+                        evictions: 2,
+                        write_backs: 2,
+                    }
+                )
+            ],
+            report.caches
+        );
+    }
+
+    #[test]
+    fn test_dram() {
+        let application = parse(
+            "sw t0, 0(zero)
+            lw t1, 4(zero)
+            sw t0, 32(zero)
+            lw t1, 0(zero)"
+                .to_string(),
+        )
+        .unwrap();
+        let config = Config::parse(
+            "[l1d]
+            size = 32
+            line = 16
+            associativity = 1
+            [dram]",
+        )
+        .unwrap();
+        let mut runner = Mvm2::new(36, &config);
+        let report = runner.run(&application).unwrap();
+        // The 4 reads and 2 write-backs are all in the first row
+        assert_eq!(
+            Some(dram::Stats {
+                row_hits: 5,
+                row_misses: 1,
+                row_conflicts: 0,
+                refreshes: 0,
+            }),
+            report.dram
+        );
+    }
+
+    #[test]
+    fn test_fence_i() {
+        // The store replaces the last instruction with addi a1, zero, 2, which is only seen once
+        // fence.i invalidates L1I
+        let application = parse(
+            "auipc t0, 0
+            li t1, 0x00200593
+            sw t1, 20(t0)
+            fence.i
+            addi a0, zero, 1"
+                .to_string(),
+        )
+        .unwrap();
+        let mut runner = Mvm2::new(0, &Config::default());
+        runner.run(&application).unwrap();
+        assert_eq!(0, runner.ctx.registers[RegisterType::A0]);
+        assert_eq!(2, runner.ctx.registers[RegisterType::A1]);
+
+        let application = parse(
+            "auipc t0, 0
+            li t1, 0x00200593
+            sw t1, 20(t0)
+            nop
+            addi a0, zero, 1"
+                .to_string(),
+        )
+        .unwrap();
+        let mut runner = Mvm2::new(0, &Config::default());
+        runner.run(&application).unwrap();
+        assert_eq!(1, runner.ctx.registers[RegisterType::A0]);
+        assert_eq!(0, runner.ctx.registers[RegisterType::A1]);
+    }
 }
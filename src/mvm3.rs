@@ -1,27 +1,49 @@
+use crate::config::Config;
+use crate::encoding::{b_immediate, decode_or_illegal, OPCODE_BRANCH};
+use crate::error::RuntimeError;
+use crate::hierarchy::Hierarchy;
 use crate::opcodes::*;
+use crate::predictor::{self, Btb, Jump, Predictor, ReturnStack};
+use crate::{Report, VirtualMachine};
+use enum_map::EnumMap;
 use queues::*;
-use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::fmt;
-use std::fs;
-use std::sync::Once;
+use std::rc::Rc;
 
-const CYCLES_L1_ACCESS: f32 = 1.;
-const CYCLES_MEMORY_ACCESS: f32 = 50. + CYCLES_L1_ACCESS;
-const L1I_CACHE_LINE: i32 = 64 * 8;
-
-pub struct Mvm3<'a> {
+pub struct Mvm3 {
     ctx: Context,
+    // Shared by the fetch of the instructions and the execution of the loads and stores
+    caches: Hierarchy,
 
     fetch_unit: FetchUnit,
-    decode_bus: Bus<usize>,
+    decode_bus: Bus<Fetched>,
     decode_unit: DecodeUnit,
-    execute_bus: Bus<&'a Box<dyn InstructionRunner>>,
-    execute_unit: ExecuteUnit<'a>,
+    execute_bus: Bus<Decoded>,
+    execute_unit: ExecuteUnit,
     write_bus: Bus<ExecutionContext>,
     write_unit: WriteUnit,
     branch_unit: BranchUnit,
 }
 
+// Instruction fetched, with the address of the next one to fetch: the target of a conditional
+// branch predicted taken or of a jump known by the BTB or the RAS, the following instruction
+// otherwise
+#[derive(Clone)]
+struct Fetched {
+    word: u32,
+    pc: i32,
+    next_pc: i32,
+}
+
+#[derive(Clone)]
+struct Decoded {
+    runner: Rc<dyn InstructionRunner>,
+    word: u32,
+    pc: i32,
+    next_pc: i32,
+}
+
 pub struct Bus<T: Clone> {
     entry: Queue<Vec<T>>,
     buffer: Queue<Vec<T>>,
@@ -110,32 +132,49 @@ impl<T: Clone> fmt::Display for Bus<T> {
     }
 }
 
-impl<'a> Mvm3<'a> {
-    pub fn run(&mut self, application: &'a Application) -> Result<f32, String> {
+impl VirtualMachine<'_> for Mvm3 {
+    fn resume(&mut self) -> Result<Report, RuntimeError> {
         let mut cycles: f32 = 0.;
+        if self.ctx.exit_code.is_some() || !self.ctx.in_text(self.ctx.pc) {
+            // Already completed, e.g. while fast-forwarding
+            return Ok(Report {
+                cycles,
+                exit_code: self.ctx.exit_code,
+                caches: self.caches.stats(),
+                dram: self.caches.dram_stats(),
+                branches: Some(self.branch_unit.stats),
+            });
+        }
+        self.fetch_unit.flush(self.ctx.pc, &self.branch_unit.ras);
         loop {
             cycles += 1.;
             self.log(cycles);
+            self.caches.set_cycle(cycles);
 
             // Fetch
-            self.fetch_unit.cycle(application, &mut self.decode_bus);
+            self.fetch_unit.cycle(
+                &self.ctx,
+                &mut self.caches,
+                &self.branch_unit,
+                &mut self.decode_bus,
+            )?;
 
             // Decode
             self.decode_bus.connect();
             self.decode_unit
-                .cycle(application, &mut self.decode_bus, &mut self.execute_bus);
+                .cycle(&mut self.decode_bus, &mut self.execute_bus);
 
             // Execute
             self.execute_bus.connect();
 
             // Create branch unit assertions
             self.branch_unit
-                .assert(&mut self.ctx, &mut self.execute_bus);
+                .assert(&mut self.execute_bus, self.execute_unit.is_empty());
 
             // Execute
             self.execute_unit.cycle(
                 &mut self.ctx,
-                application,
+                &mut self.caches,
                 &mut self.execute_bus,
                 &mut self.write_bus,
             )?;
@@ -161,17 +200,43 @@ impl<'a> Mvm3<'a> {
                     self.write_unit.cycle(&mut self.ctx, &mut self.write_bus);
                 }
 
+                if self.branch_unit.l1i_invalidation {
+                    // fence.i: the next instructions have to be fetched again from the main memory
+                    self.caches.invalidate_l1i();
+                }
                 self.flush(self.ctx.pc);
             }
+
+            if self.ctx.exit_code.is_some() {
+                // The instructions executed before exit still have to be written back
+                while !self.write_bus.is_empty() {
+                    cycles += 1.;
+                    self.write_bus.connect();
+                    self.write_unit.cycle(&mut self.ctx, &mut self.write_bus);
+                }
+                break;
+            }
             if self.is_complete() {
                 break;
             }
         }
-        return Ok(cycles);
+        Ok(Report {
+            cycles,
+            exit_code: self.ctx.exit_code,
+            caches: self.caches.stats(),
+            dram: self.caches.dram_stats(),
+            branches: Some(self.branch_unit.stats),
+        })
     }
 
+    fn context(&mut self) -> &mut Context {
+        &mut self.ctx
+    }
+}
+
+impl Mvm3 {
     fn flush(&mut self, pc: i32) {
-        self.fetch_unit.flush(pc);
+        self.fetch_unit.flush(pc, &self.branch_unit.ras);
         self.decode_unit.flush();
         self.decode_bus.flush();
         self.execute_bus.flush();
@@ -189,18 +254,28 @@ impl<'a> Mvm3<'a> {
     }
 }
 
-impl<'a> Mvm3<'a> {
-    pub fn new(memory_bytes: usize) -> Self {
+impl Mvm3 {
+    pub fn new(memory_bytes: usize, config: &Config) -> Self {
+        Mvm3::build(memory_bytes, config, false)
+    }
+
+    // MVM-4: the results are forwarded to the dependent instructions as soon as they are executed
+    pub fn with_forwarding(memory_bytes: usize, config: &Config) -> Self {
+        Mvm3::build(memory_bytes, config, true)
+    }
+
+    fn build(memory_bytes: usize, config: &Config, forwarding: bool) -> Self {
         Mvm3 {
             ctx: Context::new(memory_bytes),
-            fetch_unit: FetchUnit::new(),
-            decode_bus: Bus::new(1),
+            caches: Hierarchy::new(config),
+            fetch_unit: FetchUnit::new(config),
+            decode_bus: Bus::new(config.mvm3.decode_bus),
             decode_unit: DecodeUnit::new(),
-            execute_bus: Bus::new(1),
-            execute_unit: ExecuteUnit::new(),
-            write_bus: Bus::new(1),
+            execute_bus: Bus::new(config.mvm3.execute_bus),
+            execute_unit: ExecuteUnit::new(config, forwarding),
+            write_bus: Bus::new(config.mvm3.write_bus),
             write_unit: WriteUnit::new(),
-            branch_unit: BranchUnit::new(),
+            branch_unit: BranchUnit::new(config),
         }
     }
 
@@ -222,76 +297,108 @@ impl<'a> Mvm3<'a> {
     }
 }
 
-struct L1I {
-    boundary: (i32, i32),
-}
-
-impl L1I {
-    fn present(&self, pc: i32) -> bool {
-        pc >= self.boundary.0 && pc <= self.boundary.1
-    }
-
-    fn fetch(&mut self, pc: i32) {
-        self.boundary = (pc, pc + L1I_CACHE_LINE);
-    }
-}
-
 struct FetchUnit {
     pc: i32,
-    l1i: L1I,
+    // Instruction being fetched
+    word: u32,
     remaining_cycles: f32,
     complete: bool,
     processing: bool,
+    // Speculative return address stack, updated by the instructions fetched
+    ras: ReturnStack,
 }
 
 impl FetchUnit {
-    fn new() -> Self {
+    fn new(config: &Config) -> Self {
         FetchUnit {
             pc: 0,
-            l1i: L1I { boundary: (-1, -1) },
+            word: 0,
             remaining_cycles: 0.0,
             complete: false,
             processing: false,
+            ras: ReturnStack::new(config.predictor.ras_depth),
         }
     }
 
-    fn cycle(&mut self, application: &Application, out_bus: &mut Bus<usize>) {
+    fn cycle(
+        &mut self,
+        ctx: &Context,
+        caches: &mut Hierarchy,
+        branch_unit: &BranchUnit,
+        out_bus: &mut Bus<Fetched>,
+    ) -> Result<(), Trap> {
         if self.complete {
-            return;
+            return Ok(());
         }
 
         if !self.processing {
-            self.processing = true;
-            if self.l1i.present(self.pc) {
-                self.remaining_cycles = CYCLES_L1_ACCESS;
-            } else {
-                self.remaining_cycles = CYCLES_MEMORY_ACCESS;
-                // Should be done after the processing of the 50 cycles
-                self.l1i.fetch(self.pc);
+            // E.g. a jump right after the last instruction
+            if !ctx.in_text(self.pc) {
+                self.complete = true;
+                return Ok(());
             }
+            self.processing = true;
+            // The line is copied right away, rather than once the memory access is done. The fetch
+            // is only misaligned after a flush, once the instructions before are completed.
+            let (word, cycles) = caches.fetch(ctx, self.pc)?;
+            self.word = word;
+            self.remaining_cycles = cycles;
         }
 
         self.remaining_cycles -= 1.;
         if self.remaining_cycles == 0. {
             if out_bus.is_full() {
                 self.remaining_cycles = 1.;
-                return;
+                return Ok(());
             }
 
             self.processing = false;
-            let current_pc = self.pc;
-            self.pc += 4;
-            if self.pc / 4 >= application.instructions.len() as i32 {
+            let next_pc = self.next_pc(branch_unit.predictor.as_ref(), &branch_unit.btb);
+            out_bus.add(vec![Fetched {
+                word: self.word,
+                pc: self.pc,
+                next_pc,
+            }]);
+            self.pc = next_pc;
+            if !ctx.in_text(self.pc) {
                 self.complete = true;
             }
-            out_bus.add(vec![(current_pc / 4) as usize]);
         }
+        Ok(())
     }
 
-    fn flush(&mut self, pc: i32) {
+    // The branches and jumps are predecoded, so that the fetch goes on from the target of the
+    // conditional branches predicted taken, and of the jumps whose target is known
+    fn next_pc(&mut self, predictor: &dyn Predictor, btb: &Btb) -> i32 {
+        if self.word & 0x7f == OPCODE_BRANCH {
+            let target = self.pc + b_immediate(self.word);
+            // A misaligned target is left to the branch unit, so that it only traps if taken
+            if target % 4 == 0 && predictor.predict(self.pc, target) {
+                return target;
+            }
+        } else if let Some(jump) = predictor::jump(self.word) {
+            match jump {
+                Jump::Call => self.ras.push(self.pc + 4),
+                Jump::Return => {
+                    if let Some(address) = self.ras.pop() {
+                        return address;
+                    }
+                }
+                Jump::Other => {}
+            }
+            if let Some(target) = btb.target(self.pc) {
+                return target;
+            }
+        }
+        self.pc + 4
+    }
+
+    // The return address stack goes back to the one of the instructions executed
+    fn flush(&mut self, pc: i32, ras: &ReturnStack) {
         self.processing = false;
         self.complete = false;
         self.pc = pc;
+        self.ras = ras.clone();
     }
 
     fn is_empty(&self) -> bool {
@@ -306,18 +413,17 @@ impl DecodeUnit {
         DecodeUnit {}
     }
 
-    fn cycle<'a>(
-        &self,
-        application: &'a Application,
-        in_bus: &mut Bus<usize>,
-        out_bus: &mut Bus<&'a Box<dyn InstructionRunner>>,
-    ) {
+    fn cycle(&self, in_bus: &mut Bus<Fetched>, out_bus: &mut Bus<Decoded>) {
         if !in_bus.contains_element_in_queue() || out_bus.is_full() {
             return;
         }
-        let idx = in_bus.get();
-        let runner = &application.instructions[idx];
-        out_bus.add(vec![runner]);
+        let fetched = in_bus.get();
+        out_bus.add(vec![Decoded {
+            runner: Rc::from(decode_or_illegal(fetched.word)),
+            word: fetched.word,
+            pc: fetched.pc,
+            next_pc: fetched.next_pc,
+        }]);
     }
 
     fn flush(&mut self) {}
@@ -328,10 +434,22 @@ impl DecodeUnit {
     }
 }
 
-struct ExecuteUnit<'a> {
-    processing: bool,
+// Executes the instructions on functional units: a unit accepts a new instruction once its issue
+// interval is elapsed, so a pipelined unit can hold several of them while an iterative one blocks
+struct ExecuteUnit {
+    // Issued instructions, completed in order
+    in_flight: VecDeque<InFlight>,
+    // Cycles before each unit accepts another instruction
+    busy: EnumMap<FunctionalUnit, f32>,
+    cycles: EnumMap<InstructionType, f32>,
+    intervals: EnumMap<FunctionalUnit, f32>,
+    // Set if the results bypass the write unit
+    forwarding: bool,
+}
+
+struct InFlight {
+    runner: Rc<dyn InstructionRunner>,
     remaining_cycles: f32,
-    runner: Option<&'a Box<dyn InstructionRunner>>,
 }
 
 #[derive(Clone)]
@@ -339,78 +457,147 @@ struct ExecutionContext {
     execution: Execution,
     instruction_type: InstructionType,
     write_registers: Vec<RegisterType>,
+    // Already written to the registers by the execute unit
+    forwarded: bool,
 }
 
-impl<'a> ExecuteUnit<'a> {
-    fn new() -> Self {
+impl ExecuteUnit {
+    fn new(config: &Config, forwarding: bool) -> Self {
         ExecuteUnit {
-            processing: false,
-            remaining_cycles: 0.0,
-            runner: None,
+            in_flight: VecDeque::new(),
+            busy: EnumMap::new(),
+            cycles: config.cycles,
+            intervals: EnumMap::from(|unit| config.units[unit].interval),
+            forwarding,
         }
     }
 
     fn cycle(
         &mut self,
         ctx: &mut Context,
-        application: &Application,
-        in_bus: &mut Bus<&'a Box<dyn InstructionRunner>>,
+        caches: &mut Hierarchy,
+        in_bus: &mut Bus<Decoded>,
         out_bus: &mut Bus<ExecutionContext>,
-    ) -> Result<(), String> {
-        if !self.processing {
-            if !in_bus.contains_element_in_queue() {
-                return Ok(());
+    ) -> Result<(), RuntimeError> {
+        if in_bus.contains_element_in_queue() {
+            self.issue(ctx, caches, in_bus);
+        }
+
+        for (_, busy) in self.busy.iter_mut() {
+            if *busy > 0. {
+                *busy -= 1.;
+            }
+        }
+        for in_flight in self.in_flight.iter_mut() {
+            if in_flight.remaining_cycles > 0. {
+                in_flight.remaining_cycles -= 1.;
             }
+        }
 
-            let runner = in_bus.get();
-            self.runner = Some(runner);
-            self.remaining_cycles = cycles_per_instruction(runner.instruction_type());
-            self.processing = true;
+        while let Some(in_flight) = self.in_flight.front() {
+            if in_flight.remaining_cycles != 0. || out_bus.is_full() {
+                break;
+            }
+            let runner = self.in_flight.pop_front().unwrap().runner;
+
+            log::debug!(
+                "execute {:?} {:?} {:?}",
+                runner.instruction_type(),
+                runner.write_registers(),
+                ctx.pending_writes,
+            );
+
+            let execution = ctx.run(runner.as_ref())?;
+            ctx.pc = execution.pc;
+            let forwarded = self.forwarding && write_back(&runner.instruction_type());
+            if forwarded {
+                // Nothing is issued speculatively, so the result can be written right away
+                ctx.write(&execution);
+            }
+            out_bus.add(vec![ExecutionContext {
+                execution,
+                instruction_type: runner.instruction_type(),
+                write_registers: runner.write_registers(),
+                forwarded,
+            }]);
+            if ctx.exit_code.is_some() {
+                // The younger instructions must not change the state once the program exited
+                self.in_flight.clear();
+                break;
+            }
         }
+        Ok(())
+    }
 
-        self.remaining_cycles -= 1.;
-        if self.remaining_cycles != 0. {
-            return Ok(());
+    fn issue(&mut self, ctx: &mut Context, caches: &mut Hierarchy, in_bus: &mut Bus<Decoded>) {
+        // Nothing is issued after a branch until the Branch Unit has checked the prediction
+        if self
+            .in_flight
+            .iter()
+            .any(|in_flight| control_flow(&in_flight.runner.instruction_type()))
+        {
+            return;
         }
 
-        if out_bus.is_full() {
-            self.remaining_cycles = 1.;
-            return Ok(());
+        let runner = in_bus.peek().runner;
+        let instruction_type = runner.instruction_type();
+        // A branch waits for the previous instructions to complete
+        if control_flow(&instruction_type) && !self.in_flight.is_empty() {
+            return;
         }
 
-        let runner = self.runner.unwrap();
+        // Structural hazard
+        let unit = functional_unit(&instruction_type);
+        if self.busy[unit] != 0. {
+            return;
+        }
 
         // To avoid writeback hazard, if the pipeline contains read registers not written yet, we wait for it.
-        if ctx.contain_written_registers(&runner.read_registers()) {
-            self.remaining_cycles = 1.;
-            return Ok(());
+        if self.forwarding {
+            // Only for the instructions still executing, the others have forwarded their result
+            if self.pending(&runner.read_registers()) {
+                return;
+            }
+        } else if ctx.contain_written_registers(&runner.read_registers()) {
+            return;
         }
 
-        log::debug!(
-            "execute {:?} {:?} {:?}",
-            runner.instruction_type(),
-            runner.write_registers(),
-            ctx.read_registers,
-        );
-
-        let execution = runner.run(ctx, &application.labels)?;
-        ctx.pc = execution.pc;
-        out_bus.add(vec![ExecutionContext {
-            execution,
-            instruction_type: runner.instruction_type(),
-            write_registers: runner.write_registers(),
-        }]);
+        in_bus.get();
         ctx.add_write_registers(runner.write_registers());
-        self.runner = None;
-        self.processing = false;
-        return Ok(());
+        // An instruction shorter than the interval of its unit releases it once completed
+        let cycles = match runner.memory_access(ctx) {
+            Some(access) => caches.access(&access),
+            None => self.cycles[instruction_type],
+        };
+        self.busy[unit] = self.intervals[unit].min(cycles);
+        self.in_flight.push_back(InFlight {
+            remaining_cycles: cycles,
+            runner,
+        });
+    }
+
+    // Whether an issued instruction writes one of the registers
+    fn pending(&self, registers: &[RegisterType]) -> bool {
+        self.in_flight.iter().any(|in_flight| {
+            in_flight
+                .runner
+                .write_registers()
+                .iter()
+                .any(|register| *register != RegisterType::ZERO && registers.contains(register))
+        })
     }
 
     fn is_empty(&self) -> bool {
-        !self.processing
+        self.in_flight.is_empty()
     }
 }
 
+fn control_flow(instruction_type: &InstructionType) -> bool {
+    jump(instruction_type)
+        || conditional_branching(instruction_type)
+        || *instruction_type == InstructionType::FENCEI
+}
+
 struct WriteUnit {}
 
 impl WriteUnit {
@@ -430,7 +617,9 @@ impl WriteUnit {
                 execution.execution.value,
                 execution.execution.register
             );
-            ctx.write(&execution.execution);
+            if !execution.forwarded {
+                ctx.write(&execution.execution);
+            }
             ctx.delete_write_registers(&execution.write_registers)
         }
     }
@@ -441,36 +630,59 @@ impl WriteUnit {
 }
 
 struct BranchUnit {
-    condition_branching_expected: Option<i32>,
-    jump: bool,
+    // Address of the conditional branch, and the next address predicted by the fetch unit
+    conditional_branching: Option<(i32, i32)>,
+    // Address of the jump, the next address predicted by the fetch unit and the kind of jump
+    jump: Option<(i32, i32, Jump)>,
+    fence_i: bool,
+    // Set when the pipeline is flushed because of fence.i
+    l1i_invalidation: bool,
+    predictor: Box<dyn Predictor>,
+    btb: Btb,
+    // Return address stack of the jumps executed
+    ras: ReturnStack,
+    stats: predictor::Stats,
 }
 
-impl<'a> BranchUnit {
-    fn new() -> Self {
+impl BranchUnit {
+    fn new(config: &Config) -> Self {
         BranchUnit {
-            condition_branching_expected: None,
-            jump: false,
+            conditional_branching: None,
+            jump: None,
+            fence_i: false,
+            l1i_invalidation: false,
+            predictor: predictor::new(&config.predictor),
+            btb: Btb::new(config.predictor.btb_entries),
+            ras: ReturnStack::new(config.predictor.ras_depth),
+            stats: predictor::Stats::default(),
         }
     }
 
-    fn assert(&mut self, ctx: &mut Context, execute_bus: &mut Bus<&'a Box<dyn InstructionRunner>>) {
-        if execute_bus.contains_element_in_queue() {
-            let runner = execute_bus.peek();
-            let instruction_type = runner.instruction_type();
+    // A branch is only issued once the execute unit is empty, so its outcome is known in ctx.pc
+    // once executed
+    fn assert(&mut self, execute_bus: &mut Bus<Decoded>, execute_unit_empty: bool) {
+        if execute_unit_empty && execute_bus.contains_element_in_queue() {
+            let decoded = execute_bus.peek();
+            let instruction_type = decoded.runner.instruction_type();
             if jump(&instruction_type) {
-                self.jump();
+                // An illegal word can't be a jump
+                let kind = predictor::jump(decoded.word).unwrap();
+                self.jump(decoded.pc, decoded.next_pc, kind);
             } else if conditional_branching(&instruction_type) {
-                self.conditional_branching(ctx.pc + 4)
+                self.conditional_branching(decoded.pc, decoded.next_pc)
+            } else if let InstructionType::FENCEI = instruction_type {
+                // The instructions fetched after fence.i may be stale
+                self.fence_i = true;
             }
         }
     }
 
-    fn jump(&mut self) {
-        self.jump = true;
+    fn jump(&mut self, pc: i32, predicted: i32, kind: Jump) {
+        self.jump = Some((pc, predicted, kind));
     }
 
-    fn conditional_branching(&mut self, expected: i32) {
-        self.condition_branching_expected = Some(expected);
+    fn conditional_branching(&mut self, pc: i32, predicted: i32) {
+        self.conditional_branching = Some((pc, predicted));
     }
 
     fn pipeline_to_be_flushed(&mut self, ctx: &Context, write_bus: &Bus<ExecutionContext>) -> bool {
@@ -478,13 +690,38 @@ impl<'a> BranchUnit {
             return false;
         }
 
-        let mut conditional_branching = false;
-        if self.condition_branching_expected.is_some() {
-            conditional_branching = self.condition_branching_expected.unwrap() != ctx.pc;
+        let mut misprediction = false;
+        if let Some((pc, predicted)) = self.conditional_branching {
+            self.predictor.update(pc, ctx.pc != pc + 4);
+            self.stats.branches += 1;
+            misprediction = predicted != ctx.pc;
+            if misprediction {
+                self.stats.mispredictions += 1;
+            }
+        }
+        if let Some((pc, predicted, kind)) = self.jump {
+            self.btb.update(pc, ctx.pc);
+            match kind {
+                Jump::Call => self.ras.push(pc + 4),
+                Jump::Return => {
+                    self.ras.pop();
+                }
+                Jump::Other => {}
+            }
+            self.stats.jumps += 1;
+            if predicted != ctx.pc {
+                misprediction = true;
+                self.stats.jump_mispredictions += 1;
+            }
         }
-        let assert = conditional_branching || self.jump;
-        self.condition_branching_expected = None;
-        self.jump = false;
+        let assert = misprediction || self.fence_i;
+        if assert {
+            self.stats.flushes += 1;
+        }
+        self.l1i_invalidation = self.fence_i;
+        self.conditional_branching = None;
+        self.jump = None;
+        self.fence_i = false;
         assert
     }
 }
@@ -493,8 +730,11 @@ impl<'a> BranchUnit {
 mod tests {
     use super::*;
     use crate::bit::bytes_from_low_bits;
+    use crate::cache::Stats;
     use crate::parser::parse;
-    use std::borrow::Borrow;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::sync::Once;
 
     macro_rules! map (
     { $($key:expr => $value:expr),+ } => {
@@ -526,14 +766,14 @@ mod tests {
         expected_cycles: f32,
     ) {
         let application = parse(instructions.to_string()).unwrap();
-        let mut runner = Mvm3::new(memory_bytes);
+        let mut runner = Mvm3::new(memory_bytes, &Config::default());
         for register in init_registers {
             runner.ctx.registers[register.0] = register.1;
         }
         for memory in init_memory {
             runner.ctx.memory[memory.0] = memory.1;
         }
-        let cycles = runner.run(&application).unwrap();
+        let cycles = runner.run(&application).unwrap().cycles;
         assert_eq!(expected_cycles, cycles);
         for assertion in assertions_registers {
             assert_eq!(assertion.1, runner.ctx.registers[assertion.0]);
@@ -552,11 +792,10 @@ mod tests {
             map! {0 => bits.0,1 => bits.1,2 => bits.2,3 => bits.3},
             fs::read_to_string("res/risc/prime-number.asm")
                 .unwrap()
-                .as_str()
-                .borrow(),
+                .as_str(),
             map! {RegisterType::A0 => 4},
             map! {4=>1},
-            5149.,
+            15629.,
         );
     }
 
@@ -568,11 +807,10 @@ mod tests {
             HashMap::new(),
             fs::read_to_string("res/risc/prime-number-1109.asm")
                 .unwrap()
-                .as_str()
-                .borrow(),
+                .as_str(),
             map! {RegisterType::A0 => 4},
             map! {4=>1},
-            5200.,
+            15632.,
         );
     }
 
@@ -661,4 +899,320 @@ mod tests {
             59.,
         );
     }
+
+    #[test]
+    fn test_pipelining_load_use() {
+        setup();
+        // addi has to wait for the value loaded into t0
+        assert(
+            HashMap::new(),
+            4,
+            map! {0 => 41},
+            "lw t0, 0(zero)
+            addi t1, t0, 1",
+            map! {RegisterType::T0 => 41, RegisterType::T1 => 42},
+            HashMap::new(),
+            106.,
+        );
+    }
+
+    #[test]
+    fn test_pipelining_write_after_write() {
+        setup();
+        // The second write of t0 is still pending once the first one is written back
+        assert(
+            HashMap::new(),
+            4,
+            map! {0 => 41},
+            "addi t0, zero, 1
+            lw t0, 0(zero)
+            addi t1, t0, 1",
+            map! {RegisterType::T0 => 41, RegisterType::T1 => 42},
+            HashMap::new(),
+            107.,
+        );
+    }
+
+    #[test]
+    fn test_pipelining_conditional_branching_unsigned() {
+        setup();
+        assert(
+            HashMap::new(),
+            0,
+            HashMap::new(),
+            "addi t0, zero, -1
+            addi t1, zero, 1
+            bltu t1, t0, foo
+            addi t1, zero, 2
+            foo:
+            addi t2, zero, 3",
+            map! {RegisterType::T0=> -1, RegisterType::T1 => 1, RegisterType::T2 => 3 },
+            HashMap::new(),
+            61.,
+        );
+    }
+
+    #[test]
+    fn test_pipelining_multiplier() {
+        setup();
+        // The multiplier is pipelined: the independent mul are issued on consecutive cycles
+        assert(
+            map! {RegisterType::A0 => 6, RegisterType::A1 => 7},
+            0,
+            HashMap::new(),
+            "mul t0, a0, a1
+            mul t1, a0, a1
+            mul t2, a0, a1",
+            map! {RegisterType::T0 => 42, RegisterType::T1 => 42, RegisterType::T2 => 42},
+            HashMap::new(),
+            58.,
+        );
+    }
+
+    #[test]
+    fn test_structural_hazard() {
+        setup();
+        // The divider is iterative: each div waits for the previous one to complete
+        assert(
+            map! {RegisterType::A0 => 42, RegisterType::A1 => 7},
+            0,
+            HashMap::new(),
+            "div t0, a0, a1
+            div t1, a0, a1
+            div t2, a0, a1",
+            map! {RegisterType::T0 => 6, RegisterType::T1 => 6, RegisterType::T2 => 6},
+            HashMap::new(),
+            113.,
+        );
+    }
+
+    #[test]
+    fn test_l1d() {
+        setup();
+        // Only the first access misses, the others are in the same line
+        let application = parse(
+            "addi t0, zero, 42
+            sw t0, 0(zero)
+            lw t1, 0(zero)
+            lw t2, 4(zero)"
+                .to_string(),
+        )
+        .unwrap();
+        let mut runner = Mvm3::new(8, &Config::default());
+        let report = runner.run(&application).unwrap();
+        assert_eq!(42, runner.ctx.registers[RegisterType::T1]);
+        assert_eq!(108., report.cycles);
+        assert_eq!(
+            vec![
+                (
+                    "l1i".to_string(),
+                    Stats {
+                        hits: 3,
+                        misses: 1,
+                        evictions: 0,
+                        write_backs: 0,
+                    }
+                ),
+                (
+                    "l1d".to_string(),
+                    Stats {
+                        hits: 2,
+                        misses: 1,
+                        evictions: 0,
+                        write_backs: 0,
+                    }
+                )
+            ],
+            report.caches
+        );
+    }
+
+    #[test]
+    fn test_exit() {
+        let application = parse(
+            "addi a0, zero, 2
+            addi a7, zero, 93
+            ecall
+            addi t0, zero, 1"
+                .to_string(),
+        )
+        .unwrap();
+        let mut runner = Mvm3::new(0, &Config::default());
+        let report = runner.run(&application).unwrap();
+        assert_eq!(Some(2), report.exit_code);
+        assert_eq!(0, runner.ctx.registers[RegisterType::T0]);
+        assert_eq!(57., report.cycles);
+    }
+
+    #[test]
+    fn test_exit_wide_buses() {
+        // The load delays the ecall, so that the next instruction completes in the same cycle
+        let application = parse(
+            "addi a0, zero, 7
+            addi a7, zero, 93
+            lw t1, 0(zero)
+            ecall
+            addi t0, zero, 1"
+                .to_string(),
+        )
+        .unwrap();
+        let config = Config::parse(
+            "[mvm3]
+            write_bus = 4
+            execute_bus = 4
+            decode_bus = 4",
+        )
+        .unwrap();
+        for mut runner in [Mvm3::new(4, &config), Mvm3::with_forwarding(4, &config)] {
+            let report = runner.run(&application).unwrap();
+            assert_eq!(Some(7), report.exit_code);
+            assert_eq!(0, runner.ctx.registers[RegisterType::T0]);
+        }
+    }
+
+    #[test]
+    fn test_fence_i() {
+        // The store replaces the last instruction with addi a1, zero, 2, which is only seen once
+        // fence.i invalidates L1I
+        let application = parse(
+            "auipc t0, 0
+            li t1, 0x00200593
+            sw t1, 20(t0)
+            fence.i
+            addi a0, zero, 1"
+                .to_string(),
+        )
+        .unwrap();
+        let mut runner = Mvm3::new(0, &Config::default());
+        runner.run(&application).unwrap();
+        assert_eq!(0, runner.ctx.registers[RegisterType::A0]);
+        assert_eq!(2, runner.ctx.registers[RegisterType::A1]);
+
+        let application = parse(
+            "auipc t0, 0
+            li t1, 0x00200593
+            sw t1, 20(t0)
+            nop
+            addi a0, zero, 1"
+                .to_string(),
+        )
+        .unwrap();
+        let mut runner = Mvm3::new(0, &Config::default());
+        runner.run(&application).unwrap();
+        assert_eq!(1, runner.ctx.registers[RegisterType::A0]);
+        assert_eq!(0, runner.ctx.registers[RegisterType::A1]);
+    }
+
+    #[test]
+    fn test_config() {
+        let application =
+            parse(fs::read_to_string("res/risc/prime-number-1109.asm").unwrap()).unwrap();
+        let config = Config::parse(
+            "[latency]
+            memory_access = 10
+            [cycles]
+            rem = 10",
+        )
+        .unwrap();
+        let mut runner = Mvm3::new(5, &config);
+        assert_eq!(10022., runner.run(&application).unwrap().cycles);
+        assert_eq!(4, runner.ctx.registers[RegisterType::A0]);
+    }
+
+    #[test]
+    fn test_predictor() {
+        let application = parse(
+            "addi t0, zero, 10
+            loop:
+            addi t0, t0, -1
+            bnez t0, loop
+            addi t1, zero, 1"
+                .to_string(),
+        )
+        .unwrap();
+        // Kind, cycles and mispredictions. Each iteration sees another global history, so gshare
+        // doesn't learn the loop within 10 iterations.
+        let expected = [
+            ("not-taken", 113., 9),
+            ("btfn", 89., 1),
+            ("bimodal", 92., 2),
+            ("gshare", 113., 9),
+            ("tournament", 92., 2),
+        ];
+        for (kind, cycles, mispredictions) in expected.iter() {
+            let config =
+                Config::parse(format!("[predictor]\nkind = \"{}\"", kind).as_str()).unwrap();
+            let mut runner = Mvm3::new(0, &config);
+            let report = runner.run(&application).unwrap();
+            assert_eq!(0, runner.ctx.registers[RegisterType::T0]);
+            assert_eq!(1, runner.ctx.registers[RegisterType::T1]);
+            assert_eq!(*cycles, report.cycles);
+            let branches = report.branches.unwrap();
+            assert_eq!(10, branches.branches);
+            assert_eq!(*mispredictions, branches.mispredictions);
+            assert_eq!(*mispredictions, branches.flushes);
+        }
+    }
+
+    #[test]
+    fn test_btb() {
+        let application = parse(
+            "addi t0, zero, 10
+            loop:
+            call inc
+            addi t0, t0, -1
+            bnez t0, loop
+            j end
+            inc:
+            addi t1, t1, 1
+            ret
+            end:"
+                .to_string(),
+        )
+        .unwrap();
+        // Without BTB, every jump flushes. The BTB misses the first call and return and the jump to
+        // end, the RAS predicts every return.
+        let expected = [
+            ("", 202., 21),
+            ("btb_entries = 16", 148., 3),
+            ("ras_depth = 4", 172., 11),
+            ("btb_entries = 16\nras_depth = 4", 145., 2),
+        ];
+        for (predictor, cycles, jump_mispredictions) in expected.iter() {
+            let config =
+                Config::parse(format!("[predictor]\nkind = \"bimodal\"\n{}", predictor).as_str())
+                    .unwrap();
+            let mut runner = Mvm3::new(0, &config);
+            let report = runner.run(&application).unwrap();
+            assert_eq!(10, runner.ctx.registers[RegisterType::T1]);
+            assert_eq!(*cycles, report.cycles);
+            let branches = report.branches.unwrap();
+            assert_eq!(21, branches.jumps);
+            assert_eq!(*jump_mispredictions, branches.jump_mispredictions);
+            assert_eq!(2 + *jump_mispredictions, branches.flushes);
+        }
+    }
+
+    #[test]
+    fn test_misaligned_prediction() {
+        // The backward branch is predicted taken, but its target is misaligned, so the fetch goes
+        // on with the next instruction: the branch isn't taken, nothing traps
+        let mut application = parse(
+            "addi t0, zero, 1
+            nop
+            addi t1, zero, 1"
+                .to_string(),
+        )
+        .unwrap();
+        application.instructions[1] = Box::new(Bne {
+            rs1: RegisterType::T0,
+            rs2: RegisterType::T0,
+            offset: -2,
+        });
+        let config = Config::parse("[predictor]\nkind = \"btfn\"").unwrap();
+        let mut vm = Mvm3::new(0, &config);
+        let report = vm.run(&application).unwrap();
+        assert_eq!(1, vm.ctx.registers[RegisterType::T1]);
+        assert_eq!(0, report.branches.unwrap().mispredictions);
+    }
 }
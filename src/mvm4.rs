@@ -0,0 +1,158 @@
+use crate::config::Config;
+use crate::error::RuntimeError;
+use crate::mvm3::Mvm3;
+use crate::opcodes::*;
+use crate::{Report, VirtualMachine};
+
+// MVM-3 with a forwarding network: an executed result goes straight to the instructions depending
+// on it, instead of waiting for the write unit
+pub struct Mvm4 {
+    pipeline: Mvm3,
+}
+
+impl VirtualMachine<'_> for Mvm4 {
+    fn resume(&mut self) -> Result<Report, RuntimeError> {
+        self.pipeline.resume()
+    }
+
+    fn context(&mut self) -> &mut Context {
+        self.pipeline.context()
+    }
+}
+
+impl Mvm4 {
+    pub fn new(memory_bytes: usize, config: &Config) -> Self {
+        Mvm4 {
+            pipeline: Mvm3::with_forwarding(memory_bytes, config),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use std::collections::HashMap;
+    use std::fs;
+
+    macro_rules! map (
+    { $($key:expr => $value:expr),+ } => {
+        {
+            let mut m = ::std::collections::HashMap::new();
+            $(
+                m.insert($key, $value);
+            )+
+            m
+        }
+     };
+);
+
+    // Returns the cycles of MVM-4 and MVM-3, once checked that both end in the same state
+    fn run(
+        init_registers: HashMap<RegisterType, i32>,
+        memory_bytes: usize,
+        init_memory: HashMap<usize, i8>,
+        instructions: &str,
+        assertions_registers: HashMap<RegisterType, i32>,
+    ) -> (f32, f32) {
+        let application = parse(instructions.to_string()).unwrap();
+        let mut vms: Vec<Box<dyn VirtualMachine>> = vec![
+            Box::new(Mvm4::new(memory_bytes, &Config::default())),
+            Box::new(Mvm3::new(memory_bytes, &Config::default())),
+        ];
+        let mut cycles = vec![];
+        for vm in vms.iter_mut() {
+            for register in init_registers.iter() {
+                vm.context().registers[*register.0] = *register.1;
+            }
+            for memory in init_memory.iter() {
+                vm.context().memory[*memory.0] = *memory.1;
+            }
+            cycles.push(vm.run(&application).unwrap().cycles);
+            for assertion in assertions_registers.iter() {
+                assert_eq!(*assertion.1, vm.context().registers[*assertion.0]);
+            }
+        }
+        (cycles[0], cycles[1])
+    }
+
+    #[test]
+    fn test_prime_number_1109() {
+        let (cycles, mvm3) = run(
+            HashMap::new(),
+            5,
+            HashMap::new(),
+            fs::read_to_string("res/risc/prime-number-1109.asm")
+                .unwrap()
+                .as_str(),
+            map! {RegisterType::A0 => 4},
+        );
+        assert_eq!(15073., cycles);
+        assert_eq!(15632., mvm3);
+    }
+
+    #[test]
+    fn test_dependency_chain() {
+        // Each addi reads the result of the previous one, a cycle earlier than through the write unit
+        let (cycles, mvm3) = run(
+            HashMap::new(),
+            0,
+            HashMap::new(),
+            "addi t0, zero, 1
+            addi t0, t0, 1
+            addi t0, t0, 1
+            addi t0, t0, 1",
+            map! {RegisterType::T0 => 4},
+        );
+        assert_eq!(57., cycles);
+        assert_eq!(60., mvm3);
+    }
+
+    #[test]
+    fn test_load_use() {
+        // The load still has to complete, only the trip through the write unit is saved
+        let (cycles, mvm3) = run(
+            HashMap::new(),
+            4,
+            map! {0 => 41},
+            "lw t0, 0(zero)
+            addi t1, t0, 1",
+            map! {RegisterType::T0 => 41, RegisterType::T1 => 42},
+        );
+        assert_eq!(105., cycles);
+        assert_eq!(106., mvm3);
+    }
+
+    #[test]
+    fn test_write_after_write() {
+        // The addi writes t0 before the load, completed in order, so t1 reads the loaded value
+        let (cycles, _) = run(
+            map! {RegisterType::A0 => 8},
+            4,
+            map! {0 => 41},
+            "addi t0, zero, 1
+            lw t0, 0(zero)
+            addi t1, t0, 1
+            addi t0, a0, 0",
+            map! {RegisterType::T0 => 8, RegisterType::T1 => 42},
+        );
+        assert_eq!(107., cycles);
+    }
+
+    #[test]
+    fn test_branch() {
+        let (cycles, mvm3) = run(
+            HashMap::new(),
+            0,
+            HashMap::new(),
+            "addi t0, zero, 3
+            loop:
+            addi t0, t0, -1
+            bnez t0, loop
+            addi t1, zero, 1",
+            map! {RegisterType::T0 => 0, RegisterType::T1 => 1},
+        );
+        assert_eq!(67., cycles);
+        assert_eq!(71., mvm3);
+    }
+}
@@ -0,0 +1,565 @@
+use crate::config::Config;
+use crate::encoding::decode_or_illegal;
+use crate::error::RuntimeError;
+use crate::hierarchy::Hierarchy;
+use crate::opcodes::*;
+use crate::{Report, VirtualMachine};
+use enum_map::EnumMap;
+use std::fmt;
+use std::rc::Rc;
+
+// Classic 5-stage pipeline (IF, ID, EX, MEM, WB) with a pipeline register between each pair of
+// stages. A stage taking several cycles (a missed fetch, a division, a memory access) keeps its
+// instruction in the register before it, stalling the stages behind.
+pub struct Mvm5 {
+    ctx: Context,
+    caches: Hierarchy,
+    cycles: EnumMap<InstructionType, f32>,
+    // Address computation of the loads and stores, their access takes place in MEM
+    cycles_address: f32,
+    pipeline: PipelineRegisters,
+    // Next instruction to fetch, None once past the end of the program
+    pc: Option<i32>,
+    fetching: Option<Fetching>,
+    // Set when a branch redirects the fetch, which restarts on the next cycle
+    redirected: bool,
+    // Cycles left to the instructions in EX and MEM, None until they start
+    execute_remaining: Option<f32>,
+    memory_remaining: Option<f32>,
+    // Address of the instruction following the last one executed
+    next_pc: i32,
+    // Set from the first cycle until the program is completed
+    running: bool,
+    // Cycles since the start of the run
+    elapsed: f32,
+}
+
+// Contents of the pipeline registers, None for a bubble
+#[derive(Clone, Debug, Default)]
+pub struct PipelineRegisters {
+    pub if_id: Option<IfId>,
+    pub id_ex: Option<IdEx>,
+    pub ex_mem: Option<ExMem>,
+    pub mem_wb: Option<MemWb>,
+}
+
+#[derive(Clone, Debug)]
+pub struct IfId {
+    pub pc: i32,
+    pub word: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct IdEx {
+    pub pc: i32,
+    pub runner: Rc<dyn InstructionRunner>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ExMem {
+    pub pc: i32,
+    pub runner: Rc<dyn InstructionRunner>,
+    // Result of EX, None for the loads and stores, executed by MEM
+    pub execution: Option<Execution>,
+    pub access: Option<MemoryAccess>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MemWb {
+    pub pc: i32,
+    pub instruction_type: InstructionType,
+    pub execution: Execution,
+}
+
+struct Fetching {
+    pc: i32,
+    word: u32,
+    remaining: f32,
+}
+
+impl fmt::Display for PipelineRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IF/ID: ")?;
+        match &self.if_id {
+            Some(if_id) => write!(f, "pc={} word={:#010x}", if_id.pc, if_id.word)?,
+            None => write!(f, "bubble")?,
+        }
+        write!(f, ", ID/EX: ")?;
+        match &self.id_ex {
+            Some(id_ex) => write!(f, "pc={} {:?}", id_ex.pc, id_ex.runner)?,
+            None => write!(f, "bubble")?,
+        }
+        write!(f, ", EX/MEM: ")?;
+        match &self.ex_mem {
+            Some(ex_mem) => {
+                write!(f, "pc={} {:?}", ex_mem.pc, ex_mem.runner.instruction_type())?;
+                if let Some(execution) = &ex_mem.execution {
+                    write!(f, " {:?}={}", execution.register, execution.value)?;
+                }
+                if let Some(access) = &ex_mem.access {
+                    write!(f, " address={}", access.address)?;
+                }
+            }
+            None => write!(f, "bubble")?,
+        }
+        write!(f, ", MEM/WB: ")?;
+        match &self.mem_wb {
+            Some(mem_wb) => write!(
+                f,
+                "pc={} {:?} {:?}={}",
+                mem_wb.pc,
+                mem_wb.instruction_type,
+                mem_wb.execution.register,
+                mem_wb.execution.value
+            ),
+            None => write!(f, "bubble"),
+        }
+    }
+}
+
+impl VirtualMachine<'_> for Mvm5 {
+    fn resume(&mut self) -> Result<Report, RuntimeError> {
+        let mut cycles: f32 = 0.;
+        self.running = false;
+        while self.step()?.is_some() {
+            cycles += 1.;
+        }
+        Ok(Report {
+            cycles,
+            exit_code: self.ctx.exit_code,
+            caches: self.caches.stats(),
+            dram: self.caches.dram_stats(),
+            branches: None,
+        })
+    }
+
+    fn context(&mut self) -> &mut Context {
+        &mut self.ctx
+    }
+}
+
+impl Mvm5 {
+    pub fn new(memory_bytes: usize, config: &Config) -> Self {
+        Mvm5 {
+            ctx: Context::new(memory_bytes),
+            caches: Hierarchy::new(config),
+            cycles: config.cycles,
+            cycles_address: config.units[FunctionalUnit::Alu].latency,
+            pipeline: PipelineRegisters::default(),
+            pc: None,
+            fetching: None,
+            redirected: false,
+            execute_remaining: None,
+            memory_remaining: None,
+            next_pc: 0,
+            running: false,
+            elapsed: 0.,
+        }
+    }
+
+    // Runs a cycle of the loaded program, the first one starting from ctx.pc. Returns the pipeline
+    // registers at the end of the cycle, None once the program is completed.
+    pub fn step(&mut self) -> Result<Option<&PipelineRegisters>, RuntimeError> {
+        if !self.running {
+            if self.ctx.exit_code.is_some() || !self.ctx.in_text(self.ctx.pc) {
+                return Ok(None);
+            }
+            self.restart(self.ctx.pc);
+        }
+        self.elapsed += 1.;
+        self.caches.set_cycle(self.elapsed);
+        let result = self.cycle();
+        if result.is_err() {
+            // The next step starts again from the faulting instruction
+            self.running = false;
+        }
+        result?;
+        log::debug!("cycle {}: {}", self.elapsed, self.pipeline);
+        if self.is_complete() {
+            self.running = false;
+            self.ctx.pc = self.next_pc;
+        }
+        Ok(Some(&self.pipeline))
+    }
+
+    fn restart(&mut self, pc: i32) {
+        self.pipeline = PipelineRegisters::default();
+        self.pc = Some(pc);
+        self.fetching = None;
+        self.redirected = false;
+        self.execute_remaining = None;
+        self.memory_remaining = None;
+        self.next_pc = pc;
+        self.running = true;
+        self.elapsed = 0.;
+    }
+
+    // The stages run from the last one, so that each instruction moves forward by one stage at most
+    fn cycle(&mut self) -> Result<(), RuntimeError> {
+        self.write_back();
+        self.memory()?;
+        self.execute()?;
+        self.decode();
+        self.fetch()?;
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.pc.is_none()
+            && self.fetching.is_none()
+            && self.pipeline.if_id.is_none()
+            && self.pipeline.id_ex.is_none()
+            && self.pipeline.ex_mem.is_none()
+            && self.pipeline.mem_wb.is_none()
+    }
+
+    // The registers are written as soon as a result is produced, which stands for the forwarding
+    // paths to EX, so WB only retires the instruction
+    fn write_back(&mut self) {
+        self.pipeline.mem_wb = None;
+    }
+
+    fn memory(&mut self) -> Result<(), RuntimeError> {
+        let ex_mem = match &self.pipeline.ex_mem {
+            Some(ex_mem) => ex_mem,
+            None => return Ok(()),
+        };
+        let remaining = match self.memory_remaining {
+            Some(remaining) => remaining,
+            None => match &ex_mem.access {
+                Some(access) => self.caches.access(access),
+                None => 1.,
+            },
+        } - 1.;
+        self.memory_remaining = Some(remaining);
+        if remaining > 0. || self.pipeline.mem_wb.is_some() {
+            return Ok(());
+        }
+
+        let ex_mem = self.pipeline.ex_mem.take().unwrap();
+        self.memory_remaining = None;
+        let execution = match ex_mem.execution {
+            Some(execution) => execution,
+            None => {
+                let execution = self.run_at(ex_mem.pc, ex_mem.runner.as_ref())?;
+                if write_back(&ex_mem.runner.instruction_type()) {
+                    self.ctx.write(&execution);
+                }
+                execution
+            }
+        };
+        self.pipeline.mem_wb = Some(MemWb {
+            pc: ex_mem.pc,
+            instruction_type: ex_mem.runner.instruction_type(),
+            execution,
+        });
+        Ok(())
+    }
+
+    fn execute(&mut self) -> Result<(), RuntimeError> {
+        let id_ex = match &self.pipeline.id_ex {
+            Some(id_ex) => id_ex,
+            None => return Ok(()),
+        };
+        let instruction_type = id_ex.runner.instruction_type();
+        let memory = functional_unit(&instruction_type) == FunctionalUnit::Memory;
+        let remaining = match self.execute_remaining {
+            Some(remaining) => remaining,
+            None if memory => self.cycles_address,
+            None => self.cycles[instruction_type.clone()],
+        } - 1.;
+        self.execute_remaining = Some(remaining);
+        if remaining > 0. || self.pipeline.ex_mem.is_some() {
+            return Ok(());
+        }
+
+        let id_ex = self.pipeline.id_ex.take().unwrap();
+        self.execute_remaining = None;
+        if memory {
+            self.next_pc = id_ex.pc + 4;
+            self.pipeline.ex_mem = Some(ExMem {
+                pc: id_ex.pc,
+                access: id_ex.runner.memory_access(&self.ctx),
+                runner: id_ex.runner,
+                execution: None,
+            });
+            return Ok(());
+        }
+
+        let execution = self.run_at(id_ex.pc, id_ex.runner.as_ref())?;
+        if write_back(&instruction_type) {
+            self.ctx.write(&execution);
+        }
+        self.next_pc = execution.pc;
+        self.pipeline.ex_mem = Some(ExMem {
+            pc: id_ex.pc,
+            runner: id_ex.runner,
+            execution: Some(execution),
+            access: None,
+        });
+
+        if self.ctx.exit_code.is_some() {
+            // The instructions before exit still go through MEM and WB
+            self.pipeline.if_id = None;
+            self.fetching = None;
+            self.pc = None;
+        } else if instruction_type == InstructionType::FENCEI {
+            // The instructions fetched after fence.i may be stale
+            self.caches.invalidate_l1i();
+            self.redirect(execution.pc);
+        } else if execution.pc != id_ex.pc + 4 {
+            // Branches are predicted not taken
+            self.redirect(execution.pc);
+        }
+        Ok(())
+    }
+
+    fn decode(&mut self) {
+        let if_id = match &self.pipeline.if_id {
+            Some(if_id) => if_id,
+            None => return,
+        };
+        if self.pipeline.id_ex.is_some() {
+            return;
+        }
+        let runner: Rc<dyn InstructionRunner> = Rc::from(decode_or_illegal(if_id.word));
+
+        // Load-use hazard: a load in MEM can't forward its value to EX within the same cycle, so
+        // a bubble is inserted
+        if let Some(ex_mem) = &self.pipeline.ex_mem {
+            if ex_mem.execution.is_none() && write_back(&ex_mem.runner.instruction_type()) {
+                let read_registers = runner.read_registers();
+                if ex_mem.runner.write_registers().iter().any(|register| {
+                    *register != RegisterType::ZERO && read_registers.contains(register)
+                }) {
+                    return;
+                }
+            }
+        }
+
+        let if_id = self.pipeline.if_id.take().unwrap();
+        self.pipeline.id_ex = Some(IdEx {
+            pc: if_id.pc,
+            runner,
+        });
+    }
+
+    // Branches are resolved once the instructions before them have gone through MEM, so a fetch is
+    // never speculative and a misaligned pc traps right away
+    fn fetch(&mut self) -> Result<(), RuntimeError> {
+        if self.redirected {
+            self.redirected = false;
+            return Ok(());
+        }
+        if self.fetching.is_none() {
+            let pc = match self.pc {
+                Some(pc) => pc,
+                None => return Ok(()),
+            };
+            let (word, cycles) = self.caches.fetch(&self.ctx, pc)?;
+            self.fetching = Some(Fetching {
+                pc,
+                word,
+                remaining: cycles,
+            });
+        }
+        let fetching = self.fetching.as_mut().unwrap();
+        fetching.remaining -= 1.;
+        if fetching.remaining > 0. || self.pipeline.if_id.is_some() {
+            return Ok(());
+        }
+
+        let fetching = self.fetching.take().unwrap();
+        self.pipeline.if_id = Some(IfId {
+            pc: fetching.pc,
+            word: fetching.word,
+        });
+        let next = fetching.pc + 4;
+        self.pc = if self.ctx.in_text(next) {
+            Some(next)
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    // Flushes the instruction fetched or being fetched after a branch
+    fn redirect(&mut self, pc: i32) {
+        self.pipeline.if_id = None;
+        self.fetching = None;
+        self.redirected = true;
+        self.pc = if self.ctx.in_text(pc) { Some(pc) } else { None };
+    }
+
+    fn run_at(
+        &mut self,
+        pc: i32,
+        runner: &dyn InstructionRunner,
+    ) -> Result<Execution, RuntimeError> {
+        self.ctx.pc = pc;
+        self.ctx.run(runner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use std::fs;
+
+    // Runs a program cycle by cycle, returns the contents of the pipeline registers at the end of
+    // each cycle
+    fn trace(
+        memory_bytes: usize,
+        memory: &[i8],
+        instructions: &str,
+    ) -> (Mvm5, Vec<PipelineRegisters>) {
+        let application = parse(instructions.to_string()).unwrap();
+        let mut vm = Mvm5::new(memory_bytes, &Config::default());
+        vm.ctx.load(&application).unwrap();
+        vm.ctx.memory[..memory.len()].copy_from_slice(memory);
+        let mut trace = vec![];
+        while let Some(registers) = vm.step().unwrap() {
+            trace.push(registers.clone());
+        }
+        (vm, trace)
+    }
+
+    // Address of the instruction in IF/ID, ID/EX, EX/MEM and MEM/WB
+    fn pcs(registers: &PipelineRegisters) -> [Option<i32>; 4] {
+        [
+            registers.if_id.as_ref().map(|if_id| if_id.pc),
+            registers.id_ex.as_ref().map(|id_ex| id_ex.pc),
+            registers.ex_mem.as_ref().map(|ex_mem| ex_mem.pc),
+            registers.mem_wb.as_ref().map(|mem_wb| mem_wb.pc),
+        ]
+    }
+
+    #[test]
+    fn test_prime_number_1109() {
+        let application =
+            parse(fs::read_to_string("res/risc/prime-number-1109.asm").unwrap()).unwrap();
+        let mut vm = Mvm5::new(5, &Config::default());
+        assert_eq!(14569., vm.run(&application).unwrap().cycles);
+        assert_eq!(4, vm.ctx.registers[RegisterType::A0]);
+        assert_eq!(1, vm.ctx.memory[4]);
+    }
+
+    #[test]
+    fn test_pipeline_registers() {
+        let (vm, trace) = trace(
+            0,
+            &[],
+            "addi t0, zero, 1
+            addi t1, zero, 2
+            addi t2, zero, 3",
+        );
+        // The first fetch misses L1I, then an instruction enters the pipeline every cycle
+        assert_eq!(57, trace.len());
+        assert_eq!([Some(0), None, None, None], pcs(&trace[50]));
+        assert_eq!([Some(4), Some(0), None, None], pcs(&trace[51]));
+        assert_eq!([Some(8), Some(4), Some(0), None], pcs(&trace[52]));
+        assert_eq!([None, Some(8), Some(4), Some(0)], pcs(&trace[53]));
+        assert_eq!(1, trace[53].mem_wb.as_ref().unwrap().execution.value);
+        assert_eq!([None, None, None, None], pcs(&trace[56]));
+        assert_eq!(3, vm.ctx.registers[RegisterType::T2]);
+        assert_eq!(12, vm.next_pc);
+    }
+
+    #[test]
+    fn test_load_use() {
+        // The first load misses L1D, the second one hits
+        let loads = "lw t0, 0(zero)
+            lw t0, 0(zero)";
+        let (vm, dependent) = trace(4, &[41], &format!("{}\naddi t1, t0, 1", loads));
+        assert_eq!(42, vm.ctx.registers[RegisterType::T1]);
+        // addi waits in IF/ID while the second load is in MEM, then a bubble goes through EX
+        let stall = dependent
+            .iter()
+            .position(|registers| pcs(registers) == [Some(12), None, Some(8), Some(4)])
+            .unwrap();
+        assert_eq!([None, Some(12), None, Some(8)], pcs(&dependent[stall + 1]));
+
+        // A single bubble compared to an independent instruction
+        let (_, independent) = trace(4, &[41], &format!("{}\naddi t1, t2, 1", loads));
+        assert_eq!(independent.len() + 1, dependent.len());
+    }
+
+    #[test]
+    fn test_step() {
+        let application = parse("lw t0, 0(zero)\naddi t1, t0, 1".to_string()).unwrap();
+        let mut vm = Mvm5::new(4, &Config::default());
+        vm.context().load(&application).unwrap();
+        vm.context().memory[0] = 41;
+        let mut bubbles = 0;
+        while let Some(registers) = vm.step().unwrap() {
+            // addi waits in IF/ID while the load is in MEM, with a bubble in ID/EX
+            if pcs(registers) == [Some(8), None, Some(4), None] {
+                bubbles += 1;
+            }
+        }
+        assert!(bubbles > 0);
+        assert_eq!(42, vm.ctx.registers[RegisterType::T1]);
+        assert_eq!(12, vm.ctx.pc);
+        assert!(vm.step().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_branch() {
+        let (vm, trace) = trace(
+            0,
+            &[],
+            "addi t0, zero, 2
+            loop:
+            addi t0, t0, -1
+            bnez t0, loop
+            addi t1, zero, 1",
+        );
+        assert_eq!(0, vm.ctx.registers[RegisterType::T0]);
+        assert_eq!(1, vm.ctx.registers[RegisterType::T1]);
+        // The taken branch flushes the instruction after it
+        let taken = trace
+            .iter()
+            .position(|registers| pcs(registers) == [None, None, Some(8), Some(4)])
+            .unwrap();
+        // The target is fetched on the next cycle and executed 2 cycles later
+        assert_eq!([Some(4), None, None, Some(8)], pcs(&trace[taken + 1]));
+        assert_eq!([Some(8), Some(4), None, None], pcs(&trace[taken + 2]));
+        assert_eq!(Some(4), pcs(&trace[taken + 3])[2]);
+    }
+
+    #[test]
+    fn test_fence_i() {
+        // The store replaces the last instruction with addi a1, zero, 2, which is only seen once
+        // fence.i invalidates L1I
+        let application = parse(
+            "auipc t0, 0
+            li t1, 0x00200593
+            sw t1, 20(t0)
+            fence.i
+            addi a0, zero, 1"
+                .to_string(),
+        )
+        .unwrap();
+        let mut vm = Mvm5::new(0, &Config::default());
+        vm.run(&application).unwrap();
+        assert_eq!(0, vm.ctx.registers[RegisterType::A0]);
+        assert_eq!(2, vm.ctx.registers[RegisterType::A1]);
+    }
+
+    #[test]
+    fn test_exit() {
+        let application = parse(
+            "addi a0, zero, 2
+            addi a7, zero, 93
+            ecall
+            addi t0, zero, 1"
+                .to_string(),
+        )
+        .unwrap();
+        let mut vm = Mvm5::new(0, &Config::default());
+        let report = vm.run(&application).unwrap();
+        assert_eq!(Some(2), report.exit_code);
+        assert_eq!(0, vm.ctx.registers[RegisterType::T0]);
+    }
+}
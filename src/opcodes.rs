@@ -1,59 +1,235 @@
 use crate::bit::*;
-use core::fmt;
+use crate::encoding::*;
+use crate::error::*;
+use crate::syscall;
+use crate::syscall::Io;
 use enum_map::{Enum, EnumMap};
-use std::collections::HashMap;
-use std::collections::HashSet;
-use std::fs;
+use std::fmt;
+use std::fmt::Debug;
 
 pub struct Application {
     pub instructions: Vec<Box<dyn InstructionRunner>>,
-    pub labels: HashMap<String, i32>,
+    // Initialized memory, copied when the program is loaded
+    pub segments: Vec<Segment>,
+    // Address of the first instruction, None if the instructions have to be encoded and placed
+    // right after the main memory when the program is loaded
+    pub text_address: Option<i32>,
+    // Initial pc, relative to the first instruction
+    pub entry: i32,
+}
+
+pub struct Segment {
+    pub address: usize,
+    pub bytes: Vec<i8>,
 }
 
 pub struct Context {
     pub registers: EnumMap<RegisterType, i32>,
-    pub read_registers: HashSet<RegisterType>,
+    // Number of executed instructions per register whose result isn't written back yet
+    pub pending_writes: EnumMap<RegisterType, u32>,
     pub memory: Vec<i8>,
     pub pc: i32,
+    // Program break managed by the brk system call
+    pub brk: i32,
+    // Set once the program has called exit
+    pub exit_code: Option<i32>,
+    pub io: Io,
+    // Addresses of the instructions [start, end), the program completes once pc goes out of it
+    pub text: (i32, i32),
+    // Number of instructions executed
+    pub instructions: u64,
 }
 
 impl Context {
     pub fn new(memory_bytes: usize) -> Self {
+        let mut registers = EnumMap::<RegisterType, i32>::new();
+        // The stack grows downward from the top of memory, aligned on 16 bytes as per the ABI
+        registers[RegisterType::SP] = (memory_bytes & !0xf) as i32;
         Context {
-            registers: EnumMap::<RegisterType, i32>::new(),
-            read_registers: HashSet::new(),
+            registers,
+            pending_writes: EnumMap::new(),
             memory: vec![0; memory_bytes],
             pc: 0,
+            brk: 0,
+            exit_code: None,
+            io: Io::host(),
+            text: (0, 0),
+            instructions: 0,
+        }
+    }
+
+    pub fn load(&mut self, application: &Application) -> Result<(), RuntimeError> {
+        for segment in &application.segments {
+            let end = segment.address + segment.bytes.len();
+            if end > self.memory.len() {
+                return Err(self.error(
+                    None,
+                    Cause::SegmentOutOfMemory {
+                        address: segment.address,
+                        end,
+                        memory: self.memory.len(),
+                    },
+                ));
+            }
+            self.memory[segment.address..end].copy_from_slice(&segment.bytes);
+            // The heap starts right after the loaded data
+            self.brk = self.brk.max(end as i32);
+        }
+
+        let text_address = match application.text_address {
+            Some(address) => address,
+            None => {
+                // Aligned on 4 bytes, as instructions can't be misaligned
+                let address = (self.memory.len() + 3) & !3;
+                self.memory.resize(address, 0);
+                for instruction in &application.instructions {
+                    let word = instruction
+                        .encode()
+                        .map_err(|e| self.error(None, Cause::Encoding(e)))?;
+                    let bytes = bytes_from_low_bits(word as i32);
+                    self.memory
+                        .extend_from_slice(&[bytes.0, bytes.1, bytes.2, bytes.3]);
+                }
+                address as i32
+            }
+        };
+        self.text = (
+            text_address,
+            text_address + 4 * application.instructions.len() as i32,
+        );
+        self.pc = text_address + application.entry;
+        Ok(())
+    }
+
+    // Runs the instruction at pc, the error carries the pc and the instruction
+    pub fn run(&mut self, runner: &dyn InstructionRunner) -> Result<Execution, RuntimeError> {
+        let execution = runner.run(self).map_err(|cause| {
+            let instruction = runner.encode().ok();
+            self.error(instruction, cause)
+        })?;
+        self.instructions += 1;
+        Ok(execution)
+    }
+
+    fn error(&self, instruction: Option<u32>, cause: Cause) -> RuntimeError {
+        RuntimeError {
+            pc: self.pc,
+            instruction,
+            cause,
         }
     }
 
+    pub fn in_text(&self, pc: i32) -> bool {
+        pc >= self.text.0 && pc < self.text.1
+    }
+
+    // Reads the instruction word at pc from the main memory
+    pub fn fetch(&self, pc: i32) -> Result<u32, Trap> {
+        self.check_fetch(pc)?;
+        let idx = pc as usize;
+        Ok(i32_from_bytes(
+            self.memory[idx],
+            self.memory[idx + 1],
+            self.memory[idx + 2],
+            self.memory[idx + 3],
+        ) as u32)
+    }
+
+    // An instruction has to be aligned on 4 bytes, and in memory
+    pub fn check_fetch(&self, pc: i32) -> Result<(), Trap> {
+        self.check_access(
+            pc,
+            4,
+            Exception::InstructionAddressMisaligned,
+            Exception::InstructionAccessFault,
+        )
+        .map_err(|trap| Trap { pc, ..trap })
+    }
+
+    // Reads size bytes from memory, the access has to be aligned on its size
+    pub fn read_memory(&self, address: i32, size: usize) -> Result<&[i8], Trap> {
+        self.check_access(
+            address,
+            size,
+            Exception::LoadAddressMisaligned,
+            Exception::LoadAccessFault,
+        )?;
+        let idx = address as usize;
+        Ok(&self.memory[idx..idx + size])
+    }
+
+    // Writes bytes to memory, the access has to be aligned on its size
+    pub fn write_memory(&mut self, address: i32, bytes: &[i8]) -> Result<(), Trap> {
+        self.check_access(
+            address,
+            bytes.len(),
+            Exception::StoreAddressMisaligned,
+            Exception::StoreAccessFault,
+        )?;
+        let idx = address as usize;
+        self.memory[idx..idx + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn check_access(
+        &self,
+        address: i32,
+        size: usize,
+        misaligned: Exception,
+        fault: Exception,
+    ) -> Result<(), Trap> {
+        let exception = if address % size as i32 != 0 {
+            misaligned
+        } else if address < 0 || address as usize + size > self.memory.len() {
+            fault
+        } else {
+            return Ok(());
+        };
+        Err(Trap {
+            exception,
+            pc: self.pc,
+            address,
+        })
+    }
+
     pub fn write(&mut self, execution: &Execution) {
         self.registers[execution.register] = execution.value;
     }
 
     pub fn add_write_registers(&mut self, registers: Vec<RegisterType>) {
         for register in registers {
-            self.read_registers.insert(register);
+            // x0 is hardwired to zero, so writing it never delays a reader
+            if register != RegisterType::ZERO {
+                self.pending_writes[register] += 1;
+            }
         }
     }
 
     pub fn delete_write_registers(&mut self, registers: &Vec<RegisterType>) {
         for register in registers {
-            self.read_registers.remove(register);
+            if register != &RegisterType::ZERO {
+                self.pending_writes[*register] -= 1;
+            }
         }
     }
 
     pub fn contain_written_registers(&mut self, registers: &Vec<RegisterType>) -> bool {
         for register in registers {
-            if self.read_registers.contains(register) {
+            if self.pending_writes[*register] > 0 {
                 return true;
             }
         }
-        return false;
+        false
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MemoryAccess {
+    pub address: i32,
+    pub store: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
 pub struct Execution {
     pub register: RegisterType,
     pub value: i32,
@@ -78,11 +254,76 @@ impl Execution {
     }
 }
 
-pub trait InstructionRunner {
-    fn run(&self, ctx: &mut Context, labels: &HashMap<String, i32>) -> Result<Execution, String>;
+// Synchronous exceptions, named after the causes of the RISC-V privileged specification
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Exception {
+    InstructionAddressMisaligned,
+    InstructionAccessFault,
+    IllegalInstruction(u32),
+    Breakpoint,
+    LoadAddressMisaligned,
+    LoadAccessFault,
+    StoreAddressMisaligned,
+    StoreAccessFault,
+}
+
+// Exception raised by the instruction at pc
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Trap {
+    pub exception: Exception,
+    pub pc: i32,
+    // Faulting address of a load or a store, pc otherwise
+    pub address: i32,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.exception {
+            Exception::InstructionAddressMisaligned => {
+                write!(f, "misaligned instruction at pc {}", self.pc)
+            }
+            Exception::InstructionAccessFault => {
+                write!(f, "instruction access fault at pc {}", self.pc)
+            }
+            Exception::IllegalInstruction(word) => {
+                write!(f, "illegal instruction {:#010x} at pc {}", word, self.pc)
+            }
+            Exception::Breakpoint => write!(f, "breakpoint at pc {}", self.pc),
+            Exception::LoadAddressMisaligned => write!(
+                f,
+                "misaligned load at address {:#x}, pc {}",
+                self.address, self.pc
+            ),
+            Exception::LoadAccessFault => write!(
+                f,
+                "load access fault at address {:#x}, pc {}",
+                self.address, self.pc
+            ),
+            Exception::StoreAddressMisaligned => write!(
+                f,
+                "misaligned store at address {:#x}, pc {}",
+                self.address, self.pc
+            ),
+            Exception::StoreAccessFault => write!(
+                f,
+                "store access fault at address {:#x}, pc {}",
+                self.address, self.pc
+            ),
+        }
+    }
+}
+
+pub trait InstructionRunner: Debug {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause>;
     fn instruction_type(&self) -> InstructionType;
     fn read_registers(&self) -> Vec<RegisterType>;
     fn write_registers(&self) -> Vec<RegisterType>;
+    // Data memory accessed by the instruction with the current registers, for loads and stores
+    fn memory_access(&self, _ctx: &Context) -> Option<MemoryAccess> {
+        None
+    }
+    // Encodes the instruction into its 32-bit machine code
+    fn encode(&self) -> Result<u32, EncodeError>;
 }
 
 #[derive(PartialEq, Debug)]
@@ -93,9 +334,12 @@ pub struct Add {
 }
 
 impl InstructionRunner for Add {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, ctx.registers[self.rs1] + ctx.registers[self.rs2]);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = register_changes(
+            self.rd,
+            ctx.registers[self.rs1].wrapping_add(ctx.registers[self.rs2]),
+        );
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -109,6 +353,17 @@ impl InstructionRunner for Add {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x0,
+            FUNCT7_BASE,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -119,9 +374,9 @@ pub struct Addi {
 }
 
 impl InstructionRunner for Addi {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, ctx.registers[self.rs] + self.imm);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = register_changes(self.rd, ctx.registers[self.rs].wrapping_add(self.imm));
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -135,6 +390,10 @@ impl InstructionRunner for Addi {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        i_type(OPCODE_OP_IMM, 0x0, self.rd, self.rs, self.imm)
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -145,9 +404,9 @@ pub struct And {
 }
 
 impl InstructionRunner for And {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
         let changes = register_changes(self.rd, ctx.registers[self.rs1] & ctx.registers[self.rs2]);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -161,6 +420,17 @@ impl InstructionRunner for And {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x7,
+            FUNCT7_BASE,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -171,9 +441,9 @@ pub struct Andi {
 }
 
 impl InstructionRunner for Andi {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
         let changes = register_changes(self.rd, ctx.registers[self.rs] & self.imm);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -187,6 +457,10 @@ impl InstructionRunner for Andi {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        i_type(OPCODE_OP_IMM, 0x7, self.rd, self.rs, self.imm)
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -196,9 +470,9 @@ pub struct Auipc {
 }
 
 impl InstructionRunner for Auipc {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, ctx.pc + (self.imm << 12));
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = register_changes(self.rd, ctx.pc.wrapping_add(self.imm << 12));
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -212,26 +486,25 @@ impl InstructionRunner for Auipc {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        u_type(OPCODE_AUIPC, self.rd, self.imm)
+    }
 }
 
 #[derive(PartialEq, Debug)]
 pub struct Beq {
     pub rs1: RegisterType,
     pub rs2: RegisterType,
-    pub label: String,
+    pub offset: i32,
 }
 
 impl InstructionRunner for Beq {
-    fn run(&self, ctx: &mut Context, labels: &HashMap<String, i32>) -> Result<Execution, String> {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
         if ctx.registers[self.rs1] == ctx.registers[self.rs2] {
-            let addr: i32;
-            match labels.get(self.label.as_str()) {
-                Some(v) => addr = *v,
-                None => return Err(format_args!("label {} does not exist", self.label).to_string()),
-            }
-            return Ok(Execution::pc(addr));
+            Ok(Execution::pc(ctx.pc.wrapping_add(self.offset)))
         } else {
-            return Ok(Execution::pc(ctx.pc + 4));
+            Ok(Execution::pc(ctx.pc + 4))
         }
     }
 
@@ -246,26 +519,25 @@ impl InstructionRunner for Beq {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        b_type(0x0, self.rs1, self.rs2, self.offset)
+    }
 }
 
 #[derive(PartialEq, Debug)]
 pub struct Bge {
     pub rs1: RegisterType,
     pub rs2: RegisterType,
-    pub label: String,
+    pub offset: i32,
 }
 
 impl InstructionRunner for Bge {
-    fn run(&self, ctx: &mut Context, labels: &HashMap<String, i32>) -> Result<Execution, String> {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
         if ctx.registers[self.rs1] >= ctx.registers[self.rs2] {
-            let addr: i32;
-            match labels.get(self.label.as_str()) {
-                Some(v) => addr = *v,
-                None => return Err(format_args!("label {} does not exist", self.label).to_string()),
-            }
-            return Ok(Execution::pc(addr));
+            Ok(Execution::pc(ctx.pc.wrapping_add(self.offset)))
         } else {
-            return Ok(Execution::pc(ctx.pc + 4));
+            Ok(Execution::pc(ctx.pc + 4))
         }
     }
 
@@ -280,26 +552,25 @@ impl InstructionRunner for Bge {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        b_type(0x5, self.rs1, self.rs2, self.offset)
+    }
 }
 
 #[derive(PartialEq, Debug)]
 pub struct Bgeu {
     pub rs1: RegisterType,
     pub rs2: RegisterType,
-    pub label: String,
+    pub offset: i32,
 }
 
 impl InstructionRunner for Bgeu {
-    fn run(&self, ctx: &mut Context, labels: &HashMap<String, i32>) -> Result<Execution, String> {
-        if ctx.registers[self.rs1] >= ctx.registers[self.rs2] {
-            let addr: i32;
-            match labels.get(self.label.as_str()) {
-                Some(v) => addr = *v,
-                None => return Err(format_args!("label {} does not exist", self.label).to_string()),
-            }
-            return Ok(Execution::pc(addr));
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        if ctx.registers[self.rs1] as u32 >= ctx.registers[self.rs2] as u32 {
+            Ok(Execution::pc(ctx.pc.wrapping_add(self.offset)))
         } else {
-            return Ok(Execution::pc(ctx.pc + 4));
+            Ok(Execution::pc(ctx.pc + 4))
         }
     }
 
@@ -314,26 +585,25 @@ impl InstructionRunner for Bgeu {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        b_type(0x7, self.rs1, self.rs2, self.offset)
+    }
 }
 
 #[derive(PartialEq, Debug)]
 pub struct Blt {
     pub rs1: RegisterType,
     pub rs2: RegisterType,
-    pub label: String,
+    pub offset: i32,
 }
 
 impl InstructionRunner for Blt {
-    fn run(&self, ctx: &mut Context, labels: &HashMap<String, i32>) -> Result<Execution, String> {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
         if ctx.registers[self.rs1] < ctx.registers[self.rs2] {
-            let addr: i32;
-            match labels.get(self.label.as_str()) {
-                Some(v) => addr = *v,
-                None => return Err(format_args!("label {} does not exist", self.label).to_string()),
-            }
-            return Ok(Execution::pc(addr));
+            Ok(Execution::pc(ctx.pc.wrapping_add(self.offset)))
         } else {
-            return Ok(Execution::pc(ctx.pc + 4));
+            Ok(Execution::pc(ctx.pc + 4))
         }
     }
 
@@ -348,26 +618,25 @@ impl InstructionRunner for Blt {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        b_type(0x4, self.rs1, self.rs2, self.offset)
+    }
 }
 
 #[derive(PartialEq, Debug)]
 pub struct Bltu {
     pub rs1: RegisterType,
     pub rs2: RegisterType,
-    pub label: String,
+    pub offset: i32,
 }
 
 impl InstructionRunner for Bltu {
-    fn run(&self, ctx: &mut Context, labels: &HashMap<String, i32>) -> Result<Execution, String> {
-        if ctx.registers[self.rs1] < ctx.registers[self.rs2] {
-            let addr: i32;
-            match labels.get(self.label.as_str()) {
-                Some(v) => addr = *v,
-                None => return Err(format_args!("label {} does not exist", self.label).to_string()),
-            }
-            return Ok(Execution::pc(addr));
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        if (ctx.registers[self.rs1] as u32) < (ctx.registers[self.rs2] as u32) {
+            Ok(Execution::pc(ctx.pc.wrapping_add(self.offset)))
         } else {
-            return Ok(Execution::pc(ctx.pc + 4));
+            Ok(Execution::pc(ctx.pc + 4))
         }
     }
 
@@ -382,26 +651,25 @@ impl InstructionRunner for Bltu {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        b_type(0x6, self.rs1, self.rs2, self.offset)
+    }
 }
 
 #[derive(PartialEq, Debug)]
 pub struct Bne {
     pub rs1: RegisterType,
     pub rs2: RegisterType,
-    pub label: String,
+    pub offset: i32,
 }
 
 impl InstructionRunner for Bne {
-    fn run(&self, ctx: &mut Context, labels: &HashMap<String, i32>) -> Result<Execution, String> {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
         if ctx.registers[self.rs1] != ctx.registers[self.rs2] {
-            let addr: i32;
-            match labels.get(self.label.as_str()) {
-                Some(v) => addr = *v,
-                None => return Err(format_args!("label {} does not exist", self.label).to_string()),
-            }
-            return Ok(Execution::pc(addr));
+            Ok(Execution::pc(ctx.pc.wrapping_add(self.offset)))
         } else {
-            return Ok(Execution::pc(ctx.pc + 4));
+            Ok(Execution::pc(ctx.pc + 4))
         }
     }
 
@@ -416,6 +684,10 @@ impl InstructionRunner for Bne {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        b_type(0x1, self.rs1, self.rs2, self.offset)
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -426,9 +698,17 @@ pub struct Div {
 }
 
 impl InstructionRunner for Div {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, ctx.registers[self.rs1] / ctx.registers[self.rs2]);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let dividend = ctx.registers[self.rs1];
+        let divisor = ctx.registers[self.rs2];
+        // Division by zero and overflow don't trap, the results are defined by the spec
+        let quotient = if divisor == 0 {
+            -1
+        } else {
+            dividend.wrapping_div(divisor)
+        };
+        let changes = register_changes(self.rd, quotient);
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -442,83 +722,103 @@ impl InstructionRunner for Div {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x4,
+            FUNCT7_MULDIV,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
 }
 
 #[derive(PartialEq, Debug)]
-pub struct Jal {
-    pub label: String,
+pub struct Divu {
     pub rd: RegisterType,
+    pub rs1: RegisterType,
+    pub rs2: RegisterType,
 }
 
-impl InstructionRunner for Jal {
-    fn run(&self, ctx: &mut Context, labels: &HashMap<String, i32>) -> Result<Execution, String> {
-        let addr: i32;
-        match labels.get(self.label.as_str()) {
-            Some(v) => addr = *v,
-            None => return Err(format_args!("label {} does not exist", self.label).to_string()),
-        }
-
-        let changes = register_changes(self.rd, ctx.pc + 4);
-        return Ok(Execution::new(changes.0, changes.1, addr));
+impl InstructionRunner for Divu {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let dividend = ctx.registers[self.rs1] as u32;
+        let divisor = ctx.registers[self.rs2] as u32;
+        let quotient = dividend.checked_div(divisor).unwrap_or(u32::MAX);
+        let changes = register_changes(self.rd, quotient as i32);
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
-        InstructionType::JAL
+        InstructionType::DIVU
     }
 
     fn read_registers(&self) -> Vec<RegisterType> {
-        vec![]
+        vec![self.rs1, self.rs2]
     }
 
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x5,
+            FUNCT7_MULDIV,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
 }
 
 #[derive(PartialEq, Debug)]
-pub struct Jalr {
-    pub rd: RegisterType,
-    pub rs: RegisterType,
-    pub imm: i32,
-}
+pub struct Ecall {}
 
-impl InstructionRunner for Jalr {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, ctx.pc + 4);
-        return Ok(Execution::new(
-            changes.0,
-            changes.1,
-            ctx.registers[self.rs] + self.imm,
-        ));
+impl InstructionRunner for Ecall {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        Ok(syscall::handle(ctx))
     }
 
     fn instruction_type(&self) -> InstructionType {
-        InstructionType::JALR
+        InstructionType::ECALL
     }
 
     fn read_registers(&self) -> Vec<RegisterType> {
-        vec![self.rs]
+        vec![
+            RegisterType::A7,
+            RegisterType::A0,
+            RegisterType::A1,
+            RegisterType::A2,
+        ]
     }
 
     fn write_registers(&self) -> Vec<RegisterType> {
-        vec![self.rd]
+        vec![RegisterType::A0]
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(ECALL)
     }
 }
 
 #[derive(PartialEq, Debug)]
-pub struct Lui {
-    pub rd: RegisterType,
-    pub imm: i32,
-}
+pub struct Ebreak {}
 
-impl InstructionRunner for Lui {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, self.imm << 12);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+impl InstructionRunner for Ebreak {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        Err(Cause::Trap(Trap {
+            exception: Exception::Breakpoint,
+            pc: ctx.pc,
+            address: ctx.pc,
+        }))
     }
 
     fn instruction_type(&self) -> InstructionType {
-        InstructionType::LUI
+        InstructionType::EBREAK
     }
 
     fn read_registers(&self) -> Vec<RegisterType> {
@@ -526,118 +826,114 @@ impl InstructionRunner for Lui {
     }
 
     fn write_registers(&self) -> Vec<RegisterType> {
-        vec![self.rd]
+        vec![]
     }
-}
 
-#[derive(PartialEq, Debug)]
-pub struct Lb {
-    pub rs2: RegisterType,
-    pub offset: i32,
-    pub rs1: RegisterType,
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(EBREAK)
+    }
 }
 
-impl InstructionRunner for Lb {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let idx = ctx.registers[self.rs1] + self.offset;
-        let n = ctx.memory[idx as usize];
+#[derive(PartialEq, Debug)]
+pub struct Fence {}
 
-        let changes = register_changes(self.rs2, n as i32);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+impl InstructionRunner for Fence {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        // Memory accesses are performed in program order by a single hart
+        Ok(Execution::pc(ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
-        InstructionType::LB
+        InstructionType::FENCE
     }
 
     fn read_registers(&self) -> Vec<RegisterType> {
-        vec![self.rs1, self.rs2]
+        vec![]
     }
 
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![]
     }
-}
 
-#[derive(PartialEq, Debug)]
-pub struct Lh {
-    pub rs2: RegisterType,
-    pub offset: i32,
-    pub rs1: RegisterType,
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(FENCE)
+    }
 }
 
-impl InstructionRunner for Lh {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let mut idx = ctx.registers[self.rs1] + self.offset;
-        let i1 = ctx.memory[idx as usize];
-        idx += 1;
-        let i2 = ctx.memory[idx as usize];
-
-        let n = i32_from_bytes(i1, i2, 0, 0);
-        let changes = register_changes(self.rs2, n);
+#[derive(PartialEq, Debug)]
+pub struct FenceI {}
 
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+impl InstructionRunner for FenceI {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        // Nothing to do at the instruction level, the VMs invalidate their instruction cache
+        Ok(Execution::pc(ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
-        InstructionType::LH
+        InstructionType::FENCEI
     }
 
     fn read_registers(&self) -> Vec<RegisterType> {
-        vec![self.rs1, self.rs2]
+        vec![]
     }
 
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(FENCE_I)
+    }
 }
 
+// A word that doesn't decode into a supported instruction, it traps once executed
 #[derive(PartialEq, Debug)]
-pub struct Lw {
-    pub rs2: RegisterType,
-    pub offset: i32,
-    pub rs1: RegisterType,
+pub struct Illegal {
+    pub word: u32,
 }
 
-impl InstructionRunner for Lw {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let mut idx = ctx.registers[self.rs1] + self.offset;
-        let i1 = ctx.memory[idx as usize];
-        idx += 1;
-        let i2 = ctx.memory[idx as usize];
-        idx += 1;
-        let i3 = ctx.memory[idx as usize];
-        idx += 1;
-        let i4 = ctx.memory[idx as usize];
-
-        let n = i32_from_bytes(i1, i2, i3, i4);
-        let changes = register_changes(self.rs2, n);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+impl InstructionRunner for Illegal {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        Err(Cause::Trap(Trap {
+            exception: Exception::IllegalInstruction(self.word),
+            pc: ctx.pc,
+            address: ctx.pc,
+        }))
     }
 
     fn instruction_type(&self) -> InstructionType {
-        InstructionType::LW
+        InstructionType::ILLEGAL
     }
 
     fn read_registers(&self) -> Vec<RegisterType> {
-        vec![self.rs1, self.rs2]
+        vec![]
     }
 
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(self.word)
+    }
 }
 
 #[derive(PartialEq, Debug)]
-pub struct Nop {}
+pub struct Jal {
+    pub offset: i32,
+    pub rd: RegisterType,
+}
 
-impl InstructionRunner for Nop {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        return Ok(Execution::pc(ctx.pc + 4));
+impl InstructionRunner for Jal {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let addr = ctx.pc.wrapping_add(self.offset);
+
+        let changes = register_changes(self.rd, ctx.pc + 4);
+        Ok(Execution::new(changes.0, changes.1, addr))
     }
 
     fn instruction_type(&self) -> InstructionType {
-        InstructionType::NOP
+        InstructionType::JAL
     }
 
     fn read_registers(&self) -> Vec<RegisterType> {
@@ -645,184 +941,703 @@ impl InstructionRunner for Nop {
     }
 
     fn write_registers(&self) -> Vec<RegisterType> {
-        vec![]
+        vec![self.rd]
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        j_type(self.rd, self.offset)
     }
 }
 
 #[derive(PartialEq, Debug)]
-pub struct Mul {
+pub struct Jalr {
     pub rd: RegisterType,
-    pub rs1: RegisterType,
-    pub rs2: RegisterType,
+    pub rs: RegisterType,
+    pub imm: i32,
 }
 
-impl InstructionRunner for Mul {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, ctx.registers[self.rs1] * ctx.registers[self.rs2]);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+impl InstructionRunner for Jalr {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = register_changes(self.rd, ctx.pc + 4);
+        // The least significant bit of the target is cleared
+        let pc = ctx.registers[self.rs].wrapping_add(self.imm) & !1;
+        Ok(Execution::new(changes.0, changes.1, pc))
     }
 
     fn instruction_type(&self) -> InstructionType {
-        InstructionType::MUL
+        InstructionType::JALR
     }
 
     fn read_registers(&self) -> Vec<RegisterType> {
-        vec![self.rs1, self.rs2]
+        vec![self.rs]
     }
 
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        i_type(OPCODE_JALR, 0x0, self.rd, self.rs, self.imm)
+    }
 }
 
 #[derive(PartialEq, Debug)]
-pub struct Or {
+pub struct Lui {
     pub rd: RegisterType,
-    pub rs1: RegisterType,
-    pub rs2: RegisterType,
+    pub imm: i32,
 }
 
-impl InstructionRunner for Or {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, ctx.registers[self.rs1] | ctx.registers[self.rs2]);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+impl InstructionRunner for Lui {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = register_changes(self.rd, self.imm << 12);
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
-        InstructionType::OR
+        InstructionType::LUI
     }
 
     fn read_registers(&self) -> Vec<RegisterType> {
-        vec![self.rs1, self.rs2]
+        vec![]
     }
 
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        u_type(OPCODE_LUI, self.rd, self.imm)
+    }
 }
 
 #[derive(PartialEq, Debug)]
-pub struct Ori {
-    pub imm: i32,
+pub struct Lb {
     pub rd: RegisterType,
-    pub rs: RegisterType,
+    pub offset: i32,
+    pub rs1: RegisterType,
 }
 
-impl InstructionRunner for Ori {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, ctx.registers[self.rs] | self.imm);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+impl InstructionRunner for Lb {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let address = ctx.registers[self.rs1].wrapping_add(self.offset);
+        let n = ctx.read_memory(address, 1)?[0];
+
+        let changes = register_changes(self.rd, n as i32);
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
-        InstructionType::ORI
+        InstructionType::LB
     }
 
     fn read_registers(&self) -> Vec<RegisterType> {
-        vec![self.rs]
+        vec![self.rs1]
     }
 
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn memory_access(&self, ctx: &Context) -> Option<MemoryAccess> {
+        Some(MemoryAccess {
+            address: ctx.registers[self.rs1].wrapping_add(self.offset),
+            store: false,
+        })
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        i_type(OPCODE_LOAD, 0x0, self.rd, self.rs1, self.offset)
+    }
 }
 
 #[derive(PartialEq, Debug)]
-pub struct Rem {
+pub struct Lbu {
     pub rd: RegisterType,
+    pub offset: i32,
     pub rs1: RegisterType,
-    pub rs2: RegisterType,
 }
 
-impl InstructionRunner for Rem {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, ctx.registers[self.rs1] % ctx.registers[self.rs2]);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+impl InstructionRunner for Lbu {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let address = ctx.registers[self.rs1].wrapping_add(self.offset);
+        let n = ctx.read_memory(address, 1)?[0];
+
+        let changes = register_changes(self.rd, n as u8 as i32);
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
-        InstructionType::REM
+        InstructionType::LBU
     }
 
     fn read_registers(&self) -> Vec<RegisterType> {
-        vec![self.rs1, self.rs2]
+        vec![self.rs1]
     }
 
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn memory_access(&self, ctx: &Context) -> Option<MemoryAccess> {
+        Some(MemoryAccess {
+            address: ctx.registers[self.rs1].wrapping_add(self.offset),
+            store: false,
+        })
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        i_type(OPCODE_LOAD, 0x4, self.rd, self.rs1, self.offset)
+    }
 }
 
 #[derive(PartialEq, Debug)]
-pub struct Sb {
-    pub rs2: RegisterType,
+pub struct Lh {
+    pub rd: RegisterType,
     pub offset: i32,
     pub rs1: RegisterType,
 }
 
-impl InstructionRunner for Sb {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let idx = ctx.registers[self.rs1] + self.offset;
-        let n = ctx.registers[self.rs2];
-        ctx.memory[idx as usize] = n as i8;
-        return Ok(Execution::pc(ctx.pc + 4));
+impl InstructionRunner for Lh {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let address = ctx.registers[self.rs1].wrapping_add(self.offset);
+        let bytes = ctx.read_memory(address, 2)?;
+
+        let n = i32_from_bytes(bytes[0], bytes[1], 0, 0) as i16;
+        let changes = register_changes(self.rd, n as i32);
+
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
-        InstructionType::SB
+        InstructionType::LH
     }
 
     fn read_registers(&self) -> Vec<RegisterType> {
-        vec![self.rs1, self.rs2]
+        vec![self.rs1]
     }
 
     fn write_registers(&self) -> Vec<RegisterType> {
-        vec![]
+        vec![self.rd]
+    }
+
+    fn memory_access(&self, ctx: &Context) -> Option<MemoryAccess> {
+        Some(MemoryAccess {
+            address: ctx.registers[self.rs1].wrapping_add(self.offset),
+            store: false,
+        })
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        i_type(OPCODE_LOAD, 0x1, self.rd, self.rs1, self.offset)
     }
 }
 
 #[derive(PartialEq, Debug)]
-pub struct Sh {
-    pub rs2: RegisterType,
+pub struct Lhu {
+    pub rd: RegisterType,
     pub offset: i32,
     pub rs1: RegisterType,
 }
 
-impl InstructionRunner for Sh {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let mut idx = ctx.registers[self.rs1] + self.offset;
-        let n = ctx.registers[self.rs2];
-        let bytes = bytes_from_low_bits(n);
-        ctx.memory[idx as usize] = bytes.0;
-        idx += 1;
-        ctx.memory[idx as usize] = bytes.1;
-        return Ok(Execution::pc(ctx.pc + 4));
+impl InstructionRunner for Lhu {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let address = ctx.registers[self.rs1].wrapping_add(self.offset);
+        let bytes = ctx.read_memory(address, 2)?;
+
+        let n = i32_from_bytes(bytes[0], bytes[1], 0, 0);
+        let changes = register_changes(self.rd, n);
+
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
-        InstructionType::SH
+        InstructionType::LHU
     }
 
     fn read_registers(&self) -> Vec<RegisterType> {
-        vec![self.rs1, self.rs2]
+        vec![self.rs1]
     }
 
     fn write_registers(&self) -> Vec<RegisterType> {
-        vec![]
+        vec![self.rd]
     }
-}
 
-#[derive(PartialEq, Debug)]
-pub struct Sll {
+    fn memory_access(&self, ctx: &Context) -> Option<MemoryAccess> {
+        Some(MemoryAccess {
+            address: ctx.registers[self.rs1].wrapping_add(self.offset),
+            store: false,
+        })
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        i_type(OPCODE_LOAD, 0x5, self.rd, self.rs1, self.offset)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Lw {
+    pub rd: RegisterType,
+    pub offset: i32,
+    pub rs1: RegisterType,
+}
+
+impl InstructionRunner for Lw {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let address = ctx.registers[self.rs1].wrapping_add(self.offset);
+        let bytes = ctx.read_memory(address, 4)?;
+
+        let n = i32_from_bytes(bytes[0], bytes[1], bytes[2], bytes[3]);
+        let changes = register_changes(self.rd, n);
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
+    }
+
+    fn instruction_type(&self) -> InstructionType {
+        InstructionType::LW
+    }
+
+    fn read_registers(&self) -> Vec<RegisterType> {
+        vec![self.rs1]
+    }
+
+    fn write_registers(&self) -> Vec<RegisterType> {
+        vec![self.rd]
+    }
+
+    fn memory_access(&self, ctx: &Context) -> Option<MemoryAccess> {
+        Some(MemoryAccess {
+            address: ctx.registers[self.rs1].wrapping_add(self.offset),
+            store: false,
+        })
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        i_type(OPCODE_LOAD, 0x2, self.rd, self.rs1, self.offset)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Nop {}
+
+impl InstructionRunner for Nop {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        Ok(Execution::pc(ctx.pc + 4))
+    }
+
+    fn instruction_type(&self) -> InstructionType {
+        InstructionType::NOP
+    }
+
+    fn read_registers(&self) -> Vec<RegisterType> {
+        vec![]
+    }
+
+    fn write_registers(&self) -> Vec<RegisterType> {
+        vec![]
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(NOP)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Mul {
+    pub rd: RegisterType,
+    pub rs1: RegisterType,
+    pub rs2: RegisterType,
+}
+
+impl InstructionRunner for Mul {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = register_changes(
+            self.rd,
+            ctx.registers[self.rs1].wrapping_mul(ctx.registers[self.rs2]),
+        );
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
+    }
+
+    fn instruction_type(&self) -> InstructionType {
+        InstructionType::MUL
+    }
+
+    fn read_registers(&self) -> Vec<RegisterType> {
+        vec![self.rs1, self.rs2]
+    }
+
+    fn write_registers(&self) -> Vec<RegisterType> {
+        vec![self.rd]
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x0,
+            FUNCT7_MULDIV,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Mulh {
+    pub rd: RegisterType,
+    pub rs1: RegisterType,
+    pub rs2: RegisterType,
+}
+
+impl InstructionRunner for Mulh {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let product = ctx.registers[self.rs1] as i64 * ctx.registers[self.rs2] as i64;
+        let changes = register_changes(self.rd, (product >> 32) as i32);
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
+    }
+
+    fn instruction_type(&self) -> InstructionType {
+        InstructionType::MULH
+    }
+
+    fn read_registers(&self) -> Vec<RegisterType> {
+        vec![self.rs1, self.rs2]
+    }
+
+    fn write_registers(&self) -> Vec<RegisterType> {
+        vec![self.rd]
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x1,
+            FUNCT7_MULDIV,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Mulhsu {
+    pub rd: RegisterType,
+    pub rs1: RegisterType,
+    pub rs2: RegisterType,
+}
+
+impl InstructionRunner for Mulhsu {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let product = ctx.registers[self.rs1] as i64 * ctx.registers[self.rs2] as u32 as i64;
+        let changes = register_changes(self.rd, (product >> 32) as i32);
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
+    }
+
+    fn instruction_type(&self) -> InstructionType {
+        InstructionType::MULHSU
+    }
+
+    fn read_registers(&self) -> Vec<RegisterType> {
+        vec![self.rs1, self.rs2]
+    }
+
+    fn write_registers(&self) -> Vec<RegisterType> {
+        vec![self.rd]
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x2,
+            FUNCT7_MULDIV,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Mulhu {
+    pub rd: RegisterType,
+    pub rs1: RegisterType,
+    pub rs2: RegisterType,
+}
+
+impl InstructionRunner for Mulhu {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let product = ctx.registers[self.rs1] as u32 as u64 * ctx.registers[self.rs2] as u32 as u64;
+        let changes = register_changes(self.rd, (product >> 32) as i32);
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
+    }
+
+    fn instruction_type(&self) -> InstructionType {
+        InstructionType::MULHU
+    }
+
+    fn read_registers(&self) -> Vec<RegisterType> {
+        vec![self.rs1, self.rs2]
+    }
+
+    fn write_registers(&self) -> Vec<RegisterType> {
+        vec![self.rd]
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x3,
+            FUNCT7_MULDIV,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Or {
+    pub rd: RegisterType,
+    pub rs1: RegisterType,
+    pub rs2: RegisterType,
+}
+
+impl InstructionRunner for Or {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = register_changes(self.rd, ctx.registers[self.rs1] | ctx.registers[self.rs2]);
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
+    }
+
+    fn instruction_type(&self) -> InstructionType {
+        InstructionType::OR
+    }
+
+    fn read_registers(&self) -> Vec<RegisterType> {
+        vec![self.rs1, self.rs2]
+    }
+
+    fn write_registers(&self) -> Vec<RegisterType> {
+        vec![self.rd]
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x6,
+            FUNCT7_BASE,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Ori {
+    pub imm: i32,
+    pub rd: RegisterType,
+    pub rs: RegisterType,
+}
+
+impl InstructionRunner for Ori {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = register_changes(self.rd, ctx.registers[self.rs] | self.imm);
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
+    }
+
+    fn instruction_type(&self) -> InstructionType {
+        InstructionType::ORI
+    }
+
+    fn read_registers(&self) -> Vec<RegisterType> {
+        vec![self.rs]
+    }
+
+    fn write_registers(&self) -> Vec<RegisterType> {
+        vec![self.rd]
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        i_type(OPCODE_OP_IMM, 0x6, self.rd, self.rs, self.imm)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Rem {
+    pub rd: RegisterType,
+    pub rs1: RegisterType,
+    pub rs2: RegisterType,
+}
+
+impl InstructionRunner for Rem {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let dividend = ctx.registers[self.rs1];
+        let divisor = ctx.registers[self.rs2];
+        let remainder = if divisor == 0 {
+            dividend
+        } else {
+            dividend.wrapping_rem(divisor)
+        };
+        let changes = register_changes(self.rd, remainder);
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
+    }
+
+    fn instruction_type(&self) -> InstructionType {
+        InstructionType::REM
+    }
+
+    fn read_registers(&self) -> Vec<RegisterType> {
+        vec![self.rs1, self.rs2]
+    }
+
+    fn write_registers(&self) -> Vec<RegisterType> {
+        vec![self.rd]
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x6,
+            FUNCT7_MULDIV,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Remu {
+    pub rd: RegisterType,
+    pub rs1: RegisterType,
+    pub rs2: RegisterType,
+}
+
+impl InstructionRunner for Remu {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let dividend = ctx.registers[self.rs1] as u32;
+        let divisor = ctx.registers[self.rs2] as u32;
+        let remainder = dividend.checked_rem(divisor).unwrap_or(dividend);
+        let changes = register_changes(self.rd, remainder as i32);
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
+    }
+
+    fn instruction_type(&self) -> InstructionType {
+        InstructionType::REMU
+    }
+
+    fn read_registers(&self) -> Vec<RegisterType> {
+        vec![self.rs1, self.rs2]
+    }
+
+    fn write_registers(&self) -> Vec<RegisterType> {
+        vec![self.rd]
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x7,
+            FUNCT7_MULDIV,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Sb {
+    pub rs2: RegisterType,
+    pub offset: i32,
+    pub rs1: RegisterType,
+}
+
+impl InstructionRunner for Sb {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let address = ctx.registers[self.rs1].wrapping_add(self.offset);
+        let n = ctx.registers[self.rs2];
+        ctx.write_memory(address, &[n as i8])?;
+        Ok(Execution::pc(ctx.pc + 4))
+    }
+
+    fn instruction_type(&self) -> InstructionType {
+        InstructionType::SB
+    }
+
+    fn read_registers(&self) -> Vec<RegisterType> {
+        vec![self.rs1, self.rs2]
+    }
+
+    fn write_registers(&self) -> Vec<RegisterType> {
+        vec![]
+    }
+
+    fn memory_access(&self, ctx: &Context) -> Option<MemoryAccess> {
+        Some(MemoryAccess {
+            address: ctx.registers[self.rs1].wrapping_add(self.offset),
+            store: true,
+        })
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        s_type(0x0, self.rs1, self.rs2, self.offset)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Sh {
+    pub rs2: RegisterType,
+    pub offset: i32,
+    pub rs1: RegisterType,
+}
+
+impl InstructionRunner for Sh {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let address = ctx.registers[self.rs1].wrapping_add(self.offset);
+        let n = ctx.registers[self.rs2];
+        let bytes = bytes_from_low_bits(n);
+        ctx.write_memory(address, &[bytes.0, bytes.1])?;
+        Ok(Execution::pc(ctx.pc + 4))
+    }
+
+    fn instruction_type(&self) -> InstructionType {
+        InstructionType::SH
+    }
+
+    fn read_registers(&self) -> Vec<RegisterType> {
+        vec![self.rs1, self.rs2]
+    }
+
+    fn write_registers(&self) -> Vec<RegisterType> {
+        vec![]
+    }
+
+    fn memory_access(&self, ctx: &Context) -> Option<MemoryAccess> {
+        Some(MemoryAccess {
+            address: ctx.registers[self.rs1].wrapping_add(self.offset),
+            store: true,
+        })
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        s_type(0x1, self.rs1, self.rs2, self.offset)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Sll {
     pub rd: RegisterType,
     pub rs1: RegisterType,
     pub rs2: RegisterType,
 }
 
 impl InstructionRunner for Sll {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, ctx.registers[self.rs1] << ctx.registers[self.rs2]);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = register_changes(
+            self.rd,
+            ctx.registers[self.rs1].wrapping_shl(ctx.registers[self.rs2] as u32),
+        );
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -836,6 +1651,17 @@ impl InstructionRunner for Sll {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x1,
+            FUNCT7_BASE,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -846,9 +1672,9 @@ pub struct Slli {
 }
 
 impl InstructionRunner for Slli {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
         let changes = register_changes(self.rd, ctx.registers[self.rs] << self.imm);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -862,6 +1688,10 @@ impl InstructionRunner for Slli {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        shift_type(0x1, FUNCT7_BASE, self.rd, self.rs, self.imm)
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -872,14 +1702,13 @@ pub struct Slt {
 }
 
 impl InstructionRunner for Slt {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes;
-        if ctx.registers[self.rs1] < ctx.registers[self.rs2] {
-            changes = register_changes(self.rd, 1);
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = if ctx.registers[self.rs1] < ctx.registers[self.rs2] {
+            register_changes(self.rd, 1)
         } else {
-            changes = register_changes(self.rd, 0);
-        }
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+            register_changes(self.rd, 0)
+        };
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -893,6 +1722,17 @@ impl InstructionRunner for Slt {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x2,
+            FUNCT7_BASE,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -903,14 +1743,13 @@ pub struct Sltu {
 }
 
 impl InstructionRunner for Sltu {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes;
-        if ctx.registers[self.rs1] < ctx.registers[self.rs2] {
-            changes = register_changes(self.rd, 1);
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = if (ctx.registers[self.rs1] as u32) < (ctx.registers[self.rs2] as u32) {
+            register_changes(self.rd, 1)
         } else {
-            changes = register_changes(self.rd, 0);
-        }
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+            register_changes(self.rd, 0)
+        };
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -924,6 +1763,17 @@ impl InstructionRunner for Sltu {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x3,
+            FUNCT7_BASE,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -934,14 +1784,13 @@ pub struct Slti {
 }
 
 impl InstructionRunner for Slti {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes;
-        if ctx.registers[self.rs] < self.imm {
-            changes = register_changes(self.rd, 1);
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = if ctx.registers[self.rs] < self.imm {
+            register_changes(self.rd, 1)
         } else {
-            changes = register_changes(self.rd, 0);
-        }
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+            register_changes(self.rd, 0)
+        };
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -955,6 +1804,45 @@ impl InstructionRunner for Slti {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        i_type(OPCODE_OP_IMM, 0x2, self.rd, self.rs, self.imm)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Sltiu {
+    pub rd: RegisterType,
+    pub rs: RegisterType,
+    pub imm: i32,
+}
+
+impl InstructionRunner for Sltiu {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        // The immediate is sign-extended, then both operands are compared as unsigned numbers
+        let changes = if (ctx.registers[self.rs] as u32) < (self.imm as u32) {
+            register_changes(self.rd, 1)
+        } else {
+            register_changes(self.rd, 0)
+        };
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
+    }
+
+    fn instruction_type(&self) -> InstructionType {
+        InstructionType::SLTIU
+    }
+
+    fn read_registers(&self) -> Vec<RegisterType> {
+        vec![self.rs]
+    }
+
+    fn write_registers(&self) -> Vec<RegisterType> {
+        vec![self.rd]
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        i_type(OPCODE_OP_IMM, 0x3, self.rd, self.rs, self.imm)
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -965,9 +1853,12 @@ pub struct Sra {
 }
 
 impl InstructionRunner for Sra {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, ctx.registers[self.rs1] >> ctx.registers[self.rs2]);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = register_changes(
+            self.rd,
+            ctx.registers[self.rs1].wrapping_shr(ctx.registers[self.rs2] as u32),
+        );
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -981,6 +1872,12 @@ impl InstructionRunner for Sra {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP, 0x5, FUNCT7_ALT, self.rd, self.rs1, self.rs2,
+        ))
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -991,9 +1888,9 @@ pub struct Srai {
 }
 
 impl InstructionRunner for Srai {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
         let changes = register_changes(self.rd, ctx.registers[self.rs] >> self.imm);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -1007,6 +1904,10 @@ impl InstructionRunner for Srai {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        shift_type(0x5, FUNCT7_ALT, self.rd, self.rs, self.imm)
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -1017,9 +1918,12 @@ pub struct Srl {
 }
 
 impl InstructionRunner for Srl {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, ctx.registers[self.rs1] >> ctx.registers[self.rs2]);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = register_changes(
+            self.rd,
+            (ctx.registers[self.rs1] as u32).wrapping_shr(ctx.registers[self.rs2] as u32) as i32,
+        );
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -1033,6 +1937,17 @@ impl InstructionRunner for Srl {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x5,
+            FUNCT7_BASE,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -1043,9 +1958,9 @@ pub struct Srli {
 }
 
 impl InstructionRunner for Srli {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, ctx.registers[self.rs] >> self.imm);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = register_changes(self.rd, (ctx.registers[self.rs] as u32 >> self.imm) as i32);
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -1059,6 +1974,10 @@ impl InstructionRunner for Srli {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        shift_type(0x5, FUNCT7_BASE, self.rd, self.rs, self.imm)
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -1069,9 +1988,12 @@ pub struct Sub {
 }
 
 impl InstructionRunner for Sub {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let changes = register_changes(self.rd, ctx.registers[self.rs1] - ctx.registers[self.rs2]);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let changes = register_changes(
+            self.rd,
+            ctx.registers[self.rs1].wrapping_sub(ctx.registers[self.rs2]),
+        );
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -1085,6 +2007,12 @@ impl InstructionRunner for Sub {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP, 0x0, FUNCT7_ALT, self.rd, self.rs1, self.rs2,
+        ))
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -1095,18 +2023,12 @@ pub struct Sw {
 }
 
 impl InstructionRunner for Sw {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
-        let mut idx = ctx.registers[self.rs1] + self.offset;
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
+        let address = ctx.registers[self.rs1].wrapping_add(self.offset);
         let n = ctx.registers[self.rs2];
         let bytes = bytes_from_low_bits(n);
-        ctx.memory[idx as usize] = bytes.0;
-        idx += 1;
-        ctx.memory[idx as usize] = bytes.1;
-        idx += 1;
-        ctx.memory[idx as usize] = bytes.2;
-        idx += 1;
-        ctx.memory[idx as usize] = bytes.3;
-        return Ok(Execution::pc(ctx.pc + 4));
+        ctx.write_memory(address, &[bytes.0, bytes.1, bytes.2, bytes.3])?;
+        Ok(Execution::pc(ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -1120,6 +2042,17 @@ impl InstructionRunner for Sw {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![]
     }
+
+    fn memory_access(&self, ctx: &Context) -> Option<MemoryAccess> {
+        Some(MemoryAccess {
+            address: ctx.registers[self.rs1].wrapping_add(self.offset),
+            store: true,
+        })
+    }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        s_type(0x2, self.rs1, self.rs2, self.offset)
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -1130,9 +2063,9 @@ pub struct Xor {
 }
 
 impl InstructionRunner for Xor {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
         let changes = register_changes(self.rd, ctx.registers[self.rs1] ^ ctx.registers[self.rs2]);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -1146,6 +2079,17 @@ impl InstructionRunner for Xor {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        Ok(r_type(
+            OPCODE_OP,
+            0x4,
+            FUNCT7_BASE,
+            self.rd,
+            self.rs1,
+            self.rs2,
+        ))
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -1156,9 +2100,9 @@ pub struct Xori {
 }
 
 impl InstructionRunner for Xori {
-    fn run(&self, ctx: &mut Context, _: &HashMap<String, i32>) -> Result<Execution, String> {
+    fn run(&self, ctx: &mut Context) -> Result<Execution, Cause> {
         let changes = register_changes(self.rd, ctx.registers[self.rs] ^ self.imm);
-        return Ok(Execution::new(changes.0, changes.1, ctx.pc + 4));
+        Ok(Execution::new(changes.0, changes.1, ctx.pc + 4))
     }
 
     fn instruction_type(&self) -> InstructionType {
@@ -1172,6 +2116,10 @@ impl InstructionRunner for Xori {
     fn write_registers(&self) -> Vec<RegisterType> {
         vec![self.rd]
     }
+
+    fn encode(&self) -> Result<u32, EncodeError> {
+        i_type(OPCODE_OP_IMM, 0x4, self.rd, self.rs, self.imm)
+    }
 }
 
 fn register_changes(register: RegisterType, value: i32) -> (RegisterType, i32) {
@@ -1179,9 +2127,11 @@ fn register_changes(register: RegisterType, value: i32) -> (RegisterType, i32) {
         return (RegisterType::ZERO, 0);
     }
 
-    return (register, value);
+    (register, value)
 }
 
+// Named after the RISC-V ABI names
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Debug, Enum, Clone, Copy, Eq, Hash)]
 pub enum RegisterType {
     ZERO,
@@ -1218,7 +2168,9 @@ pub enum RegisterType {
     T6,
 }
 
-#[derive(Clone, Debug)]
+// Named after the RISC-V mnemonics
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq, Enum)]
 pub enum InstructionType {
     ADD,
     ADDI,
@@ -1232,17 +2184,29 @@ pub enum InstructionType {
     BLTU,
     BNE,
     DIV,
+    DIVU,
+    ECALL,
+    EBREAK,
+    FENCE,
+    FENCEI,
+    ILLEGAL,
     JAL,
     JALR,
     LUI,
     LB,
+    LBU,
     LH,
+    LHU,
     LW,
     NOP,
     MUL,
+    MULH,
+    MULHSU,
+    MULHU,
     OR,
     ORI,
     REM,
+    REMU,
     SB,
     SH,
     SLL,
@@ -1250,6 +2214,7 @@ pub enum InstructionType {
     SLT,
     SLTU,
     SLTI,
+    SLTIU,
     SRA,
     SRAI,
     SRL,
@@ -1260,79 +2225,70 @@ pub enum InstructionType {
     XORI,
 }
 
-pub fn cycles_per_instruction(instruction_type: InstructionType) -> f32 {
+// Class of execution units running an instruction type
+#[derive(Copy, Clone, PartialEq, Debug, Enum)]
+pub enum FunctionalUnit {
+    Alu,
+    Multiplier,
+    Divider,
+    Memory,
+}
+
+pub fn functional_unit(instruction_type: &InstructionType) -> FunctionalUnit {
     match instruction_type {
-        InstructionType::ADD => 1.,
-        InstructionType::ADDI => 1.,
-        InstructionType::AND => 1.,
-        InstructionType::ANDI => 1.,
-        InstructionType::AUIPC => 1.,
-        InstructionType::BEQ => 1.,
-        InstructionType::BGE => 1.,
-        InstructionType::BGEU => 1.,
-        InstructionType::BLT => 1.,
-        InstructionType::BLTU => 1.,
-        InstructionType::BNE => 1.,
-        InstructionType::DIV => 1.,
-        InstructionType::JAL => 1.,
-        InstructionType::JALR => 1.,
-        InstructionType::LUI => 1.,
-        InstructionType::LB => 50.,
-        InstructionType::LH => 50.,
-        InstructionType::LW => 50.,
-        InstructionType::NOP => 1.,
-        InstructionType::MUL => 1.,
-        InstructionType::OR => 1.,
-        InstructionType::ORI => 1.,
-        InstructionType::REM => 1.,
-        InstructionType::SB => 50.,
-        InstructionType::SH => 50.,
-        InstructionType::SLL => 1.,
-        InstructionType::SLLI => 1.,
-        InstructionType::SLT => 1.,
-        InstructionType::SLTU => 1.,
-        InstructionType::SLTI => 1.,
-        InstructionType::SRA => 1.,
-        InstructionType::SRAI => 1.,
-        InstructionType::SRL => 1.,
-        InstructionType::SRLI => 1.,
-        InstructionType::SUB => 1.,
-        InstructionType::SW => 50.,
-        InstructionType::XOR => 1.,
-        InstructionType::XORI => 1.,
+        InstructionType::MUL
+        | InstructionType::MULH
+        | InstructionType::MULHSU
+        | InstructionType::MULHU => FunctionalUnit::Multiplier,
+        InstructionType::DIV
+        | InstructionType::DIVU
+        | InstructionType::REM
+        | InstructionType::REMU => FunctionalUnit::Divider,
+        InstructionType::LB
+        | InstructionType::LBU
+        | InstructionType::LH
+        | InstructionType::LHU
+        | InstructionType::LW
+        | InstructionType::SB
+        | InstructionType::SH
+        | InstructionType::SW => FunctionalUnit::Memory,
+        _ => FunctionalUnit::Alu,
     }
 }
 
 pub fn write_back(instruction_type: &InstructionType) -> bool {
-    match instruction_type {
-        InstructionType::SB | InstructionType::SW | InstructionType::SH => false,
-        _ => true,
-    }
+    !matches!(
+        instruction_type,
+        InstructionType::SB | InstructionType::SW | InstructionType::SH
+    )
 }
 
 pub fn jump(instruction_type: &InstructionType) -> bool {
-    match instruction_type {
-        InstructionType::JAL | InstructionType::JALR => true,
-        _ => false,
-    }
+    matches!(
+        instruction_type,
+        InstructionType::JAL | InstructionType::JALR
+    )
 }
 
 pub fn conditional_branching(instruction_type: &InstructionType) -> bool {
-    match instruction_type {
+    matches!(
+        instruction_type,
         InstructionType::BEQ
-        | InstructionType::BNE
-        | InstructionType::BLT
-        | InstructionType::BGE
-        | InstructionType::BGEU => true,
-        _ => false,
-    }
+            | InstructionType::BNE
+            | InstructionType::BLT
+            | InstructionType::BGE
+            | InstructionType::BGEU
+            | InstructionType::BLTU
+    )
 }
 
+#[cfg(test)]
 struct Runner {
     ctx: Context,
     application: Application,
 }
 
+#[cfg(test)]
 impl Runner {
     fn new(application: Application, memory_bytes: usize) -> Self {
         Runner {
@@ -1341,14 +2297,15 @@ impl Runner {
         }
     }
 
-    fn run(&mut self) -> Result<(), String> {
-        while self.ctx.pc / 4 < self.application.instructions.len() as i32 {
-            let runner = &self.application.instructions[(self.ctx.pc / 4) as usize];
-            let execution = runner.run(&mut self.ctx, &self.application.labels)?;
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        self.ctx.load(&self.application)?;
+        while self.ctx.exit_code.is_none() && self.ctx.in_text(self.ctx.pc) {
+            let runner = decode_or_illegal(self.ctx.fetch(self.ctx.pc)?);
+            let execution = self.ctx.run(runner.as_ref())?;
             self.ctx.write(&execution);
             self.ctx.pc = execution.pc;
         }
-        return Ok(());
+        Ok(())
     }
 }
 
@@ -1356,7 +2313,8 @@ impl Runner {
 mod tests {
     use super::*;
     use crate::parser::parse;
-    use std::borrow::Borrow;
+    use std::collections::HashMap;
+    use std::fs;
 
     macro_rules! map(
     { $($key:expr => $value:expr),+ } => {
@@ -1403,8 +2361,7 @@ mod tests {
             map! {0 => 9},
             fs::read_to_string("res/risc/prime-number.asm")
                 .unwrap()
-                .as_str()
-                .borrow(),
+                .as_str(),
             map! {RegisterType::A0 => 4},
             map! {4=>0},
         );
@@ -1415,8 +2372,7 @@ mod tests {
             map! {0 => 13},
             fs::read_to_string("res/risc/prime-number.asm")
                 .unwrap()
-                .as_str()
-                .borrow(),
+                .as_str(),
             map! {RegisterType::A0 => 4},
             map! {4=>1},
         );
@@ -1574,6 +2530,19 @@ mod tests {
             map! {RegisterType::T0 => 2, RegisterType::T1 => 1},
             HashMap::new(),
         );
+
+        // Unsigned comparison: -1 is the greatest value
+        assert(
+            map! {RegisterType::T0 => -1, RegisterType::T1 => 10},
+            0,
+            HashMap::new(),
+            "bgeu t0, t1, foo
+            addi t0, zero, 2
+            foo:
+            addi t1, zero, 1",
+            map! {RegisterType::T0 => -1, RegisterType::T1 => 1},
+            HashMap::new(),
+        );
     }
 
     #[test]
@@ -1628,6 +2597,18 @@ mod tests {
             map! {RegisterType::T0 => 0, RegisterType::T1 => 1},
             HashMap::new(),
         );
+
+        assert(
+            map! {RegisterType::T0 => -1, RegisterType::T1 => 10},
+            0,
+            HashMap::new(),
+            "bltu t0, t1, foo
+            addi t0, zero, 2
+            foo:
+            addi t1, zero, 1",
+            map! {RegisterType::T0 => 2, RegisterType::T1 => 1},
+            HashMap::new(),
+        );
     }
 
     #[test]
@@ -1676,6 +2657,128 @@ mod tests {
             map! {RegisterType::T0 => 1},
             HashMap::new(),
         );
+
+        assert(
+            map! {RegisterType::T1 => -7, RegisterType::T2 => 2},
+            0,
+            HashMap::new(),
+            "div t0, t1, t2",
+            map! {RegisterType::T0 => -3},
+            HashMap::new(),
+        );
+
+        assert(
+            map! {RegisterType::T1 => 4},
+            0,
+            HashMap::new(),
+            "div t0, t1, t2",
+            map! {RegisterType::T0 => -1},
+            HashMap::new(),
+        );
+
+        assert(
+            map! {RegisterType::T1 => i32::MIN, RegisterType::T2 => -1},
+            0,
+            HashMap::new(),
+            "div t0, t1, t2",
+            map! {RegisterType::T0 => i32::MIN},
+            HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn test_divu() {
+        assert(
+            map! {RegisterType::T1 => 7, RegisterType::T2 => 2},
+            0,
+            HashMap::new(),
+            "divu t0, t1, t2",
+            map! {RegisterType::T0 => 3},
+            HashMap::new(),
+        );
+
+        assert(
+            map! {RegisterType::T1 => -2, RegisterType::T2 => 2},
+            0,
+            HashMap::new(),
+            "divu t0, t1, t2",
+            map! {RegisterType::T0 => i32::MAX},
+            HashMap::new(),
+        );
+
+        assert(
+            map! {RegisterType::T1 => 4},
+            0,
+            HashMap::new(),
+            "divu t0, t1, t2",
+            map! {RegisterType::T0 => -1},
+            HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn test_ebreak() {
+        let application = parse("addi t0, zero, 1\nebreak".to_string()).unwrap();
+        let mut runner = Runner::new(application, 0);
+        assert_eq!(
+            Err(RuntimeError {
+                pc: 4,
+                instruction: Some(EBREAK),
+                cause: Cause::Trap(Trap {
+                    exception: Exception::Breakpoint,
+                    pc: 4,
+                    address: 4,
+                }),
+            }),
+            runner.run()
+        );
+        assert_eq!(runner.ctx.registers[RegisterType::T0], 1);
+        assert_eq!(runner.ctx.pc, 4);
+    }
+
+    #[test]
+    fn test_fence() {
+        assert(
+            HashMap::new(),
+            0,
+            HashMap::new(),
+            "addi t0, zero, 1
+            fence
+            fence iorw, iorw
+            addi t1, t0, 1",
+            map! {RegisterType::T1 => 2},
+            HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn test_fence_i() {
+        // Replaces the last instruction with addi a1, zero, 2
+        assert(
+            HashMap::new(),
+            8,
+            HashMap::new(),
+            "auipc t0, 0
+            li t1, 0x00200593
+            sw t1, 20(t0)
+            fence.i
+            addi a0, zero, 1",
+            map! {RegisterType::T0 => 8, RegisterType::A0 => 0, RegisterType::A1 => 2},
+            HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn test_load_text_after_memory() {
+        let application = parse("addi t0, zero, 1\nnop".to_string()).unwrap();
+        let mut ctx = Context::new(6);
+        ctx.load(&application).unwrap();
+        // Aligned on 4 bytes
+        assert_eq!((8, 16), ctx.text);
+        assert_eq!(8, ctx.pc);
+        assert_eq!(NOP, ctx.fetch(12).unwrap());
+        assert!(ctx.in_text(12));
+        assert!(!ctx.in_text(16));
     }
 
     #[test]
@@ -1707,6 +2810,19 @@ mod tests {
             map! {RegisterType::T0 => 8, RegisterType::T1 => 2,RegisterType::T2 => 0},
             HashMap::new(),
         );
+
+        // The least significant bit of the target is cleared
+        assert(
+            HashMap::new(),
+            0,
+            HashMap::new(),
+            "addi t1, zero, 5
+            jalr t0, t1, 8
+            addi t2, zero, 2
+            addi t1, zero, 2",
+            map! {RegisterType::T0 => 8, RegisterType::T1 => 2,RegisterType::T2 => 0},
+            HashMap::new(),
+        );
     }
 
     #[test]
@@ -1749,6 +2865,78 @@ mod tests {
             map! {RegisterType::T0 => 8},
             HashMap::new(),
         );
+
+        assert(
+            map! {RegisterType::T1 => i32::MAX, RegisterType::T2 => 2},
+            0,
+            HashMap::new(),
+            "mul t0, t1, t2",
+            map! {RegisterType::T0 => -2},
+            HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn test_mulh() {
+        assert(
+            map! {RegisterType::T1 => i32::MAX, RegisterType::T2 => 4},
+            0,
+            HashMap::new(),
+            "mulh t0, t1, t2",
+            map! {RegisterType::T0 => 1},
+            HashMap::new(),
+        );
+
+        assert(
+            map! {RegisterType::T1 => -1, RegisterType::T2 => 1},
+            0,
+            HashMap::new(),
+            "mulh t0, t1, t2",
+            map! {RegisterType::T0 => -1},
+            HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn test_mulhsu() {
+        assert(
+            map! {RegisterType::T1 => -1, RegisterType::T2 => -1},
+            0,
+            HashMap::new(),
+            "mulhsu t0, t1, t2",
+            map! {RegisterType::T0 => -1},
+            HashMap::new(),
+        );
+
+        assert(
+            map! {RegisterType::T1 => 2, RegisterType::T2 => -1},
+            0,
+            HashMap::new(),
+            "mulhsu t0, t1, t2",
+            map! {RegisterType::T0 => 1},
+            HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn test_mulhu() {
+        assert(
+            map! {RegisterType::T1 => -1, RegisterType::T2 => -1},
+            0,
+            HashMap::new(),
+            "mulhu t0, t1, t2",
+            map! {RegisterType::T0 => -2},
+            HashMap::new(),
+        );
+
+        assert(
+            map! {RegisterType::T1 => 2, RegisterType::T2 => 3},
+            0,
+            HashMap::new(),
+            "mulhu t0, t1, t2",
+            map! {RegisterType::T0 => 0},
+            HashMap::new(),
+        );
     }
 
     #[test]
@@ -1794,6 +2982,63 @@ mod tests {
             map! {RegisterType::T0 => 1},
             HashMap::new(),
         );
+
+        assert(
+            map! {RegisterType::T1 => -7, RegisterType::T2 => 2},
+            0,
+            HashMap::new(),
+            "rem t0, t1, t2",
+            map! {RegisterType::T0 => -1},
+            HashMap::new(),
+        );
+
+        assert(
+            map! {RegisterType::T1 => 4},
+            0,
+            HashMap::new(),
+            "rem t0, t1, t2",
+            map! {RegisterType::T0 => 4},
+            HashMap::new(),
+        );
+
+        assert(
+            map! {RegisterType::T1 => i32::MIN, RegisterType::T2 => -1},
+            0,
+            HashMap::new(),
+            "rem t0, t1, t2",
+            map! {RegisterType::T0 => 0},
+            HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn test_remu() {
+        assert(
+            map! {RegisterType::T1 => 7, RegisterType::T2 => 2},
+            0,
+            HashMap::new(),
+            "remu t0, t1, t2",
+            map! {RegisterType::T0 => 1},
+            HashMap::new(),
+        );
+
+        assert(
+            map! {RegisterType::T1 => -1, RegisterType::T2 => 16},
+            0,
+            HashMap::new(),
+            "remu t0, t1, t2",
+            map! {RegisterType::T0 => 15},
+            HashMap::new(),
+        );
+
+        assert(
+            map! {RegisterType::T1 => 4},
+            0,
+            HashMap::new(),
+            "remu t0, t1, t2",
+            map! {RegisterType::T0 => 4},
+            HashMap::new(),
+        );
     }
 
     #[test]
@@ -1806,6 +3051,16 @@ mod tests {
             map! {RegisterType::T0 => 4},
             HashMap::new(),
         );
+
+        // Only the 5 low bits of the shift amount are used
+        assert(
+            map! {RegisterType::T1 => 1,RegisterType::T2 => 33},
+            0,
+            HashMap::new(),
+            "sll t0, t1, t2",
+            map! {RegisterType::T0 => 2},
+            HashMap::new(),
+        );
     }
 
     #[test]
@@ -1854,6 +3109,36 @@ mod tests {
             map! {RegisterType::T0 => 1},
             HashMap::new(),
         );
+
+        assert(
+            map! {RegisterType::T1 => -1,RegisterType::T2 => 3},
+            0,
+            HashMap::new(),
+            "sltu t0, t1, t2",
+            map! {RegisterType::T0 => 0},
+            HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn test_sltiu() {
+        assert(
+            map! {RegisterType::T1 => 2},
+            0,
+            HashMap::new(),
+            "sltiu t0, t1, -1",
+            map! {RegisterType::T0 => 1},
+            HashMap::new(),
+        );
+
+        assert(
+            map! {RegisterType::T1 => -1},
+            0,
+            HashMap::new(),
+            "sltiu t0, t1, 5",
+            map! {RegisterType::T0 => 0},
+            HashMap::new(),
+        );
     }
 
     #[test]
@@ -1890,6 +3175,16 @@ mod tests {
             map! {RegisterType::T0 => 1},
             HashMap::new(),
         );
+
+        // Logical shift: the sign bit isn't extended
+        assert(
+            map! {RegisterType::T1 => -4,RegisterType::T2 => 30},
+            0,
+            HashMap::new(),
+            "srl t0, t1, t2",
+            map! {RegisterType::T0 => 3},
+            HashMap::new(),
+        );
     }
 
     #[test]
@@ -1902,6 +3197,15 @@ mod tests {
             map! {RegisterType::T0 => 1},
             HashMap::new(),
         );
+
+        assert(
+            map! {RegisterType::T1 => -4},
+            0,
+            HashMap::new(),
+            "srli t0, t1, 30",
+            map! {RegisterType::T0 => 3},
+            HashMap::new(),
+        );
     }
 
     #[test]
@@ -1916,6 +3220,163 @@ mod tests {
         );
     }
 
+    // Sample of each instruction type with its source and destination registers, following the
+    // spec formats: the samples use a0 as rd, a1 as rs1 and a2 as rs2
+    fn operands(
+        instruction_type: &InstructionType,
+    ) -> (&'static str, Vec<RegisterType>, Vec<RegisterType>) {
+        use RegisterType::*;
+        let (source, format) = match instruction_type {
+            InstructionType::ADD => ("add a0, a1, a2", 'R'),
+            InstructionType::ADDI => ("addi a0, a1, 1", 'I'),
+            InstructionType::AND => ("and a0, a1, a2", 'R'),
+            InstructionType::ANDI => ("andi a0, a1, 1", 'I'),
+            InstructionType::AUIPC => ("auipc a0, 1", 'U'),
+            InstructionType::BEQ => ("beq a1, a2, foo\nfoo:", 'B'),
+            InstructionType::BGE => ("bge a1, a2, foo\nfoo:", 'B'),
+            InstructionType::BGEU => ("bgeu a1, a2, foo\nfoo:", 'B'),
+            InstructionType::BLT => ("blt a1, a2, foo\nfoo:", 'B'),
+            InstructionType::BLTU => ("bltu a1, a2, foo\nfoo:", 'B'),
+            InstructionType::BNE => ("bne a1, a2, foo\nfoo:", 'B'),
+            InstructionType::DIV => ("div a0, a1, a2", 'R'),
+            InstructionType::DIVU => ("divu a0, a1, a2", 'R'),
+            // The system call number is in a7, the arguments in a0-a2 and the result in a0
+            InstructionType::ECALL => {
+                return ("ecall", vec![A7, A0, A1, A2], vec![A0]);
+            }
+            InstructionType::EBREAK => ("ebreak", '-'),
+            InstructionType::FENCE => ("fence", '-'),
+            InstructionType::FENCEI => ("fence.i", '-'),
+            InstructionType::ILLEGAL => ("", '-'),
+            InstructionType::JAL => ("jal a0, foo\nfoo:", 'J'),
+            InstructionType::JALR => ("jalr a0, a1, 0", 'I'),
+            InstructionType::LUI => ("lui a0, 1", 'U'),
+            InstructionType::LB => ("lb a0, 0(a1)", 'I'),
+            InstructionType::LBU => ("lbu a0, 0(a1)", 'I'),
+            InstructionType::LH => ("lh a0, 0(a1)", 'I'),
+            InstructionType::LHU => ("lhu a0, 0(a1)", 'I'),
+            InstructionType::LW => ("lw a0, 0(a1)", 'I'),
+            InstructionType::NOP => ("nop", '-'),
+            InstructionType::MUL => ("mul a0, a1, a2", 'R'),
+            InstructionType::MULH => ("mulh a0, a1, a2", 'R'),
+            InstructionType::MULHSU => ("mulhsu a0, a1, a2", 'R'),
+            InstructionType::MULHU => ("mulhu a0, a1, a2", 'R'),
+            InstructionType::OR => ("or a0, a1, a2", 'R'),
+            InstructionType::ORI => ("ori a0, a1, 1", 'I'),
+            InstructionType::REM => ("rem a0, a1, a2", 'R'),
+            InstructionType::REMU => ("remu a0, a1, a2", 'R'),
+            InstructionType::SB => ("sb a2, 0(a1)", 'S'),
+            InstructionType::SH => ("sh a2, 0(a1)", 'S'),
+            InstructionType::SLL => ("sll a0, a1, a2", 'R'),
+            InstructionType::SLLI => ("slli a0, a1, 1", 'I'),
+            InstructionType::SLT => ("slt a0, a1, a2", 'R'),
+            InstructionType::SLTU => ("sltu a0, a1, a2", 'R'),
+            InstructionType::SLTI => ("slti a0, a1, 1", 'I'),
+            InstructionType::SLTIU => ("sltiu a0, a1, 1", 'I'),
+            InstructionType::SRA => ("sra a0, a1, a2", 'R'),
+            InstructionType::SRAI => ("srai a0, a1, 1", 'I'),
+            InstructionType::SRL => ("srl a0, a1, a2", 'R'),
+            InstructionType::SRLI => ("srli a0, a1, 1", 'I'),
+            InstructionType::SUB => ("sub a0, a1, a2", 'R'),
+            InstructionType::SW => ("sw a2, 0(a1)", 'S'),
+            InstructionType::XOR => ("xor a0, a1, a2", 'R'),
+            InstructionType::XORI => ("xori a0, a1, 1", 'I'),
+        };
+        let (read, write) = match format {
+            'R' => (vec![A1, A2], vec![A0]),
+            'I' => (vec![A1], vec![A0]),
+            'S' | 'B' => (vec![A1, A2], vec![]),
+            'U' | 'J' => (vec![], vec![A0]),
+            _ => (vec![], vec![]),
+        };
+        (source, read, write)
+    }
+
+    #[test]
+    fn test_operands() {
+        for i in 0..<InstructionType as Enum<()>>::POSSIBLE_VALUES {
+            let instruction_type = <InstructionType as Enum<()>>::from_usize(i);
+            let (source, read, write) = operands(&instruction_type);
+            let runner: Box<dyn InstructionRunner> = match instruction_type {
+                InstructionType::ILLEGAL => Box::new(Illegal { word: 0 }),
+                _ => parse(source.to_string()).unwrap().instructions.remove(0),
+            };
+            assert_eq!(instruction_type, runner.instruction_type());
+            assert_eq!(read, runner.read_registers(), "{:?}", instruction_type);
+            assert_eq!(write, runner.write_registers(), "{:?}", instruction_type);
+        }
+    }
+
+    #[test]
+    fn test_memory_access() {
+        let mut ctx = Context::new(0);
+        ctx.registers[RegisterType::A1] = 12;
+        for i in 0..<InstructionType as Enum<()>>::POSSIBLE_VALUES {
+            let instruction_type = <InstructionType as Enum<()>>::from_usize(i);
+            let (source, _, _) = operands(&instruction_type);
+            let runner: Box<dyn InstructionRunner> = match instruction_type {
+                InstructionType::ILLEGAL => Box::new(Illegal { word: 0 }),
+                _ => parse(source.to_string()).unwrap().instructions.remove(0),
+            };
+            let access = runner.memory_access(&ctx);
+            if functional_unit(&instruction_type) == FunctionalUnit::Memory {
+                assert_eq!(12, access.unwrap().address, "{:?}", instruction_type);
+            } else {
+                assert_eq!(None, access, "{:?}", instruction_type);
+            }
+        }
+        let runner = parse("sw t1, -4(a1)".to_string())
+            .unwrap()
+            .instructions
+            .remove(0);
+        assert_eq!(
+            Some(MemoryAccess {
+                address: 8,
+                store: true,
+            }),
+            runner.memory_access(&ctx)
+        );
+    }
+
+    #[test]
+    fn test_memory_faults() {
+        let cases = vec![
+            ("lw t0, 2(zero)", Exception::LoadAddressMisaligned, 2),
+            ("lh t0, 1(zero)", Exception::LoadAddressMisaligned, 1),
+            ("lw t0, -4(zero)", Exception::LoadAccessFault, -4),
+            ("lbu t0, 1024(zero)", Exception::LoadAccessFault, 1024),
+            ("sh t0, 3(zero)", Exception::StoreAddressMisaligned, 3),
+            ("sw t0, 1024(zero)", Exception::StoreAccessFault, 1024),
+            ("sb t0, -1(zero)", Exception::StoreAccessFault, -1),
+        ];
+        for (instruction, exception, address) in cases {
+            let application = parse(format!("nop\n{}", instruction)).unwrap();
+            let mut runner = Runner::new(application, 16);
+            let error = runner.run().err().unwrap();
+            assert_eq!(20, error.pc);
+            assert_eq!(
+                Cause::Trap(Trap {
+                    exception,
+                    pc: 20,
+                    address,
+                }),
+                error.cause,
+                "{}",
+                instruction
+            );
+        }
+
+        // The instructions are in memory too
+        assert(
+            HashMap::new(),
+            16,
+            HashMap::new(),
+            "lw t0, 16(zero)",
+            map! {RegisterType::T0 => 0x01002283},
+            HashMap::new(),
+        );
+    }
+
     #[test]
     fn test_sb_lb() {
         assert(
@@ -1962,6 +3423,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sb_lbu() {
+        assert(
+            map! {RegisterType::T0 => -1, RegisterType::T1 => 2},
+            8,
+            HashMap::new(),
+            "sb t0, 2, t1
+            lbu t2, 2, t1
+            lb t3, 2, t1",
+            map! {RegisterType::T2 => 255, RegisterType::T3 => -1},
+            map! { 4=>-1},
+        );
+    }
+
+    #[test]
+    fn test_sh_lhu() {
+        assert(
+            map! {RegisterType::T0 => -2, RegisterType::T1 => 2},
+            8,
+            HashMap::new(),
+            "sh t0, 2, t1
+            lhu t2, 2, t1
+            lh t3, 2, t1",
+            map! {RegisterType::T2 => 65534, RegisterType::T3 => -2},
+            map! { 4=>-2, 5=>-1},
+        );
+    }
+
     #[test]
     fn test_sw_lw() {
         assert(
@@ -2009,6 +3498,158 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_li() {
+        assert(
+            HashMap::new(),
+            0,
+            HashMap::new(),
+            "li t0, -5
+            li t1, 305419896
+            li t2, 2147483647
+            li t3, -2147483648
+            li t4, 4096",
+            map! {RegisterType::T0 => -5, RegisterType::T1 => 305419896, RegisterType::T2 => i32::MAX, RegisterType::T3 => i32::MIN, RegisterType::T4 => 4096},
+            HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn test_la() {
+        assert(
+            HashMap::new(),
+            0,
+            HashMap::new(),
+            "nop
+            la t0, foo
+            la t1, start
+            foo:
+            start:",
+            map! {RegisterType::T0 => 20, RegisterType::T1 => 20},
+            HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn test_data_section() {
+        assert(
+            HashMap::new(),
+            16,
+            HashMap::new(),
+            ".data
+            n: .word 40
+            .byte 1, 2
+            .align 2
+            result: .space 4
+            .text
+            la t0, n
+            lw t1, 0, t0
+            addi t1, t1, 2
+            la t2, result
+            sw t1, 0, t2
+            lb t3, 4, t0",
+            map! {RegisterType::T1 => 42, RegisterType::T2 => 8, RegisterType::T3 => 1},
+            map! {8 => 42, 5 => 2},
+        );
+    }
+
+    #[test]
+    fn test_gnu_syntax() {
+        assert(
+            map! {RegisterType::SP => 32, RegisterType::S0 => 7},
+            32,
+            HashMap::new(),
+            "\t.file\t\"square.c\"
+            \t.option nopic
+            \t.section\t.rodata
+            \t.align\t2
+            .LC0:
+            \t.word\t0x10
+            \t.text
+            \t.align\t2
+            \t.globl\tmain
+            \t.type\tmain, @function
+            main:
+            \taddi\tsp,sp,-16
+            \tsw\tfp,12(sp)
+            \taddi\tfp,sp,16
+            \tlui\ta5,%hi(.LC0)
+            \tlw\ta5,%lo(.LC0)(a5)
+            \tmul\ta0,a5,a5
+            \tli\tx5,'A'
+            \tlw\tfp,12(sp)
+            \taddi\tsp,sp,16
+            \t.size\tmain, .-main
+            \t.ident\t\"GCC: (GNU) 12.2.0\"",
+            map! {
+                RegisterType::A0 => 256,
+                RegisterType::T0 => 65,
+                RegisterType::SP => 32,
+                RegisterType::S0 => 7
+            },
+            map! {28 => 7},
+        );
+    }
+
+    #[test]
+    fn test_call_ret() {
+        assert(
+            HashMap::new(),
+            0,
+            HashMap::new(),
+            "li a0, 1
+            call double
+            call double
+            j end
+            double:
+            add a0, a0, a0
+            ret
+            end:
+            mv t0, a0",
+            map! {RegisterType::T0 => 4, RegisterType::RA => 20},
+            HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn test_pseudo_branches() {
+        assert(
+            map! {RegisterType::T1 => 2, RegisterType::T2 => 1},
+            0,
+            HashMap::new(),
+            "beqz t0, a
+            li a0, 1
+            a:
+            bnez t0, b
+            li a1, 1
+            b:
+            bgt t1, t2, c
+            li a2, 1
+            c:
+            ble t1, t2, d
+            li a3, 1
+            d:",
+            map! {RegisterType::A0 => 0, RegisterType::A1 => 1, RegisterType::A2 => 0, RegisterType::A3 => 1},
+            HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn test_pseudo_arithmetic() {
+        assert(
+            map! {RegisterType::T0 => 5},
+            0,
+            HashMap::new(),
+            "mv t1, t0
+            not t2, t0
+            neg t3, t0
+            seqz t4, t0
+            seqz t5, zero",
+            map! {RegisterType::T1 => 5, RegisterType::T2 => -6, RegisterType::T3 => -5, RegisterType::T4 => 0, RegisterType::T5 => 1},
+            HashMap::new(),
+        );
+    }
+
     #[test]
     fn test_zero() {
         assert(
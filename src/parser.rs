@@ -1,434 +1,1396 @@
+use crate::bit::bytes_from_low_bits;
+use crate::error::{ParseError, ParseErrorKind};
 use crate::opcodes::*;
 use std::collections::HashMap;
 
-pub fn parse(s: String) -> Result<Application, String> {
-    let mut instructions: Vec<Box<dyn InstructionRunner>> = vec![];
-    let mut labels = HashMap::new();
+// Address where the data section is loaded
+pub const DATA_ADDRESS: usize = 0;
+
+struct Statement<'a> {
+    // Line number, starting at 0, and source line of the statement
+    number: usize,
+    source: &'a str,
+    line: &'a str,
+    instruction_type: String,
+    elements: Vec<&'a str>,
+    remaining_line: &'a str,
+    pc: i32,
+}
+
+#[derive(PartialEq)]
+enum Section {
+    Text,
+    Data,
+}
+
+#[derive(Default)]
+struct Symbols {
+    // Text labels with their pc
+    labels: HashMap<String, i32>,
+    // Data labels with their address
+    data_labels: HashMap<String, i32>,
+    // Constants defined with .equ
+    constants: HashMap<String, i32>,
+}
+
+impl Symbols {
+    fn immediate(&self, s: &str) -> Result<i32, ParseErrorKind> {
+        let s = s.trim();
+        // %hi and %lo are the upper 20 and lower 12 bits of an address, as used by lui and addi
+        if let Some(symbol) = s.strip_prefix("%hi(").and_then(|s| s.strip_suffix(')')) {
+            return Ok(split_immediate(self.address(symbol)?).0);
+        }
+        if let Some(symbol) = s.strip_prefix("%lo(").and_then(|s| s.strip_suffix(')')) {
+            return Ok(split_immediate(self.address(symbol)?).1);
+        }
+        match self.constants.get(s) {
+            Some(v) => Ok(*v),
+            None => i32(s.to_string()),
+        }
+    }
+
+    fn address(&self, label: &str) -> Result<i32, ParseErrorKind> {
+        let label = label.trim();
+        match self
+            .data_labels
+            .get(label)
+            .or_else(|| self.labels.get(label))
+        {
+            Some(v) => Ok(*v),
+            None => Err(ParseErrorKind::UndefinedLabel(label.to_string())),
+        }
+    }
+
+    // Offset from pc to a text label, as used by the branches and jumps
+    fn offset(&self, label: &str, pc: i32) -> Result<i32, ParseErrorKind> {
+        let label = label.trim();
+        match self.labels.get(label) {
+            Some(v) => Ok(*v - pc),
+            None => Err(ParseErrorKind::UndefinedLabel(label.to_string())),
+        }
+    }
+
+    // Either an immediate or the address of a data label
+    fn value(&self, s: &str) -> Result<i32, ParseErrorKind> {
+        match self.data_labels.get(s.trim()) {
+            Some(v) => Ok(*v),
+            None => self.immediate(s),
+        }
+    }
+}
+
+impl Statement<'_> {
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        located(
+            kind,
+            self.number,
+            self.source,
+            self.line,
+            self.remaining_line,
+        )
+    }
+}
+
+pub fn parse(s: String) -> Result<Application, ParseError> {
+    let mut statements: Vec<Statement> = vec![];
+    let mut symbols = Symbols::default();
+    let mut data: Vec<i8> = vec![];
+    let mut section = Section::Text;
     let mut pc: i32 = 0;
 
-    for line in s.split("\n") {
-        let trimmed_line = line.trim();
-        if trimmed_line.len() == 0 || trimmed_line.chars().next().unwrap() == '#' {
+    // First pass: compute the address of every label, as a pseudo-instruction may be expanded
+    // into several instructions
+    for (number, line) in s.split('\n').enumerate() {
+        let mut trimmed_line = strip_comment(line).trim();
+        if trimmed_line.is_empty() {
             continue;
         }
 
-        let first_whitespace = trimmed_line.find(' ');
-        let last_character = trimmed_line.chars().last().unwrap();
-        if first_whitespace.is_none() && last_character != ':' {
-            return Err(format_args!("invalid line: {}", trimmed_line).to_string());
-        } else if first_whitespace.is_none() && last_character == ':' {
-            labels.insert(trimmed_line[..trimmed_line.len() - 1].to_string(), pc);
+        // A label can be followed by an instruction or a directive on the same line
+        if let Some(i) = label_end(trimmed_line) {
+            let label = trimmed_line[..i].to_string();
+            match section {
+                Section::Text => symbols.labels.insert(label, pc),
+                Section::Data => symbols
+                    .data_labels
+                    .insert(label, (DATA_ADDRESS + data.len()) as i32),
+            };
+            trimmed_line = trimmed_line[i + 1..].trim();
+            if trimmed_line.is_empty() {
+                continue;
+            }
+        }
+
+        let (instruction_type, remaining_line) = match trimmed_line.find(char::is_whitespace) {
+            Some(i) => (&trimmed_line[..i], trimmed_line[i + 1..].trim()),
+            None => (trimmed_line, ""),
+        };
+
+        let elements: Vec<&str> = if remaining_line.is_empty() {
+            vec![]
+        } else {
+            remaining_line.split(',').collect()
+        };
+
+        let error = |kind| located(kind, number, line, trimmed_line, remaining_line);
+        let instruction_type = instruction_type.to_lowercase();
+        if instruction_type.starts_with('.') {
+            match instruction_type.as_str() {
+                ".text" => section = Section::Text,
+                ".data" | ".rodata" | ".bss" => section = Section::Data,
+                ".section" => {
+                    validate_args_interval(1, 3, &elements).map_err(error)?;
+                    // e.g. .text.startup emitted by gcc
+                    section = if elements[0].trim().starts_with(".text") {
+                        Section::Text
+                    } else {
+                        Section::Data
+                    };
+                }
+                ".equ" | ".set" => {
+                    validate_args(2, &elements).map_err(error)?;
+                    let value = symbols.value(elements[1]).map_err(error)?;
+                    symbols
+                        .constants
+                        .insert(elements[0].trim().to_string(), value);
+                }
+                // Symbol and file metadata emitted by gcc, not needed to run a program
+                ".globl" | ".global" | ".local" | ".type" | ".size" | ".file" | ".ident"
+                | ".option" | ".attribute" => (),
+                ".align" | ".p2align" | ".balign" if section == Section::Text => {
+                    validate_args(1, &elements).map_err(error)?;
+                    let alignment = symbols
+                        .immediate(elements[0])
+                        .and_then(|n| alignment(&instruction_type, n))
+                        .map_err(error)?;
+                    // The padding is made of nop instructions
+                    while pc % alignment != 0 {
+                        statements.push(Statement {
+                            number,
+                            source: line,
+                            line: trimmed_line,
+                            instruction_type: "nop".to_string(),
+                            elements: vec![],
+                            remaining_line: "",
+                            pc,
+                        });
+                        pc += 4;
+                    }
+                }
+                _ if section == Section::Data => data_directive(
+                    &instruction_type,
+                    &elements,
+                    remaining_line,
+                    &symbols,
+                    &mut data,
+                )
+                .map_err(error)?,
+                _ => return Err(error(ParseErrorKind::UnknownDirective(instruction_type))),
+            }
             continue;
         }
 
-        let mut remaining_line = &trimmed_line[first_whitespace.unwrap() + 1..];
-        let comment = remaining_line.find('#');
-        match comment {
-            None => (),
-            Some(i) => remaining_line = &remaining_line[..i].trim(),
+        if section == Section::Data {
+            return Err(error(ParseErrorKind::InstructionInDataSection));
         }
 
-        let elements: Vec<&str> = remaining_line.split(',').collect();
+        let statement = Statement {
+            number,
+            source: line,
+            line: trimmed_line,
+            instruction_type,
+            elements,
+            remaining_line,
+            pc,
+        };
+        pc += 4 * instructions_count(&statement, &symbols).map_err(|e| statement.error(e))?;
+        statements.push(statement);
+    }
 
-        let instruction: Box<dyn InstructionRunner> = match trimmed_line
-            [..first_whitespace.unwrap()]
-            .to_lowercase()
-            .as_str()
-        {
-            "add" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs1 = parse_register(elements[1].trim().to_string())?;
-                let rs2 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Add { rd, rs1, rs2 })
-            }
-            "addi" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let imm = i32(elements[2].trim().to_string())?;
-                Box::new(Addi { rd, rs, imm })
-            }
-            "and" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs1 = parse_register(elements[1].trim().to_string())?;
-                let rs2 = parse_register(elements[2].trim().to_string())?;
-                Box::new(And { rd, rs1, rs2 })
-            }
-            "andi" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let imm = i32(elements[2].trim().to_string())?;
-                Box::new(Andi { rd, rs, imm })
-            }
-            "auipc" => {
-                validate_args(2, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let imm = i32(elements[1].trim().to_string())?;
-                Box::new(Auipc { rd, imm })
+    // Second pass: parse the instructions, now that every label is known
+    let mut instructions: Vec<Box<dyn InstructionRunner>> = vec![];
+    for statement in statements {
+        let mut parsed =
+            parse_instructions(&statement, &symbols).map_err(|e| statement.error(e))?;
+        // Rejects what can't be encoded, e.g. an immediate or an offset out of range
+        for instruction in &parsed {
+            instruction
+                .encode()
+                .map_err(|e| statement.error(ParseErrorKind::Encoding(e)))?;
+        }
+        instructions.append(&mut parsed);
+    }
+
+    let mut segments = vec![];
+    if !data.is_empty() {
+        segments.push(Segment {
+            address: DATA_ADDRESS,
+            bytes: data,
+        });
+    }
+
+    Ok(Application {
+        instructions,
+        segments,
+        text_address: None,
+        entry: 0,
+    })
+}
+
+// Locates an error in a source line: the column is the one of the token the error is about if it
+// appears in the operands, otherwise the one of the statement
+fn located(
+    kind: ParseErrorKind,
+    number: usize,
+    source: &str,
+    statement: &str,
+    operands: &str,
+) -> ParseError {
+    let start = match kind.token().and_then(|token| operands.find(token.trim())) {
+        Some(i) => offset(source, operands) + i,
+        None => offset(source, statement),
+    };
+    ParseError {
+        line: number + 1,
+        column: source[..start].chars().count() + 1,
+        kind,
+    }
+}
+
+// Byte offset of a slice borrowed from a line, 0 if it's borrowed from elsewhere
+fn offset(line: &str, s: &str) -> usize {
+    match (s.as_ptr() as usize).checked_sub(line.as_ptr() as usize) {
+        Some(offset) if offset <= line.len() => offset,
+        _ => 0,
+    }
+}
+
+fn data_directive(
+    directive: &str,
+    elements: &Vec<&str>,
+    line: &str,
+    symbols: &Symbols,
+    data: &mut Vec<i8>,
+) -> Result<(), ParseErrorKind> {
+    match directive {
+        ".word" | ".half" | ".short" | ".byte" => {
+            validate_args_interval(1, usize::MAX, elements)?;
+            let size = match directive {
+                ".word" => 4,
+                ".half" | ".short" => 2,
+                _ => 1,
+            };
+            for element in elements {
+                let bytes = bytes_from_low_bits(symbols.value(element)?);
+                data.extend_from_slice(&[bytes.0, bytes.1, bytes.2, bytes.3][..size]);
             }
-            "beq" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let label = elements[2].trim().to_string();
-                Box::new(Beq {
-                    rs1: rd,
-                    rs2: rs,
-                    label,
-                })
+        }
+        ".ascii" | ".asciz" | ".string" => {
+            for b in parse_string(line)? {
+                data.push(b as i8);
             }
-            "bge" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let label = elements[2].trim().to_string();
-                Box::new(Bge {
-                    rs1: rd,
-                    rs2: rs,
-                    label,
-                })
+            if directive != ".ascii" {
+                data.push(0);
             }
-            "bgeu" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let label = elements[2].trim().to_string();
-                Box::new(Bgeu {
-                    rs1: rd,
-                    rs2: rs,
-                    label,
-                })
+        }
+        ".space" | ".zero" => {
+            validate_args(1, elements)?;
+            let n = symbols.immediate(elements[0])?;
+            if n < 0 {
+                return Err(ParseErrorKind::InvalidSize(n));
             }
-            "blt" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let label = elements[2].trim().to_string();
-                Box::new(Blt {
-                    rs1: rd,
-                    rs2: rs,
-                    label,
-                })
+            data.resize(data.len() + n as usize, 0);
+        }
+        ".align" | ".p2align" | ".balign" => {
+            validate_args(1, elements)?;
+            let alignment = alignment(directive, symbols.immediate(elements[0])?)? as usize;
+            while !(DATA_ADDRESS + data.len()).is_multiple_of(alignment) {
+                data.push(0);
             }
-            "bltu" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let label = elements[2].trim().to_string();
-                Box::new(Bltu {
-                    rs1: rd,
-                    rs2: rs,
-                    label,
-                })
+        }
+        _ => return Err(ParseErrorKind::UnknownDirective(directive.to_string())),
+    }
+    Ok(())
+}
+
+// .align and .p2align take a power of two (like the RISC-V GNU assembler), .balign a number of
+// bytes
+fn alignment(directive: &str, n: i32) -> Result<i32, ParseErrorKind> {
+    if directive == ".balign" {
+        if n <= 0 || n & (n - 1) != 0 {
+            return Err(ParseErrorKind::InvalidAlignment(n));
+        }
+        return Ok(n);
+    }
+    if !(0..31).contains(&n) {
+        return Err(ParseErrorKind::InvalidAlignment(n));
+    }
+    Ok(1 << n)
+}
+
+fn parse_string(s: &str) -> Result<Vec<u8>, ParseErrorKind> {
+    let s = s.trim();
+    if s.len() < 2 || !s.starts_with('"') || !s.ends_with('"') {
+        return Err(ParseErrorKind::InvalidString(s.to_string()));
+    }
+
+    let mut bytes = vec![];
+    let mut chars = s[1..s.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('r') => b'\r',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            Some('\'') => b'\'',
+            _ => return Err(ParseErrorKind::InvalidString(s.to_string())),
+        };
+        bytes.push(escaped);
+    }
+    Ok(bytes)
+}
+
+// Removes a comment, ignoring the # characters within a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' if in_string => {
+                escaped = !escaped;
+                continue;
             }
-            "bne" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let label = elements[2].trim().to_string();
-                Box::new(Bne {
-                    rs1: rd,
-                    rs2: rs,
-                    label,
+            '"' if !escaped => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => (),
+        }
+        escaped = false;
+    }
+    line
+}
+
+// Returns the position of the colon if the line starts with a label
+fn label_end(line: &str) -> Option<usize> {
+    let i = line.find(':')?;
+    let label = &line[..i];
+    if label.is_empty()
+        || !label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$')
+    {
+        return None;
+    }
+    Some(i)
+}
+
+fn instructions_count(statement: &Statement, symbols: &Symbols) -> Result<i32, ParseErrorKind> {
+    match statement.instruction_type.as_str() {
+        "call" | "la" => Ok(2),
+        "li" => {
+            validate_args(2, &statement.elements)?;
+            let imm = symbols.immediate(statement.elements[1])?;
+            Ok(li(RegisterType::ZERO, imm).len() as i32)
+        }
+        _ => Ok(1),
+    }
+}
+
+fn parse_instructions(
+    statement: &Statement,
+    symbols: &Symbols,
+) -> Result<Vec<Box<dyn InstructionRunner>>, ParseErrorKind> {
+    let elements = &statement.elements;
+
+    let instruction: Box<dyn InstructionRunner> = match statement.instruction_type.as_str() {
+        "add" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Add { rd, rs1, rs2 })
+        }
+        "addi" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let imm = symbols.immediate(elements[2])?;
+            Box::new(Addi { rd, rs, imm })
+        }
+        "and" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(And { rd, rs1, rs2 })
+        }
+        "andi" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let imm = symbols.immediate(elements[2])?;
+            Box::new(Andi { rd, rs, imm })
+        }
+        "auipc" => {
+            validate_args(2, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let imm = symbols.immediate(elements[1])?;
+            Box::new(Auipc { rd, imm })
+        }
+        "beq" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let offset = symbols.offset(elements[2], statement.pc)?;
+            Box::new(Beq {
+                rs1: rd,
+                rs2: rs,
+                offset,
+            })
+        }
+        "bge" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let offset = symbols.offset(elements[2], statement.pc)?;
+            Box::new(Bge {
+                rs1: rd,
+                rs2: rs,
+                offset,
+            })
+        }
+        "bgeu" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let offset = symbols.offset(elements[2], statement.pc)?;
+            Box::new(Bgeu {
+                rs1: rd,
+                rs2: rs,
+                offset,
+            })
+        }
+        "blt" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let offset = symbols.offset(elements[2], statement.pc)?;
+            Box::new(Blt {
+                rs1: rd,
+                rs2: rs,
+                offset,
+            })
+        }
+        "bltu" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let offset = symbols.offset(elements[2], statement.pc)?;
+            Box::new(Bltu {
+                rs1: rd,
+                rs2: rs,
+                offset,
+            })
+        }
+        "bne" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let offset = symbols.offset(elements[2], statement.pc)?;
+            Box::new(Bne {
+                rs1: rd,
+                rs2: rs,
+                offset,
+            })
+        }
+        "div" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Div { rd, rs1, rs2 })
+        }
+        "divu" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Divu { rd, rs1, rs2 })
+        }
+        "ebreak" => {
+            validate_args(0, elements)?;
+            Box::new(Ebreak {})
+        }
+        "ecall" => {
+            validate_args(0, elements)?;
+            Box::new(Ecall {})
+        }
+        "fence" => {
+            // The predecessor and successor sets are accepted but ignored
+            validate_args_interval(0, 2, elements)?;
+            Box::new(Fence {})
+        }
+        "fence.i" => {
+            validate_args(0, elements)?;
+            Box::new(FenceI {})
+        }
+        "jal" => {
+            validate_args_interval(1, 2, elements)?;
+            // jal label is a shorthand for jal ra, label
+            if elements.len() == 1 {
+                let offset = symbols.offset(elements[0], statement.pc)?;
+                Box::new(Jal {
+                    rd: RegisterType::RA,
+                    offset,
                 })
-            }
-            "div" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs1 = parse_register(elements[1].trim().to_string())?;
-                let rs2 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Div { rd, rs1, rs2 })
-            }
-            "jal" => {
-                validate_args(2, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let label = elements[1].trim().to_string();
-                Box::new(Jal { rd, label })
-            }
-            "jalr" => {
-                validate_args(3, &elements, remaining_line)?;
+            } else {
                 let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let imm = i32(elements[2].trim().to_string())?;
-                Box::new(Jalr { rd, rs, imm })
+                let offset = symbols.offset(elements[1], statement.pc)?;
+                Box::new(Jal { rd, offset })
             }
-            "lui" => {
-                validate_args(2, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let imm = i32(elements[1].trim().to_string())?;
-                Box::new(Lui { rd, imm })
-            }
-            "lb" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rs2 = parse_register(elements[0].trim().to_string())?;
-                let offset = i32(elements[1].trim().to_string())?;
-                let rs1 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Lb { rs2, offset, rs1 })
-            }
-            "lh" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rs2 = parse_register(elements[0].trim().to_string())?;
-                let offset = i32(elements[1].trim().to_string())?;
-                let rs1 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Lh { rs2, offset, rs1 })
-            }
-            "lw" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rs2 = parse_register(elements[0].trim().to_string())?;
-                let offset = i32(elements[1].trim().to_string())?;
-                let rs1 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Lw { rs2, offset, rs1 })
-            }
-            "nop" => Box::new(Nop {}),
-            "mul" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs1 = parse_register(elements[1].trim().to_string())?;
-                let rs2 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Mul { rd, rs1, rs2 })
-            }
-            "or" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs1 = parse_register(elements[1].trim().to_string())?;
-                let rs2 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Or { rd, rs1, rs2 })
-            }
-            "ori" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let imm = i32(elements[2].trim().to_string())?;
-                Box::new(Ori { rd, rs, imm })
-            }
-            "rem" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs1 = parse_register(elements[1].trim().to_string())?;
-                let rs2 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Rem { rd, rs1, rs2 })
-            }
-            "sb" => {
-                validate_args_interval(2, 3, &elements, remaining_line)?;
-                if elements.len() == 2 {
-                    let rs2 = parse_register(elements[0].trim().to_string())?;
-                    let imm_reg = parse_offset_reg(elements[1].to_string())?;
-                    Box::new(Sb {
-                        rs2,
-                        offset: imm_reg.0,
-                        rs1: imm_reg.1,
+        }
+        "jalr" => {
+            validate_args_interval(1, 3, elements)?;
+            match elements.len() {
+                // jalr rs is a shorthand for jalr ra, 0(rs)
+                1 => {
+                    let rs = parse_register(elements[0].trim().to_string())?;
+                    Box::new(Jalr {
+                        rd: RegisterType::RA,
+                        rs,
+                        imm: 0,
                     })
-                } else {
-                    let rs2 = parse_register(elements[0].trim().to_string())?;
-                    let offset = i32(elements[1].trim().to_string())?;
-                    let rs1 = parse_register(elements[2].trim().to_string())?;
-                    Box::new(Sb { rs2, offset, rs1 })
+                }
+                2 => {
+                    let rd = parse_register(elements[0].trim().to_string())?;
+                    let (imm, rs) = parse_offset_reg(elements[1], symbols)?;
+                    Box::new(Jalr { rd, rs, imm })
+                }
+                _ => {
+                    let rd = parse_register(elements[0].trim().to_string())?;
+                    let rs = parse_register(elements[1].trim().to_string())?;
+                    let imm = symbols.immediate(elements[2])?;
+                    Box::new(Jalr { rd, rs, imm })
                 }
             }
-            "sh" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rs2 = parse_register(elements[0].trim().to_string())?;
-                let offset = i32(elements[1].trim().to_string())?;
-                let rs1 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Sh { rs2, offset, rs1 })
-            }
-            "sll" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs1 = parse_register(elements[1].trim().to_string())?;
-                let rs2 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Sll { rd, rs1, rs2 })
-            }
-            "slli" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let imm = i32(elements[2].trim().to_string())?;
-                Box::new(Slli { rd, rs, imm })
-            }
-            "slt" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs1 = parse_register(elements[1].trim().to_string())?;
-                let rs2 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Slt { rd, rs1, rs2 })
-            }
-            "sltu" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs1 = parse_register(elements[1].trim().to_string())?;
-                let rs2 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Sltu { rd, rs1, rs2 })
-            }
-            "slti" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let imm = i32(elements[2].trim().to_string())?;
-                Box::new(Slti { rd, rs, imm })
-            }
-            "sra" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs1 = parse_register(elements[1].trim().to_string())?;
-                let rs2 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Sra { rd, rs1, rs2 })
-            }
-            "srai" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let imm = i32(elements[2].trim().to_string())?;
-                Box::new(Srai { rd, rs, imm })
-            }
-            "srl" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs1 = parse_register(elements[1].trim().to_string())?;
-                let rs2 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Srl { rd, rs1, rs2 })
-            }
-            "srli" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let imm = i32(elements[2].trim().to_string())?;
-                Box::new(Srli { rd, rs, imm })
-            }
-            "sub" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs1 = parse_register(elements[1].trim().to_string())?;
-                let rs2 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Sub { rd, rs1, rs2 })
-            }
-            "sw" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rs2 = parse_register(elements[0].trim().to_string())?;
-                let offset = i32(elements[1].trim().to_string())?;
-                let rs1 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Sw { rs2, offset, rs1 })
-            }
-            "xor" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs1 = parse_register(elements[1].trim().to_string())?;
-                let rs2 = parse_register(elements[2].trim().to_string())?;
-                Box::new(Xor { rd, rs1, rs2 })
-            }
-            "xori" => {
-                validate_args(3, &elements, remaining_line)?;
-                let rd = parse_register(elements[0].trim().to_string())?;
-                let rs = parse_register(elements[1].trim().to_string())?;
-                let imm = i32(elements[2].trim().to_string())?;
-                Box::new(Xori { rd, rs, imm })
+        }
+        "lui" => {
+            validate_args(2, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let imm = symbols.immediate(elements[1])?;
+            Box::new(Lui { rd, imm })
+        }
+        "lb" => {
+            let (rd, offset, rs1) = memory_operands(elements, symbols)?;
+            Box::new(Lb { rd, offset, rs1 })
+        }
+        "lbu" => {
+            let (rd, offset, rs1) = memory_operands(elements, symbols)?;
+            Box::new(Lbu { rd, offset, rs1 })
+        }
+        "lh" => {
+            let (rd, offset, rs1) = memory_operands(elements, symbols)?;
+            Box::new(Lh { rd, offset, rs1 })
+        }
+        "lhu" => {
+            let (rd, offset, rs1) = memory_operands(elements, symbols)?;
+            Box::new(Lhu { rd, offset, rs1 })
+        }
+        "lw" => {
+            let (rd, offset, rs1) = memory_operands(elements, symbols)?;
+            Box::new(Lw { rd, offset, rs1 })
+        }
+        "nop" => Box::new(Nop {}),
+        "mul" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Mul { rd, rs1, rs2 })
+        }
+        "mulh" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Mulh { rd, rs1, rs2 })
+        }
+        "mulhsu" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Mulhsu { rd, rs1, rs2 })
+        }
+        "mulhu" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Mulhu { rd, rs1, rs2 })
+        }
+        "or" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Or { rd, rs1, rs2 })
+        }
+        "ori" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let imm = symbols.immediate(elements[2])?;
+            Box::new(Ori { rd, rs, imm })
+        }
+        "rem" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Rem { rd, rs1, rs2 })
+        }
+        "remu" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Remu { rd, rs1, rs2 })
+        }
+        "sb" => {
+            let (rs2, offset, rs1) = memory_operands(elements, symbols)?;
+            Box::new(Sb { rs2, offset, rs1 })
+        }
+        "sh" => {
+            let (rs2, offset, rs1) = memory_operands(elements, symbols)?;
+            Box::new(Sh { rs2, offset, rs1 })
+        }
+        "sll" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Sll { rd, rs1, rs2 })
+        }
+        "slli" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let imm = symbols.immediate(elements[2])?;
+            Box::new(Slli { rd, rs, imm })
+        }
+        "slt" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Slt { rd, rs1, rs2 })
+        }
+        "sltu" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Sltu { rd, rs1, rs2 })
+        }
+        "slti" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let imm = symbols.immediate(elements[2])?;
+            Box::new(Slti { rd, rs, imm })
+        }
+        "sltiu" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let imm = symbols.immediate(elements[2])?;
+            Box::new(Sltiu { rd, rs, imm })
+        }
+        "sra" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Sra { rd, rs1, rs2 })
+        }
+        "srai" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let imm = symbols.immediate(elements[2])?;
+            Box::new(Srai { rd, rs, imm })
+        }
+        "srl" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Srl { rd, rs1, rs2 })
+        }
+        "srli" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let imm = symbols.immediate(elements[2])?;
+            Box::new(Srli { rd, rs, imm })
+        }
+        "sub" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Sub { rd, rs1, rs2 })
+        }
+        "sw" => {
+            let (rs2, offset, rs1) = memory_operands(elements, symbols)?;
+            Box::new(Sw { rs2, offset, rs1 })
+        }
+        "xor" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs1 = parse_register(elements[1].trim().to_string())?;
+            let rs2 = parse_register(elements[2].trim().to_string())?;
+            Box::new(Xor { rd, rs1, rs2 })
+        }
+        "xori" => {
+            validate_args(3, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            let imm = symbols.immediate(elements[2])?;
+            Box::new(Xori { rd, rs, imm })
+        }
+        // Pseudo-instructions
+        "beqz" => {
+            validate_args(2, elements)?;
+            let rs1 = parse_register(elements[0].trim().to_string())?;
+            let offset = symbols.offset(elements[1], statement.pc)?;
+            Box::new(Beq {
+                rs1,
+                rs2: RegisterType::ZERO,
+                offset,
+            })
+        }
+        "bgt" => {
+            validate_args(3, elements)?;
+            let rs = parse_register(elements[0].trim().to_string())?;
+            let rt = parse_register(elements[1].trim().to_string())?;
+            let offset = symbols.offset(elements[2], statement.pc)?;
+            Box::new(Blt {
+                rs1: rt,
+                rs2: rs,
+                offset,
+            })
+        }
+        "ble" => {
+            validate_args(3, elements)?;
+            let rs = parse_register(elements[0].trim().to_string())?;
+            let rt = parse_register(elements[1].trim().to_string())?;
+            let offset = symbols.offset(elements[2], statement.pc)?;
+            Box::new(Bge {
+                rs1: rt,
+                rs2: rs,
+                offset,
+            })
+        }
+        "bnez" => {
+            validate_args(2, elements)?;
+            let rs1 = parse_register(elements[0].trim().to_string())?;
+            let offset = symbols.offset(elements[1], statement.pc)?;
+            Box::new(Bne {
+                rs1,
+                rs2: RegisterType::ZERO,
+                offset,
+            })
+        }
+        "call" => {
+            validate_args(1, elements)?;
+            // auipc and jalr rather than jal, to reach any address and not only ±1 MiB
+            let (hi, lo) = split_immediate(symbols.offset(elements[0], statement.pc)?);
+            return Ok(vec![
+                Box::new(Auipc {
+                    rd: RegisterType::RA,
+                    imm: hi,
+                }),
+                Box::new(Jalr {
+                    rd: RegisterType::RA,
+                    rs: RegisterType::RA,
+                    imm: lo,
+                }),
+            ]);
+        }
+        "j" => {
+            validate_args(1, elements)?;
+            let offset = symbols.offset(elements[0], statement.pc)?;
+            Box::new(Jal {
+                rd: RegisterType::ZERO,
+                offset,
+            })
+        }
+        "jr" => {
+            validate_args(1, elements)?;
+            let rs = parse_register(elements[0].trim().to_string())?;
+            Box::new(Jalr {
+                rd: RegisterType::ZERO,
+                rs,
+                imm: 0,
+            })
+        }
+        "la" => {
+            validate_args(2, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let label = elements[1].trim();
+            if let Some(address) = symbols.labels.get(label) {
+                // PC-relative so that the program can be loaded anywhere
+                let (hi, lo) = split_immediate(address - statement.pc);
+                return Ok(vec![
+                    Box::new(Auipc { rd, imm: hi }),
+                    Box::new(Addi {
+                        rd,
+                        rs: rd,
+                        imm: lo,
+                    }),
+                ]);
             }
-            _ => return Err(format_args!("invalid instruction type: {}", trimmed_line).to_string()),
-        };
-        instructions.push(instruction);
-        pc += 4;
+            let address = match symbols.data_labels.get(label) {
+                Some(v) => *v,
+                None => return Err(ParseErrorKind::UndefinedLabel(label.to_string())),
+            };
+            // The data section is at a fixed address
+            let (hi, lo) = split_immediate(address);
+            return Ok(vec![
+                Box::new(Lui { rd, imm: hi }),
+                Box::new(Addi {
+                    rd,
+                    rs: rd,
+                    imm: lo,
+                }),
+            ]);
+        }
+        "li" => {
+            validate_args(2, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let imm = symbols.immediate(elements[1])?;
+            return Ok(li(rd, imm));
+        }
+        "mv" => {
+            validate_args(2, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            Box::new(Addi { rd, rs, imm: 0 })
+        }
+        "neg" => {
+            validate_args(2, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs2 = parse_register(elements[1].trim().to_string())?;
+            Box::new(Sub {
+                rd,
+                rs1: RegisterType::ZERO,
+                rs2,
+            })
+        }
+        "not" => {
+            validate_args(2, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            Box::new(Xori { rd, rs, imm: -1 })
+        }
+        "ret" => {
+            validate_args(0, elements)?;
+            Box::new(Jalr {
+                rd: RegisterType::ZERO,
+                rs: RegisterType::RA,
+                imm: 0,
+            })
+        }
+        "seqz" => {
+            validate_args(2, elements)?;
+            let rd = parse_register(elements[0].trim().to_string())?;
+            let rs = parse_register(elements[1].trim().to_string())?;
+            Box::new(Sltiu { rd, rs, imm: 1 })
+        }
+        _ => {
+            return Err(ParseErrorKind::UnknownInstruction(
+                statement.instruction_type.clone(),
+            ))
+        }
+    };
+    Ok(vec![instruction])
+}
+
+// Loads an immediate using a single addi if it fits in 12 bits, otherwise lui followed by addi.
+fn li(rd: RegisterType, imm: i32) -> Vec<Box<dyn InstructionRunner>> {
+    if (-2048..2048).contains(&imm) {
+        return vec![Box::new(Addi {
+            rd,
+            rs: RegisterType::ZERO,
+            imm,
+        })];
     }
 
-    return Ok(Application {
-        instructions,
-        labels,
-    });
+    let (hi, lo) = split_immediate(imm);
+    if lo == 0 {
+        return vec![Box::new(Lui { rd, imm: hi })];
+    }
+    vec![
+        Box::new(Lui { rd, imm: hi }),
+        Box::new(Addi {
+            rd,
+            rs: rd,
+            imm: lo,
+        }),
+    ]
 }
 
-fn validate_args(expected: usize, args: &Vec<&str>, line: &str) -> Result<(), String> {
-    if args.len() == expected {
-        return Ok(());
-    }
+// Splits a value into the upper 20 bits used by lui/auipc and the sign-extended lower 12 bits
+// used by addi.
+fn split_immediate(n: i32) -> (i32, i32) {
+    let hi = n.wrapping_add(0x800) >> 12;
+    let lo = n.wrapping_sub(hi << 12);
+    (hi, lo)
+}
 
-    return Err(format_args!(
-        "invalid line expected {} arguments, got {}: {}",
-        expected,
-        args.len(),
-        line
-    )
-    .to_string());
+fn validate_args(expected: usize, args: &[&str]) -> Result<(), ParseErrorKind> {
+    validate_args_interval(expected, expected, args)
 }
 
-fn validate_args_interval(
-    min: usize,
-    max: usize,
-    args: &Vec<&str>,
-    line: &str,
-) -> Result<(), String> {
+fn validate_args_interval(min: usize, max: usize, args: &[&str]) -> Result<(), ParseErrorKind> {
     if args.len() >= min && args.len() <= max {
         return Ok(());
     }
 
-    return Err(format_args!(
-        "invalid line expected between {} and {} arguments, got {}: {}",
+    Err(ParseErrorKind::ArgumentCount {
         min,
         max,
-        args.len(),
-        line
-    )
-    .to_string());
+        got: args.len(),
+    })
 }
 
-fn i32(s: String) -> Result<i32, String> {
-    return match s.parse::<i32>() {
-        Ok(n) => Ok(n),
-        Err(e) => Err(e.to_string()),
+// Parses a decimal, hexadecimal (0x), binary (0b) or character ('c') literal
+fn i32(s: String) -> Result<i32, ParseErrorKind> {
+    if let Some(c) = s.strip_prefix('\'').and_then(|c| c.strip_suffix('\'')) {
+        let bytes = parse_string(&format!("\"{}\"", c))?;
+        if bytes.len() != 1 {
+            return Err(ParseErrorKind::InvalidImmediate(s));
+        }
+        return Ok(bytes[0] as i32);
+    }
+
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s.strip_prefix('+').unwrap_or(&s)),
     };
+    let lower = digits.to_lowercase();
+    let n = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2)
+    } else {
+        digits.parse::<i64>()
+    }
+    .map_err(|_| ParseErrorKind::InvalidImmediate(s.clone()))?;
+    let n = if negative { -n } else { n };
+
+    // Values up to 0xffffffff are accepted and reinterpreted as signed
+    if n < i32::MIN as i64 || n > u32::MAX as i64 {
+        return Err(ParseErrorKind::ImmediateOutOfRange(s));
+    }
+    Ok(n as u32 as i32)
 }
 
-fn parse_offset_reg(s: String) -> Result<(i32, RegisterType), String> {
-    let first_parenthesis = s.find('(');
-    let first_parenthesis_idx: usize;
-    match first_parenthesis {
-        None => return Err(format_args!("invalid offset register: {}", s).to_string()),
-        Some(i) => first_parenthesis_idx = i,
+// Operands of a load or a store, either rd, imm(rs1) or rd, imm, rs1
+fn memory_operands(
+    elements: &Vec<&str>,
+    symbols: &Symbols,
+) -> Result<(RegisterType, i32, RegisterType), ParseErrorKind> {
+    validate_args_interval(2, 3, elements)?;
+    let rd = parse_register(elements[0].trim().to_string())?;
+    if elements.len() == 2 {
+        let (offset, rs1) = parse_offset_reg(elements[1], symbols)?;
+        return Ok((rd, offset, rs1));
+    }
+    let offset = symbols.immediate(elements[1])?;
+    let rs1 = parse_register(elements[2].trim().to_string())?;
+    Ok((rd, offset, rs1))
+}
+
+fn parse_offset_reg(s: &str, symbols: &Symbols) -> Result<(i32, RegisterType), ParseErrorKind> {
+    let s = s.trim();
+    // The last parenthesis, as the offset can be a %lo(symbol)
+    let last_parenthesis = s.rfind('(');
+
+    let last_parenthesis_idx: usize = match last_parenthesis {
+        Some(i) if s.ends_with(')') => i,
+        _ => return Err(ParseErrorKind::InvalidOperand(s.to_string())),
     };
 
-    let imm_string = s[..first_parenthesis_idx].trim();
-    let imm = i32(imm_string.to_string())?;
+    let imm_string = s[..last_parenthesis_idx].trim();
+    // The offset can be omitted, e.g. (t0)
+    let imm = if imm_string.is_empty() {
+        0
+    } else {
+        symbols.immediate(imm_string)?
+    };
 
-    let reg_string = s[first_parenthesis_idx + 1..s.len() - 1].trim();
+    let reg_string = s[last_parenthesis_idx + 1..s.len() - 1].trim();
 
-    return Ok((imm, parse_register(reg_string.to_string())?));
+    Ok((imm, parse_register(reg_string.to_string())?))
 }
 
-fn parse_register(s: String) -> Result<RegisterType, String> {
-    return match s.as_str() {
-        "zero" | "$zero" => Ok(RegisterType::ZERO),
-        "ra" | "$ra" => Ok(RegisterType::RA),
-        "sp" | "$sp" => Ok(RegisterType::SP),
-        "gp" | "$gp" => Ok(RegisterType::GP),
-        "tp" | "$tp" => Ok(RegisterType::TP),
-        "t0" | "$t0" => Ok(RegisterType::T0),
-        "t1" | "$t1" => Ok(RegisterType::T1),
-        "t2" | "$t2" => Ok(RegisterType::T2),
-        "s0" | "$s0" => Ok(RegisterType::S0),
-        "s1" | "$s1" => Ok(RegisterType::S1),
-        "a0" | "$a0" => Ok(RegisterType::A0),
-        "a1" | "$a1" => Ok(RegisterType::A1),
-        "a2" | "$a2" => Ok(RegisterType::A2),
-        "a3" | "$a3" => Ok(RegisterType::A3),
-        "a4" | "$a4" => Ok(RegisterType::A4),
-        "a5" | "$a5" => Ok(RegisterType::A5),
-        "a6" | "$a6" => Ok(RegisterType::A6),
-        "a7" | "$a7" => Ok(RegisterType::A7),
-        "s2" | "$s2" => Ok(RegisterType::S2),
-        "s3" | "$s3" => Ok(RegisterType::S3),
-        "s4" | "$s4" => Ok(RegisterType::S4),
-        "s5" | "$s5" => Ok(RegisterType::S5),
-        "s6" | "$s6" => Ok(RegisterType::S6),
-        "s7" | "$s7" => Ok(RegisterType::S7),
-        "s8" | "$s8" => Ok(RegisterType::S8),
-        "s9" | "$s9" => Ok(RegisterType::S9),
-        "s10" | "$s10" => Ok(RegisterType::S10),
-        "s11" | "$s11" => Ok(RegisterType::S11),
-        "t3" | "$t3" => Ok(RegisterType::T3),
-        "t4" | "$t4" => Ok(RegisterType::T4),
-        "t5" | "$t5" => Ok(RegisterType::T5),
-        "t6" | "$t6" => Ok(RegisterType::T6),
-        _ => Err(format_args!("unknown register: {}", s).to_string()),
-    };
+pub fn parse_register(s: String) -> Result<RegisterType, ParseErrorKind> {
+    match s.as_str() {
+        "zero" | "$zero" | "x0" | "$x0" => Ok(RegisterType::ZERO),
+        "ra" | "$ra" | "x1" | "$x1" => Ok(RegisterType::RA),
+        "sp" | "$sp" | "x2" | "$x2" => Ok(RegisterType::SP),
+        "gp" | "$gp" | "x3" | "$x3" => Ok(RegisterType::GP),
+        "tp" | "$tp" | "x4" | "$x4" => Ok(RegisterType::TP),
+        "t0" | "$t0" | "x5" | "$x5" => Ok(RegisterType::T0),
+        "t1" | "$t1" | "x6" | "$x6" => Ok(RegisterType::T1),
+        "t2" | "$t2" | "x7" | "$x7" => Ok(RegisterType::T2),
+        "s0" | "$s0" | "x8" | "$x8" | "fp" | "$fp" => Ok(RegisterType::S0),
+        "s1" | "$s1" | "x9" | "$x9" => Ok(RegisterType::S1),
+        "a0" | "$a0" | "x10" | "$x10" => Ok(RegisterType::A0),
+        "a1" | "$a1" | "x11" | "$x11" => Ok(RegisterType::A1),
+        "a2" | "$a2" | "x12" | "$x12" => Ok(RegisterType::A2),
+        "a3" | "$a3" | "x13" | "$x13" => Ok(RegisterType::A3),
+        "a4" | "$a4" | "x14" | "$x14" => Ok(RegisterType::A4),
+        "a5" | "$a5" | "x15" | "$x15" => Ok(RegisterType::A5),
+        "a6" | "$a6" | "x16" | "$x16" => Ok(RegisterType::A6),
+        "a7" | "$a7" | "x17" | "$x17" => Ok(RegisterType::A7),
+        "s2" | "$s2" | "x18" | "$x18" => Ok(RegisterType::S2),
+        "s3" | "$s3" | "x19" | "$x19" => Ok(RegisterType::S3),
+        "s4" | "$s4" | "x20" | "$x20" => Ok(RegisterType::S4),
+        "s5" | "$s5" | "x21" | "$x21" => Ok(RegisterType::S5),
+        "s6" | "$s6" | "x22" | "$x22" => Ok(RegisterType::S6),
+        "s7" | "$s7" | "x23" | "$x23" => Ok(RegisterType::S7),
+        "s8" | "$s8" | "x24" | "$x24" => Ok(RegisterType::S8),
+        "s9" | "$s9" | "x25" | "$x25" => Ok(RegisterType::S9),
+        "s10" | "$s10" | "x26" | "$x26" => Ok(RegisterType::S10),
+        "s11" | "$s11" | "x27" | "$x27" => Ok(RegisterType::S11),
+        "t3" | "$t3" | "x28" | "$x28" => Ok(RegisterType::T3),
+        "t4" | "$t4" | "x29" | "$x29" => Ok(RegisterType::T4),
+        "t5" | "$t5" | "x30" | "$x30" => Ok(RegisterType::T5),
+        "t6" | "$t6" | "x31" | "$x31" => Ok(RegisterType::T6),
+        _ => Err(ParseErrorKind::UnknownRegister(s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::EncodeError;
+
+    fn instruction_types(application: &Application) -> Vec<String> {
+        application
+            .instructions
+            .iter()
+            .map(|runner| format!("{:?}", runner.instruction_type()))
+            .collect()
+    }
+
+    #[test]
+    fn test_li() {
+        let application = parse(
+            "li t0, 1
+            li t1, 305419896
+            li t2, 4096
+            foo:
+            addi t3, zero, 1
+            j foo"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["ADDI", "LUI", "ADDI", "LUI", "ADDI", "JAL"],
+            instruction_types(&application)
+        );
+        // foo is at 16
+        assert_eq!(
+            "Jal { offset: -4, rd: ZERO }",
+            format!("{:?}", application.instructions[5])
+        );
+    }
+
+    #[test]
+    fn test_la() {
+        let application = parse(
+            "foo:
+            li t1, 305419896
+            la t0, foo
+            la t0, bar
+            bar:"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["LUI", "ADDI", "AUIPC", "ADDI", "AUIPC", "ADDI"],
+            instruction_types(&application)
+        );
+        // bar is at 24
+        assert_eq!(
+            "Addi { imm: 8, rd: T0, rs: T0 }",
+            format!("{:?}", application.instructions[5])
+        );
+        assert!(parse("la t0, foo".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_data_directives() {
+        let application = parse(
+            ".equ SIZE, 2
+            .data
+            a: .word 1, -2
+            b: .half 4660
+            .byte SIZE
+            .align 2
+            c: .asciz \"a#\\n\" # comment
+            d: .space SIZE
+            e: .word c
+            .text
+            main: la t0, c
+            lw t1, 0, t0
+            j main"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(1, application.segments.len());
+        assert_eq!(DATA_ADDRESS, application.segments[0].address);
+        assert_eq!(
+            vec![
+                1, 0, 0, 0, -2, -1, -1, -1, 0x34, 0x12, 2, 0, 'a' as i8, '#' as i8, '\n' as i8, 0,
+                0, 0, 12, 0, 0, 0
+            ],
+            application.segments[0].bytes
+        );
+        assert_eq!(
+            "Jal { offset: -12, rd: ZERO }",
+            format!("{:?}", application.instructions[3])
+        );
+        assert_eq!(
+            vec!["LUI", "ADDI", "LW", "JAL"],
+            instruction_types(&application)
+        );
+        // c is a data label
+        assert!(parse(".data\nc: .word 1\n.text\nj c".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_directive_errors() {
+        assert!(parse(".data\nadd t0, t1, t2".to_string()).is_err());
+        assert!(parse(".foo 1".to_string()).is_err());
+        assert!(parse(".data\n.ascii foo".to_string()).is_err());
+        assert!(parse(".data\n.align 31".to_string()).is_err());
+        assert!(parse(".text\n.word 1".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_text_align() {
+        let application = parse(
+            "nop
+            .align 3
+            foo: nop
+            beq zero, zero, foo"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["NOP", "NOP", "NOP", "BEQ"],
+            instruction_types(&application)
+        );
+        // foo is at 8
+        assert_eq!(
+            "Beq { rs1: ZERO, rs2: ZERO, offset: -4 }",
+            format!("{:?}", application.instructions[3])
+        );
+    }
+
+    #[test]
+    fn test_immediates() {
+        assert_eq!(42, i32("42".to_string()).unwrap());
+        assert_eq!(-42, i32("-42".to_string()).unwrap());
+        assert_eq!(255, i32("0xFF".to_string()).unwrap());
+        assert_eq!(-16, i32("-0x10".to_string()).unwrap());
+        assert_eq!(-1, i32("0xffffffff".to_string()).unwrap());
+        assert_eq!(5, i32("0b101".to_string()).unwrap());
+        assert_eq!(97, i32("'a'".to_string()).unwrap());
+        assert_eq!(10, i32("'\\n'".to_string()).unwrap());
+        assert!(i32("0x100000000".to_string()).is_err());
+        assert!(i32("0b2".to_string()).is_err());
+        assert!(i32("'ab'".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_registers() {
+        assert_eq!(
+            RegisterType::ZERO,
+            parse_register("x0".to_string()).unwrap()
+        );
+        assert_eq!(RegisterType::S0, parse_register("fp".to_string()).unwrap());
+        assert_eq!(RegisterType::S0, parse_register("x8".to_string()).unwrap());
+        assert_eq!(RegisterType::A0, parse_register("x10".to_string()).unwrap());
+        assert_eq!(RegisterType::T6, parse_register("x31".to_string()).unwrap());
+        assert!(parse_register("x32".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_offset_operands() {
+        let application = parse(
+            ".data
+            n: .word 1
+            .text
+            lw a5, -20(s0)
+            sw ra, 12(sp)
+            lbu a0, (a1)
+            lui a5, %hi(n)
+            lw a0, %lo(n)(a5)
+            lw a0, 4, a5
+            jalr a5
+            jalr zero, 0(ra)
+            jal foo
+            foo:"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["LW", "SW", "LBU", "LUI", "LW", "LW", "JALR", "JALR", "JAL"],
+            instruction_types(&application)
+        );
+        assert!(parse("lw a0, 4(a1".to_string()).is_err());
+        assert!(parse("lw a0, %lo(foo)(a1)".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_unencodable_instructions() {
+        assert!(parse("addi t0, t0, 2048".to_string()).is_err());
+        assert!(parse("slli t0, t0, 32".to_string()).is_err());
+        assert!(parse("lw t0, -2049(sp)".to_string()).is_err());
+        assert!(parse("beq t0, t1, foo".to_string()).is_err());
+        assert!(parse("addi t0, t0, -2048".to_string()).is_ok());
+    }
+
+    #[test]
+    fn test_label_offsets() {
+        let application = parse(
+            "loop: nop
+            nop
+            bne a0, zero, loop
+            j loop
+            j end
+            end:"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            "Bne { rs1: A0, rs2: ZERO, offset: -8 }",
+            format!("{:?}", application.instructions[2])
+        );
+        // bne a0, zero, -8
+        assert_eq!(0xfe051ce3, application.instructions[2].encode().unwrap());
+        assert_eq!(
+            "Jal { offset: -12, rd: ZERO }",
+            format!("{:?}", application.instructions[3])
+        );
+        assert_eq!(
+            "Jal { offset: 4, rd: ZERO }",
+            format!("{:?}", application.instructions[4])
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |s: &str| parse(s.to_string()).err().unwrap();
+
+        let e = error("addi t0, t0, 1\nadd t0, t9, t1");
+        assert_eq!(
+            ParseError {
+                line: 2,
+                column: 9,
+                kind: ParseErrorKind::UnknownRegister("t9".to_string()),
+            },
+            e
+        );
+        assert_eq!("line 2, column 9: unknown register: t9", e.to_string());
+
+        let e = error("foo: beq t0, t1, bar");
+        assert_eq!(ParseErrorKind::UndefinedLabel("bar".to_string()), e.kind);
+        assert_eq!((1, 18), (e.line, e.column));
+
+        let e = error("  foo t0");
+        assert_eq!(
+            ParseErrorKind::UnknownInstruction("foo".to_string()),
+            e.kind
+        );
+        assert_eq!(3, e.column);
+
+        let e = error("addi t0, t0");
+        assert_eq!(
+            ParseErrorKind::ArgumentCount {
+                min: 3,
+                max: 3,
+                got: 2
+            },
+            e.kind
+        );
+
+        let e = error("addi t0, t0, 2048");
+        assert_eq!(
+            ParseErrorKind::Encoding(EncodeError::ImmediateOutOfRange {
+                imm: 2048,
+                bits: 12
+            }),
+            e.kind
+        );
+
+        let e = error(".data\n\tadd t0, t1, t2");
+        assert_eq!(ParseErrorKind::InstructionInDataSection, e.kind);
+        assert_eq!((2, 2), (e.line, e.column));
+
+        let e = error(".data\n.word 1, foo");
+        assert_eq!(ParseErrorKind::InvalidImmediate("foo".to_string()), e.kind);
+        assert_eq!(10, e.column);
+    }
+
+    #[test]
+    fn test_pseudo_instructions() {
+        let application = parse(
+            "mv t0, t1
+            not t0, t1
+            neg t0, t1
+            seqz t0, t1
+            beqz t0, foo
+            bnez t0, foo
+            bgt t0, t1, foo
+            ble t0, t1, foo
+            j foo
+            call foo
+            jr t0
+            foo:
+            ret"
+            .to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                "ADDI", "XORI", "SUB", "SLTIU", "BEQ", "BNE", "BLT", "BGE", "JAL", "AUIPC", "JALR",
+                "JALR", "JALR"
+            ],
+            instruction_types(&application)
+        );
+    }
+
+    #[test]
+    fn test_split_immediate() {
+        assert_eq!((0, 1), split_immediate(1));
+        assert_eq!((1, -2048), split_immediate(2048));
+        assert_eq!((0x12345, 0x678), split_immediate(0x12345678));
+        assert_eq!((0x12346, -0x322), split_immediate(0x12345cde));
+        assert_eq!((-0x80000, -1), split_immediate(i32::MAX));
+        assert_eq!((0, -1), split_immediate(-1));
+    }
 }
@@ -0,0 +1,429 @@
+use crate::config::PredictorConfig;
+use crate::encoding::{OPCODE_JAL, OPCODE_JALR};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::fmt;
+
+// Direction predictor of the conditional branches
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PredictorKind {
+    // Never taken
+    NotTaken,
+    // Backward taken, forward not taken: loops are predicted to iterate
+    Btfn,
+    // 2-bit saturating counter per branch
+    Bimodal,
+    // 2-bit saturating counters indexed by the address xor the global history
+    Gshare,
+    // Bimodal and gshare, with a 2-bit counter per branch choosing the best one
+    Tournament,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    // Conditional branches executed
+    pub branches: u64,
+    pub mispredictions: u64,
+    // Jumps executed, and the ones whose target wasn't predicted by the fetch unit
+    pub jumps: u64,
+    pub jump_mispredictions: u64,
+    // Pipeline flushes, including the mispredicted jumps and fence.i
+    pub flushes: u64,
+}
+
+impl Stats {
+    pub fn misprediction_rate(&self) -> f64 {
+        if self.branches == 0 {
+            return 0.;
+        }
+        self.mispredictions as f64 / self.branches as f64
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} branches, {} mispredictions ({:.2}% misprediction rate), {} jumps, {} jump mispredictions, {} flushes",
+            self.branches,
+            self.mispredictions,
+            self.misprediction_rate() * 100.,
+            self.jumps,
+            self.jump_mispredictions,
+            self.flushes
+        )
+    }
+}
+
+// Predicts whether a conditional branch is taken when it's fetched, and learns its outcome once
+// executed
+pub trait Predictor {
+    // target is the address of the branch destination
+    fn predict(&self, pc: i32, target: i32) -> bool;
+    fn update(&mut self, pc: i32, taken: bool);
+}
+
+pub fn new(config: &PredictorConfig) -> Box<dyn Predictor> {
+    match config.kind {
+        PredictorKind::NotTaken => Box::new(NotTaken {}),
+        PredictorKind::Btfn => Box::new(Btfn {}),
+        PredictorKind::Bimodal => Box::new(Bimodal::new(config.entries)),
+        PredictorKind::Gshare => Box::new(Gshare::new(config.entries, config.history)),
+        PredictorKind::Tournament => Box::new(Tournament {
+            bimodal: Bimodal::new(config.entries),
+            gshare: Gshare::new(config.entries, config.history),
+            chooser: Counters::new(config.entries),
+        }),
+    }
+}
+
+struct NotTaken {}
+
+impl Predictor for NotTaken {
+    fn predict(&self, _pc: i32, _target: i32) -> bool {
+        false
+    }
+
+    fn update(&mut self, _pc: i32, _taken: bool) {}
+}
+
+struct Btfn {}
+
+impl Predictor for Btfn {
+    fn predict(&self, pc: i32, target: i32) -> bool {
+        target < pc
+    }
+
+    fn update(&mut self, _pc: i32, _taken: bool) {}
+}
+
+// 2-bit saturating counters, predicting taken from 2, starting weakly not taken. The number of
+// counters is a power of 2.
+struct Counters {
+    counters: Vec<u8>,
+}
+
+impl Counters {
+    fn new(entries: u32) -> Self {
+        Counters {
+            counters: vec![1; entries as usize],
+        }
+    }
+
+    fn index(&self, value: u32) -> usize {
+        value as usize & (self.counters.len() - 1)
+    }
+
+    fn taken(&self, value: u32) -> bool {
+        self.counters[self.index(value)] >= 2
+    }
+
+    fn update(&mut self, value: u32, taken: bool) {
+        let idx = self.index(value);
+        let counter = &mut self.counters[idx];
+        if taken {
+            *counter = (*counter + 1).min(3);
+        } else {
+            *counter = counter.saturating_sub(1);
+        }
+    }
+}
+
+// Instructions are aligned on 4 bytes, so the low bits of the address are dropped
+fn word_address(pc: i32) -> u32 {
+    pc as u32 >> 2
+}
+
+struct Bimodal {
+    counters: Counters,
+}
+
+impl Bimodal {
+    fn new(entries: u32) -> Self {
+        Bimodal {
+            counters: Counters::new(entries),
+        }
+    }
+}
+
+impl Predictor for Bimodal {
+    fn predict(&self, pc: i32, _target: i32) -> bool {
+        self.counters.taken(word_address(pc))
+    }
+
+    fn update(&mut self, pc: i32, taken: bool) {
+        self.counters.update(word_address(pc), taken);
+    }
+}
+
+struct Gshare {
+    counters: Counters,
+    // Outcomes of the last branches, the most recent one in the lowest bit
+    history: u32,
+    history_mask: u32,
+}
+
+impl Gshare {
+    fn new(entries: u32, history: u32) -> Self {
+        Gshare {
+            counters: Counters::new(entries),
+            history: 0,
+            history_mask: ((1u64 << history) - 1) as u32,
+        }
+    }
+
+    fn value(&self, pc: i32) -> u32 {
+        word_address(pc) ^ self.history
+    }
+}
+
+impl Predictor for Gshare {
+    fn predict(&self, pc: i32, _target: i32) -> bool {
+        self.counters.taken(self.value(pc))
+    }
+
+    fn update(&mut self, pc: i32, taken: bool) {
+        self.counters.update(self.value(pc), taken);
+        self.history = (self.history << 1 | taken as u32) & self.history_mask;
+    }
+}
+
+struct Tournament {
+    bimodal: Bimodal,
+    gshare: Gshare,
+    // Taken means gshare
+    chooser: Counters,
+}
+
+impl Predictor for Tournament {
+    fn predict(&self, pc: i32, target: i32) -> bool {
+        if self.chooser.taken(word_address(pc)) {
+            return self.gshare.predict(pc, target);
+        }
+        self.bimodal.predict(pc, target)
+    }
+
+    fn update(&mut self, pc: i32, taken: bool) {
+        let bimodal = self.bimodal.predict(pc, 0);
+        let gshare = self.gshare.predict(pc, 0);
+        // The chooser only learns when one of them is right and the other one wrong
+        if bimodal != gshare {
+            self.chooser.update(word_address(pc), gshare == taken);
+        }
+        self.bimodal.update(pc, taken);
+        self.gshare.update(pc, taken);
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Jump {
+    // jal or jalr linking into ra
+    Call,
+    // jalr zero, ra, 0
+    Return,
+    Other,
+}
+
+// Predecodes the jumps from the instruction word
+pub fn jump(word: u32) -> Option<Jump> {
+    let rd = word >> 7 & 0x1f;
+    match word & 0x7f {
+        OPCODE_JALR if word == 0x00008067 => Some(Jump::Return),
+        OPCODE_JAL | OPCODE_JALR if rd == 1 => Some(Jump::Call),
+        OPCODE_JAL | OPCODE_JALR => Some(Jump::Other),
+        _ => None,
+    }
+}
+
+// Branch target buffer: the last target of the jumps, direct-mapped by their address. Without
+// entries, no target is known.
+pub struct Btb {
+    // Address of the jump and its target
+    entries: Vec<Option<(i32, i32)>>,
+}
+
+impl Btb {
+    pub fn new(entries: u32) -> Self {
+        Btb {
+            entries: vec![None; entries as usize],
+        }
+    }
+
+    fn index(&self, pc: i32) -> usize {
+        word_address(pc) as usize & (self.entries.len() - 1)
+    }
+
+    pub fn target(&self, pc: i32) -> Option<i32> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        match self.entries[self.index(pc)] {
+            Some((address, target)) if address == pc => Some(target),
+            _ => None,
+        }
+    }
+
+    pub fn update(&mut self, pc: i32, target: i32) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let idx = self.index(pc);
+        self.entries[idx] = Some((pc, target));
+    }
+}
+
+// Return address stack: the calls push the address following them, and the returns pop it. Once
+// full, the oldest address is dropped.
+#[derive(Clone)]
+pub struct ReturnStack {
+    addresses: VecDeque<i32>,
+    depth: usize,
+}
+
+impl ReturnStack {
+    pub fn new(depth: u32) -> Self {
+        ReturnStack {
+            addresses: VecDeque::new(),
+            depth: depth as usize,
+        }
+    }
+
+    pub fn push(&mut self, address: i32) {
+        if self.depth == 0 {
+            return;
+        }
+        if self.addresses.len() == self.depth {
+            self.addresses.pop_front();
+        }
+        self.addresses.push_back(address);
+    }
+
+    pub fn pop(&mut self) -> Option<i32> {
+        self.addresses.pop_back()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn predictor(kind: PredictorKind) -> Box<dyn Predictor> {
+        new(&PredictorConfig {
+            kind,
+            entries: 16,
+            history: 4,
+            btb_entries: 0,
+            ras_depth: 0,
+        })
+    }
+
+    // Predicts then trains on each outcome of the branch at pc, returns the number of mispredictions
+    fn mispredictions(predictor: &mut Box<dyn Predictor>, pc: i32, outcomes: &[bool]) -> usize {
+        let mut mispredictions = 0;
+        for taken in outcomes {
+            if predictor.predict(pc, 0) != *taken {
+                mispredictions += 1;
+            }
+            predictor.update(pc, *taken);
+        }
+        mispredictions
+    }
+
+    #[test]
+    fn test_static() {
+        let mut not_taken = predictor(PredictorKind::NotTaken);
+        assert_eq!(3, mispredictions(&mut not_taken, 64, &[true, true, true]));
+        let btfn = predictor(PredictorKind::Btfn);
+        assert!(btfn.predict(64, 32));
+        assert!(!btfn.predict(64, 96));
+    }
+
+    #[test]
+    fn test_bimodal() {
+        let mut bimodal = predictor(PredictorKind::Bimodal);
+        // Learns after one taken branch, and tolerates a single not taken one
+        assert_eq!(1, mispredictions(&mut bimodal, 64, &[true, true, true]));
+        assert_eq!(1, mispredictions(&mut bimodal, 64, &[false, true, true]));
+        // Another counter
+        assert!(!bimodal.predict(68, 0));
+        // Aliasing with 16 counters
+        assert!(bimodal.predict(64 + 16 * 4, 0));
+    }
+
+    #[test]
+    fn test_gshare() {
+        // An alternating branch is mispredicted by a bimodal predictor, not once gshare has learned
+        // each history
+        let outcomes: Vec<bool> = (0..32).map(|i| i % 2 == 0).collect();
+        let mut bimodal = predictor(PredictorKind::Bimodal);
+        assert_eq!(32, mispredictions(&mut bimodal, 64, &outcomes));
+        let mut gshare = predictor(PredictorKind::Gshare);
+        mispredictions(&mut gshare, 64, &outcomes[..16]);
+        assert_eq!(0, mispredictions(&mut gshare, 64, &outcomes[16..]));
+    }
+
+    #[test]
+    fn test_tournament() {
+        let outcomes: Vec<bool> = (0..64).map(|i| i % 2 == 0).collect();
+        let mut tournament = predictor(PredictorKind::Tournament);
+        mispredictions(&mut tournament, 64, &outcomes[..32]);
+        // Chooses gshare for the alternating branch
+        assert_eq!(0, mispredictions(&mut tournament, 64, &outcomes[32..]));
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = Stats {
+            branches: 8,
+            mispredictions: 2,
+            jumps: 1,
+            jump_mispredictions: 1,
+            flushes: 3,
+        };
+        assert_eq!(0.25, stats.misprediction_rate());
+        assert_eq!(0., Stats::default().misprediction_rate());
+    }
+
+    #[test]
+    fn test_jump() {
+        // jal ra, 8
+        assert_eq!(Some(Jump::Call), jump(0x008000ef));
+        // jalr ra, 0(t0)
+        assert_eq!(Some(Jump::Call), jump(0x000280e7));
+        // ret
+        assert_eq!(Some(Jump::Return), jump(0x00008067));
+        // j 8, jalr zero, 0(t0)
+        assert_eq!(Some(Jump::Other), jump(0x0080006f));
+        assert_eq!(Some(Jump::Other), jump(0x00028067));
+        // addi zero, zero, 0
+        assert_eq!(None, jump(0x00000013));
+    }
+
+    #[test]
+    fn test_btb() {
+        let mut btb = Btb::new(4);
+        assert_eq!(None, btb.target(8));
+        btb.update(8, 64);
+        assert_eq!(Some(64), btb.target(8));
+        // Same entry, another jump
+        assert_eq!(None, btb.target(8 + 16));
+        btb.update(8 + 16, 128);
+        assert_eq!(None, btb.target(8));
+        let mut disabled = Btb::new(0);
+        disabled.update(8, 64);
+        assert_eq!(None, disabled.target(8));
+    }
+
+    #[test]
+    fn test_return_stack() {
+        let mut ras = ReturnStack::new(2);
+        ras.push(4);
+        ras.push(8);
+        ras.push(12);
+        assert_eq!(Some(12), ras.pop());
+        assert_eq!(Some(8), ras.pop());
+        // 4 was dropped
+        assert_eq!(None, ras.pop());
+    }
+}
@@ -0,0 +1,232 @@
+use crate::opcodes::*;
+use std::io;
+use std::io::{Read, Write};
+
+// System call numbers, as defined by the RISC-V Linux ABI (also used by newlib)
+pub const SYS_READ: i32 = 63;
+pub const SYS_WRITE: i32 = 64;
+pub const SYS_EXIT: i32 = 93;
+pub const SYS_EXIT_GROUP: i32 = 94;
+pub const SYS_BRK: i32 = 214;
+
+const STDIN: i32 = 0;
+const STDOUT: i32 = 1;
+const STDERR: i32 = 2;
+
+const EIO: i32 = 5;
+const EBADF: i32 = 9;
+const EFAULT: i32 = 14;
+const ENOSYS: i32 = 38;
+
+// Host streams used by the guest file descriptors 0, 1 and 2.
+pub struct Io {
+    pub stdin: Box<dyn Read>,
+    pub stdout: Box<dyn Write>,
+    pub stderr: Box<dyn Write>,
+}
+
+impl Io {
+    pub fn host() -> Self {
+        Io {
+            stdin: Box::new(io::stdin()),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+        }
+    }
+}
+
+// Handles an ecall: the system call number is in a7, the arguments in a0-a2 and the result is
+// returned in a0 (negative errno values on failure).
+pub fn handle(ctx: &mut Context) -> Execution {
+    let a0 = ctx.registers[RegisterType::A0];
+    let a1 = ctx.registers[RegisterType::A1];
+    let a2 = ctx.registers[RegisterType::A2];
+
+    let result = match ctx.registers[RegisterType::A7] {
+        SYS_EXIT | SYS_EXIT_GROUP => {
+            ctx.exit_code = Some(a0);
+            return Execution::pc(ctx.pc + 4);
+        }
+        SYS_WRITE => write(ctx, a0, a1, a2),
+        SYS_READ => read(ctx, a0, a1, a2),
+        SYS_BRK => brk(ctx, a0),
+        n => {
+            log::warn!("unsupported system call {} at pc {}", n, ctx.pc);
+            -ENOSYS
+        }
+    };
+    Execution::new(RegisterType::A0, result, ctx.pc + 4)
+}
+
+fn write(ctx: &mut Context, fd: i32, buf: i32, count: i32) -> i32 {
+    let range = match memory_range(ctx, buf, count) {
+        Some(range) => range,
+        None => return -EFAULT,
+    };
+    let bytes: Vec<u8> = ctx.memory[range].iter().map(|b| *b as u8).collect();
+    let stream = match fd {
+        STDOUT => &mut ctx.io.stdout,
+        STDERR => &mut ctx.io.stderr,
+        _ => return -EBADF,
+    };
+    match stream.write_all(&bytes).and_then(|_| stream.flush()) {
+        Ok(_) => count,
+        Err(_) => -EIO,
+    }
+}
+
+fn read(ctx: &mut Context, fd: i32, buf: i32, count: i32) -> i32 {
+    if fd != STDIN {
+        return -EBADF;
+    }
+    let range = match memory_range(ctx, buf, count) {
+        Some(range) => range,
+        None => return -EFAULT,
+    };
+    let mut bytes = vec![0; range.len()];
+    let n = match ctx.io.stdin.read(&mut bytes) {
+        Ok(n) => n,
+        Err(_) => return -EIO,
+    };
+    for (i, b) in bytes[..n].iter().enumerate() {
+        ctx.memory[range.start + i] = *b as i8;
+    }
+    n as i32
+}
+
+fn brk(ctx: &mut Context, addr: i32) -> i32 {
+    // The heap can't grow over the instructions placed after it
+    let limit = if ctx.text.1 > ctx.text.0 && ctx.text.0 >= ctx.brk {
+        ctx.text.0
+    } else {
+        ctx.memory.len() as i32
+    };
+    // Like Linux, an invalid break (including 0) leaves it unchanged and returns the current one
+    if addr >= ctx.brk && addr <= limit {
+        ctx.brk = addr;
+    }
+    ctx.brk
+}
+
+fn memory_range(ctx: &Context, buf: i32, count: i32) -> Option<std::ops::Range<usize>> {
+    if buf < 0 || count < 0 {
+        return None;
+    }
+    let start = buf as usize;
+    let end = start + count as usize;
+    if end > ctx.memory.len() {
+        return None;
+    }
+    Some(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(ctx: &mut Context, instructions: &str) {
+        let application = parse(instructions.to_string()).unwrap();
+        ctx.pc = 0;
+        while ctx.exit_code.is_none() && ctx.pc / 4 < application.instructions.len() as i32 {
+            let runner = &application.instructions[(ctx.pc / 4) as usize];
+            let execution = runner.run(ctx).unwrap();
+            ctx.write(&execution);
+            ctx.pc = execution.pc;
+        }
+    }
+
+    #[test]
+    fn test_exit() {
+        let mut ctx = Context::new(0);
+        run(
+            &mut ctx,
+            "addi a0, zero, 3
+            addi a7, zero, 93
+            ecall
+            addi t0, zero, 1",
+        );
+        assert_eq!(Some(3), ctx.exit_code);
+        assert_eq!(0, ctx.registers[RegisterType::T0]);
+    }
+
+    #[test]
+    fn test_write() {
+        let stdout = Buffer(Rc::new(RefCell::new(vec![])));
+        let mut ctx = Context::new(4);
+        ctx.io.stdout = Box::new(stdout.clone());
+        ctx.memory[1] = 'h' as i8;
+        ctx.memory[2] = 'i' as i8;
+        run(
+            &mut ctx,
+            "addi a0, zero, 1
+            addi a1, zero, 1
+            addi a2, zero, 2
+            addi a7, zero, 64
+            ecall",
+        );
+        assert_eq!(b"hi".to_vec(), *stdout.0.borrow());
+        assert_eq!(2, ctx.registers[RegisterType::A0]);
+
+        run(
+            &mut ctx,
+            "addi a0, zero, 1
+            addi a1, zero, 3
+            addi a2, zero, 2
+            addi a7, zero, 64
+            ecall",
+        );
+        assert_eq!(-EFAULT, ctx.registers[RegisterType::A0]);
+    }
+
+    #[test]
+    fn test_read() {
+        let mut ctx = Context::new(4);
+        ctx.io.stdin = Box::new(&b"abc"[..]);
+        run(
+            &mut ctx,
+            "addi a0, zero, 0
+            addi a1, zero, 1
+            addi a2, zero, 3
+            addi a7, zero, 63
+            ecall",
+        );
+        assert_eq!(3, ctx.registers[RegisterType::A0]);
+        assert_eq!(vec![0, 'a' as i8, 'b' as i8, 'c' as i8], ctx.memory);
+    }
+
+    #[test]
+    fn test_brk() {
+        let mut ctx = Context::new(64);
+        run(
+            &mut ctx,
+            "addi a0, zero, 0
+            addi a7, zero, 214
+            ecall
+            addi t0, a0, 0
+            addi a0, zero, 32
+            ecall
+            addi t1, a0, 0
+            addi a0, zero, 128
+            ecall",
+        );
+        assert_eq!(0, ctx.registers[RegisterType::T0]);
+        assert_eq!(32, ctx.registers[RegisterType::T1]);
+        assert_eq!(32, ctx.registers[RegisterType::A0]);
+    }
+}